@@ -1 +0,0 @@
-../../api.rs
\ No newline at end of file
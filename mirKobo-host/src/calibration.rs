@@ -0,0 +1,340 @@
+// Gui
+use eframe::egui::{pos2, Pos2, Vec2};
+
+// Other
+use crate::profile::Profile;
+
+// Where the targets go, as parts of the screen. Spread out so a wrong scale shows up
+// as a miss, the middle one checks the rest
+const TARGETS: [(f32, f32); 5] = [
+    (0.15, 0.15),
+    (0.85, 0.15),
+    (0.85, 0.85),
+    (0.15, 0.85),
+    (0.5, 0.5),
+];
+
+// Clicks further off than this on average, in window pixels, mean something went wrong
+const MAX_ERROR: f32 = 20.0;
+
+// Same for taps on the device, in touchscreen pixels. A finger is less exact than a mouse
+const MAX_TOUCH_ERROR: f32 = 60.0;
+
+fn target(n: usize, image_size: Vec2) -> Pos2 {
+    let (x, y) = TARGETS[n];
+    pos2((image_size.x * x).round(), (image_size.y * y).round())
+}
+
+// Targets are drawn at known framebuffer pixels, the user taps each one on the device and
+// clicks it on the mirror. The clicks find where the window puts the image, the taps, which
+// the device reads from its touchscreen, how the touchscreen is turned against the screen.
+// Without a tap on every target the flips and the swap can't be known and stay like they are
+#[derive(Default)]
+pub struct Calibration {
+    clicks: Vec<Pos2>,
+    touches: Vec<Option<Pos2>>, // Raw touchscreen position of the tap on each target
+}
+
+impl Calibration {
+    // In framebuffer pixels, None once every target was clicked
+    pub fn next_target(&self, image_size: Vec2) -> Option<(u32, u32)> {
+        if self.clicks.len() >= TARGETS.len() {
+            return None;
+        }
+        let target = target(self.clicks.len(), image_size);
+        Some((target.x as u32, target.y as u32))
+    }
+
+    pub fn click(&mut self, pos: Pos2) {
+        self.clicks.push(pos);
+    }
+
+    // A tap on the device, for the target shown now. Tapping again replaces it
+    pub fn touched(&mut self, pos: Pos2) {
+        let current = self.clicks.len();
+        if current >= TARGETS.len() {
+            return;
+        }
+        self.touches.resize(current + 1, None);
+        self.touches[current] = Some(pos);
+    }
+
+    // If the target shown now was tapped on the device
+    pub fn is_touched(&self) -> bool {
+        self.touches
+            .get(self.clicks.len())
+            .is_some_and(|touch| touch.is_some())
+    }
+
+    // Target being clicked now and how many there are, counted from 1
+    pub fn step(&self) -> (usize, usize) {
+        ((self.clicks.len() + 1).min(TARGETS.len()), TARGETS.len())
+    }
+
+    // rotation is the one of the screen the targets were drawn on, the flips are for it
+    pub fn solve(
+        &self,
+        app_size: Vec2,
+        image_size: Vec2,
+        rotation: Option<u32>,
+    ) -> Result<Profile, String> {
+        let targets: Vec<Pos2> = (0..TARGETS.len()).map(|n| target(n, image_size)).collect();
+        let (shift, error) = solve(&self.clicks, &targets, app_size, image_size)
+            .ok_or_else(|| String::from("Calibration needs a click on every target"))?;
+        if error > MAX_ERROR {
+            return Err(format!(
+                "Calibration failed, the clicks are {:.0} pixels off from each other. Try again",
+                error
+            ));
+        }
+        let touches: Option<Vec<Pos2>> = self.touches.iter().copied().collect();
+        let Some((flips, error)) = touches
+            .filter(|touches| touches.len() == TARGETS.len())
+            .and_then(|touches| solve_touch(&touches, &targets, image_size))
+        else {
+            return Ok(shift);
+        };
+        if error > MAX_TOUCH_ERROR {
+            return Err(format!(
+                "Calibration failed, the taps on the device are {:.0} pixels off whatever way the touchscreen is turned. Try again, or set the touch options by hand",
+                error
+            ));
+        }
+        Ok(Profile {
+            rotation,
+            ..shift.over(flips)
+        })
+    }
+}
+
+// The shift MyApp::device_position adds before scaling, the one that fits the clicks
+// best, with the average miss. Only add_to_x and add_to_y are set
+pub fn solve(
+    clicks: &[Pos2],
+    targets: &[Pos2],
+    app_size: Vec2,
+    image_size: Vec2,
+) -> Option<(Profile, f32)> {
+    if clicks.is_empty() || clicks.len() != targets.len() {
+        return None;
+    }
+    let scale = image_size / app_size;
+    // Where each target is in the window, minus where it was clicked
+    let offsets: Vec<Vec2> = targets
+        .iter()
+        .zip(clicks)
+        .map(|(target, click)| (target.to_vec2() / scale) - click.to_vec2())
+        .collect();
+    let offset =
+        offsets.iter().fold(Vec2::ZERO, |sum, offset| sum + *offset) / offsets.len() as f32;
+    let error = offsets
+        .iter()
+        .map(|each| (*each - offset).length())
+        .sum::<f32>()
+        / offsets.len() as f32;
+    let profile = Profile {
+        add_to_x: Some(offset.x.round()),
+        add_to_y: Some(offset.y.round()),
+        ..Profile::default()
+    };
+    Some((profile, error))
+}
+
+// Every flip and swap MyApp::device_position can do after scaling. The one that takes the
+// targets closest to where they were tapped wins, with its average miss in touchscreen
+// pixels. The touchscreen has to use the pixels of the framebuffer, like device_position
+// expects
+pub fn solve_touch(touches: &[Pos2], targets: &[Pos2], image_size: Vec2) -> Option<(Profile, f32)> {
+    if touches.is_empty() || touches.len() != targets.len() {
+        return None;
+    }
+    let mut best: Option<(Profile, f32)> = None;
+    for flags in 0..8 {
+        let (invert_x, invert_y, reverse_coordinates) =
+            (flags & 1 != 0, flags & 2 != 0, flags & 4 != 0);
+        let error = targets
+            .iter()
+            .zip(touches)
+            .map(|(target, touch)| {
+                let mut pos = *target;
+                if invert_x {
+                    pos.x = image_size.x - pos.x;
+                }
+                if invert_y {
+                    pos.y = image_size.y - pos.y;
+                }
+                if reverse_coordinates {
+                    std::mem::swap(&mut pos.x, &mut pos.y);
+                }
+                (pos - *touch).length()
+            })
+            .sum::<f32>()
+            / touches.len() as f32;
+        if best
+            .as_ref()
+            .is_none_or(|(_, best_error)| error < *best_error)
+        {
+            let profile = Profile {
+                invert_x: Some(invert_x),
+                invert_y: Some(invert_y),
+                reverse_coordinates: Some(reverse_coordinates),
+                ..Profile::default()
+            };
+            best = Some((profile, error));
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eframe::egui::vec2;
+
+    #[test]
+    fn finds_the_window_frame() {
+        let app_size = vec2(536.0, 724.0);
+        let image_size = vec2(1072.0, 1448.0);
+        // The image is drawn at half size, the pointer is 8 and 9 pixels off because of
+        // the window frame. The user misses by a pixel here and there
+        let misses = [(1.0, 0.0), (-1.0, 1.0), (0.0, -1.0), (0.0, 0.0), (1.0, 0.0)];
+        let targets: Vec<Pos2> = (0..5).map(|n| target(n, image_size)).collect();
+        let clicks: Vec<Pos2> = targets
+            .iter()
+            .zip(misses)
+            .map(|(target, miss)| {
+                pos2(target.x / 2.0 + 8.0 + miss.0, target.y / 2.0 + 9.0 + miss.1)
+            })
+            .collect();
+        let (found, error) = solve(&clicks, &targets, app_size, image_size).unwrap();
+        assert_eq!(found.add_to_x, Some(-8.0));
+        assert_eq!(found.add_to_y, Some(-9.0));
+        assert!(error < 2.0);
+        // What a Kobo needs stays, the clicks alone can't tell it
+        let kobo = Profile {
+            invert_x: Some(true),
+            reverse_coordinates: Some(true),
+            ..Profile::default()
+        };
+        let calibrated = kobo.over(found);
+        assert_eq!(calibrated.invert_x, Some(true));
+        assert_eq!(calibrated.reverse_coordinates, Some(true));
+        assert_eq!(calibrated.add_to_x, Some(-8.0));
+    }
+
+    #[test]
+    fn wizard_goes_through_every_target() {
+        let app_size = vec2(500.0, 500.0);
+        let image_size = vec2(1000.0, 1000.0);
+        let mut calibration = Calibration::default();
+        assert_eq!(calibration.step(), (1, 5));
+        assert!(calibration.solve(app_size, image_size, Some(3)).is_err());
+        // The window is 10 pixels lower than the image, a title bar
+        while let Some((x, y)) = calibration.next_target(image_size) {
+            calibration.click(pos2(x as f32 / 2.0, y as f32 / 2.0 + 10.0));
+        }
+        assert_eq!(calibration.step(), (5, 5));
+        // Nothing was tapped on the device
+        let profile = calibration.solve(app_size, image_size, Some(3)).unwrap();
+        assert_eq!(
+            profile,
+            Profile {
+                add_to_x: Some(0.0),
+                add_to_y: Some(-10.0),
+                ..Profile::default()
+            }
+        );
+    }
+
+    #[test]
+    fn taps_on_the_device_give_the_flips() {
+        let app_size = vec2(500.0, 500.0);
+        let image_size = vec2(1000.0, 1000.0);
+        let mut calibration = Calibration::default();
+        // Like a Kobo, x is flipped and the touchscreen is on its side. The finger misses
+        // by a few pixels
+        while let Some((x, y)) = calibration.next_target(image_size) {
+            assert!(!calibration.is_touched());
+            calibration.touched(pos2(0.0, 0.0));
+            calibration.touched(pos2(y as f32 + 7.0, 1000.0 - x as f32 - 5.0));
+            assert!(calibration.is_touched());
+            calibration.click(pos2(x as f32 / 2.0, y as f32 / 2.0 + 10.0));
+        }
+        let profile = calibration.solve(app_size, image_size, Some(3)).unwrap();
+        assert_eq!(
+            profile,
+            Profile {
+                add_to_x: Some(0.0),
+                add_to_y: Some(-10.0),
+                invert_x: Some(true),
+                invert_y: Some(false),
+                reverse_coordinates: Some(true),
+                rotation: Some(3),
+                ..Profile::default()
+            }
+        );
+    }
+
+    #[test]
+    fn every_flip_and_swap_is_found() {
+        let image_size = vec2(1072.0, 1448.0);
+        let targets: Vec<Pos2> = (0..5).map(|n| target(n, image_size)).collect();
+        for flags in 0..8 {
+            let (invert_x, invert_y, reverse) = (flags & 1 != 0, flags & 2 != 0, flags & 4 != 0);
+            let touches: Vec<Pos2> = targets
+                .iter()
+                .map(|target| {
+                    let mut pos = *target;
+                    if invert_x {
+                        pos.x = image_size.x - pos.x;
+                    }
+                    if invert_y {
+                        pos.y = image_size.y - pos.y;
+                    }
+                    if reverse {
+                        std::mem::swap(&mut pos.x, &mut pos.y);
+                    }
+                    pos
+                })
+                .collect();
+            let (found, error) = solve_touch(&touches, &targets, image_size).unwrap();
+            assert_eq!(
+                (found.invert_x, found.invert_y, found.reverse_coordinates),
+                (Some(invert_x), Some(invert_y), Some(reverse))
+            );
+            assert_eq!(error, 0.0);
+        }
+    }
+
+    #[test]
+    fn random_clicks_are_rejected() {
+        let size = vec2(500.0, 500.0);
+        let mut calibration = Calibration::default();
+        for pos in [
+            (10.0, 10.0),
+            (10.0, 20.0),
+            (400.0, 30.0),
+            (20.0, 10.0),
+            (300.0, 300.0),
+        ] {
+            calibration.click(pos2(pos.0, pos.1));
+        }
+        assert!(calibration.solve(size, size, None).is_err());
+        // Good clicks, but taps all over the place
+        let mut calibration = Calibration::default();
+        let mut taps = [
+            (900.0, 10.0),
+            (10.0, 10.0),
+            (500.0, 20.0),
+            (30.0, 800.0),
+            (0.0, 0.0),
+        ]
+        .iter();
+        while let Some((x, y)) = calibration.next_target(size) {
+            let tap = taps.next().unwrap();
+            calibration.touched(pos2(tap.0, tap.1));
+            calibration.click(pos2(x as f32, y as f32));
+        }
+        assert!(calibration.solve(size, size, None).is_err());
+    }
+}
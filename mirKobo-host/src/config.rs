@@ -0,0 +1,117 @@
+// Logging
+use log::debug;
+
+// Other
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use toml_edit::{Document, Table};
+
+// Reading, parsing and applying it is shared with the device
+pub use mir_kobo_proto::config::{apply, initial, load};
+
+// How often the config file is looked at for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+// $XDG_CONFIG_HOME/mirkobo/host.toml, or ~/.config/mirkobo/host.toml
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("mirkobo").join("host.toml"))
+}
+
+// Lets write set keys in the file, everything else in it stays. A broken file is left alone
+pub fn save(path: &Path, write: impl FnOnce(&mut Table)) -> Result<(), String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.to_string()),
+    };
+    let mut document: Document = text.parse().map_err(|err| format!("{}", err))?;
+    write(document.as_table_mut());
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+    }
+    std::fs::write(path, document.to_string()).map_err(|err| err.to_string())
+}
+
+// Tells when the file was changed, by its modification time
+pub struct Watcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl Watcher {
+    pub fn new(path: PathBuf, now: Instant) -> Self {
+        let modified = modified(&path);
+        Watcher {
+            path,
+            modified,
+            checked: now,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // We wrote it ourselves, that isn't a change to reload
+    pub fn saved(&mut self) {
+        self.modified = modified(&self.path);
+    }
+
+    pub fn changed(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.checked) < WATCH_INTERVAL {
+            return false;
+        }
+        self.checked = now;
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return false;
+        }
+        debug!("Config {:?} changed", self.path);
+        self.modified = modified;
+        true
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saving_keeps_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mirkobo").join("host.toml");
+        save(&path, |table| table["port"] = toml_edit::value(1)).unwrap();
+        std::fs::write(&path, "# Mine\nport = 1\nstreaming = true\n").unwrap();
+        save(&path, |table| table["port"] = toml_edit::value(2)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "# Mine\nport = 2\nstreaming = true\n"
+        );
+        std::fs::write(&path, "port = ").unwrap();
+        assert!(save(&path, |_| ()).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "port = ");
+    }
+
+    #[test]
+    fn notices_changes() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let start = Instant::now();
+        let mut watcher = Watcher::new(file.path().to_path_buf(), start);
+        // Pretend it was saved before, mtime granularity can be coarse
+        watcher.modified = Some(SystemTime::UNIX_EPOCH);
+        assert!(!watcher.changed(start));
+        assert!(watcher.changed(start + WATCH_INTERVAL));
+        assert!(!watcher.changed(start + WATCH_INTERVAL * 2));
+    }
+}
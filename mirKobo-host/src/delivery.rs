@@ -0,0 +1,294 @@
+// Gui
+use eframe::egui::Pos2;
+
+// Logging
+use log::{debug, error, warn};
+
+// Network
+use mir_kobo_proto::{FromServerMessage, Input};
+
+// Other
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// No ack after this long, on top of how long the input takes, means it got lost
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_ATTEMPTS: u32 = 3;
+
+// How long a tap stays marked on the screen after it landed or failed
+const MARK_SHOW: Duration = Duration::from_millis(1500);
+
+// What typing one character through the on screen keyboard roughly takes
+const TEXT_MS_PER_CHAR: u64 = 200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Delivery {
+    Pending,
+    Landed,
+    Failed(String),
+}
+
+// Where an input went, in window coordinates, for drawing
+pub struct Mark {
+    pub pos: Pos2,
+    pub delivery: Delivery,
+    id: u32,
+    done: Option<Instant>,
+}
+
+struct Sent {
+    input: Input,
+    sent: Instant,
+    attempts: u32,
+}
+
+// How long the device is busy with it before it can ack
+fn duration(input: &Input) -> Duration {
+    let ms = match input {
+        Input::TouchUp { held_ms, .. } => *held_ms as u64,
+        Input::Gesture(points) => points.last().map_or(0, |point| point.time_ms as u64),
+        Input::Pinch(points) => points.last().map_or(0, |point| point.time_ms as u64),
+        Input::Text(text) => text.chars().count() as u64 * TEXT_MS_PER_CHAR,
+        Input::TouchDown(..) | Input::Key(..) => 0,
+    };
+    Duration::from_millis(ms)
+}
+
+// For logs and errors, typed text can be a password
+fn describe(input: &Input) -> String {
+    match input {
+        Input::Text(text) => format!("Text of {} characters", text.chars().count()),
+        _ => format!("{:?}", input),
+    }
+}
+
+// Gives every input an id and waits for the device to ack it. The device works through
+// them in order, so only the oldest one can time out, the others wait behind it
+pub struct InputTracker {
+    next_id: u32,
+    pending: BTreeMap<u32, Sent>,
+    progress: Instant, // Last ack, the oldest pending input waits from there
+    marks: VecDeque<Mark>,
+}
+
+impl Default for InputTracker {
+    fn default() -> Self {
+        InputTracker {
+            next_id: 0,
+            pending: BTreeMap::new(),
+            progress: Instant::now(),
+            marks: VecDeque::new(),
+        }
+    }
+}
+
+impl InputTracker {
+    // The message to send for it. pos is marked on the screen until it lands
+    pub fn send(&mut self, input: Input, pos: Option<Pos2>, now: Instant) -> FromServerMessage {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        if self.pending.is_empty() {
+            self.progress = now;
+        }
+        if let Some(pos) = pos {
+            self.marks.push_back(Mark {
+                pos,
+                delivery: Delivery::Pending,
+                id,
+                done: None,
+            });
+        }
+        self.pending.insert(
+            id,
+            Sent {
+                input: input.clone(),
+                sent: now,
+                attempts: 1,
+            },
+        );
+        FromServerMessage::Input { id, input }
+    }
+
+    fn finish(&mut self, id: u32, delivery: Delivery, now: Instant) {
+        if let Some(mark) = self.marks.iter_mut().find(|mark| mark.id == id) {
+            mark.delivery = delivery;
+            mark.done = Some(now);
+        }
+    }
+
+    // The text of the failure, if it failed
+    pub fn ack(&mut self, id: u32, error: Option<String>, now: Instant) -> Option<String> {
+        let Some(sent) = self.pending.remove(&id) else {
+            debug!("Ack for input {} that isn't pending, a retry probably", id);
+            return None;
+        };
+        self.progress = now;
+        debug!(
+            "Input {} landed after {:?}",
+            id,
+            now.duration_since(sent.sent)
+        );
+        match error {
+            Some(err) => {
+                let text = format!("Device failed to inject {}: {}", describe(&sent.input), err);
+                self.finish(id, Delivery::Failed(err), now);
+                Some(text)
+            }
+            None => {
+                self.finish(id, Delivery::Landed, now);
+                None
+            }
+        }
+    }
+
+    // Messages to send again, and the text of what gave up
+    pub fn retries(&mut self, now: Instant) -> (Vec<FromServerMessage>, Option<String>) {
+        let Some((&id, sent)) = self.pending.iter_mut().next() else {
+            return (Vec::new(), None);
+        };
+        let waiting_since = sent.sent.max(self.progress);
+        if now.duration_since(waiting_since) < ACK_TIMEOUT + duration(&sent.input) {
+            return (Vec::new(), None);
+        }
+        if sent.attempts < MAX_ATTEMPTS {
+            sent.attempts += 1;
+            sent.sent = now;
+            warn!(
+                "No ack for input {}, sending it again, attempt {}",
+                id, sent.attempts
+            );
+            let message = FromServerMessage::Input {
+                id,
+                input: sent.input.clone(),
+            };
+            return (vec![message], None);
+        }
+        let sent = self.pending.remove(&id).unwrap();
+        let text = format!(
+            "Input {} got lost, no ack after {} attempts",
+            describe(&sent.input),
+            MAX_ATTEMPTS
+        );
+        error!("{}", text);
+        self.progress = now;
+        self.finish(id, Delivery::Failed(String::from("no ack")), now);
+        (Vec::new(), Some(text))
+    }
+
+    // A new connection, the old one won't ack anything
+    pub fn reset(&mut self, now: Instant) {
+        let ids: Vec<u32> = self.pending.keys().copied().collect();
+        for id in ids {
+            self.finish(id, Delivery::Failed(String::from("disconnected")), now);
+        }
+        self.pending.clear();
+    }
+
+    // Marks worth drawing, old ones are dropped
+    pub fn marks(&mut self, now: Instant) -> impl Iterator<Item = &Mark> {
+        self.marks.retain(|mark| {
+            mark.done
+                .is_none_or(|done| now.duration_since(done) < MARK_SHOW)
+        });
+        self.marks.iter()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty() && self.marks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eframe::egui::pos2;
+
+    fn tap() -> Input {
+        Input::TouchUp {
+            x: 1,
+            y: 2,
+            held_ms: 0,
+        }
+    }
+
+    fn delivery(tracker: &mut InputTracker, now: Instant) -> Vec<Delivery> {
+        tracker
+            .marks(now)
+            .map(|mark| mark.delivery.clone())
+            .collect()
+    }
+
+    #[test]
+    fn ids_go_up_and_acks_land() {
+        let now = Instant::now();
+        let mut tracker = InputTracker::default();
+        assert_eq!(
+            tracker.send(tap(), Some(pos2(5.0, 5.0)), now),
+            FromServerMessage::Input {
+                id: 0,
+                input: tap()
+            }
+        );
+        tracker.send(Input::Key(116, true), None, now);
+        assert_eq!(delivery(&mut tracker, now), [Delivery::Pending]);
+        assert_eq!(tracker.ack(0, None, now), None);
+        assert!(tracker.ack(1, Some(String::from("no key")), now).is_some());
+        assert_eq!(delivery(&mut tracker, now), [Delivery::Landed]);
+        // Gone after a while
+        assert!(!tracker.is_idle());
+        assert_eq!(delivery(&mut tracker, now + MARK_SHOW), []);
+        assert!(tracker.is_idle());
+    }
+
+    #[test]
+    fn oldest_is_retried_then_given_up() {
+        let start = Instant::now();
+        let mut tracker = InputTracker::default();
+        tracker.send(tap(), Some(pos2(5.0, 5.0)), start);
+        tracker.send(tap(), None, start);
+        assert_eq!(tracker.retries(start + Duration::from_secs(1)).0, []);
+        let mut now = start;
+        for _ in 1..MAX_ATTEMPTS {
+            now += ACK_TIMEOUT;
+            assert_eq!(
+                tracker.retries(now).0,
+                [FromServerMessage::Input {
+                    id: 0,
+                    input: tap()
+                }]
+            );
+        }
+        now += ACK_TIMEOUT;
+        let (again, failed) = tracker.retries(now);
+        assert!(again.is_empty() && failed.is_some());
+        assert_eq!(
+            delivery(&mut tracker, now),
+            [Delivery::Failed(String::from("no ack"))]
+        );
+        // The next one waits from the give up, not from when it was sent
+        assert_eq!(tracker.retries(now).0, []);
+        assert_eq!(tracker.retries(now + ACK_TIMEOUT).0.len(), 1);
+    }
+
+    #[test]
+    fn long_inputs_get_more_time() {
+        let start = Instant::now();
+        let mut tracker = InputTracker::default();
+        tracker.send(Input::Text(String::from("0123456789")), None, start);
+        assert_eq!(tracker.retries(start + ACK_TIMEOUT).0, []);
+        assert_eq!(tracker.retries(start + ACK_TIMEOUT * 2).0.len(), 1);
+    }
+
+    #[test]
+    fn reset_fails_the_pending() {
+        let now = Instant::now();
+        let mut tracker = InputTracker::default();
+        tracker.send(tap(), Some(pos2(5.0, 5.0)), now);
+        tracker.reset(now);
+        assert_eq!(
+            delivery(&mut tracker, now),
+            [Delivery::Failed(String::from("disconnected"))]
+        );
+        assert_eq!(tracker.retries(now + ACK_TIMEOUT * 10).0, []);
+    }
+}
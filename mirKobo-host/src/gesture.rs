@@ -0,0 +1,326 @@
+// Gui
+use eframe::egui::{pos2, vec2, Pos2, Vec2};
+
+// Arguments
+use clap::ValueEnum;
+
+// Other
+use std::time::{Duration, Instant};
+
+// Moving less than this, in window pixels, is still a click
+const TAP_SLOP: f32 = 8.0;
+
+// Still for this long, it's a long press and the finger goes down on the device right away
+const HOLD_MS: u32 = 250;
+
+// Pinch fingers start this far apart, in window pixels, and never get closer than PINCH_MIN
+const PINCH_START: f32 = 80.0;
+const PINCH_MIN: f32 = 20.0;
+
+// The wheel counts as stopped after this long, then zooms and scrolls are sent
+const WHEEL_SETTLE: Duration = Duration::from_millis(150);
+// A zoom is sent as one pinch this long
+const ZOOM_PINCH_MS: u32 = 300;
+const ZOOM_PINCH_STEPS: u32 = 10;
+
+// Scrolls are swipes this long
+const SCROLL_SWIPE_MS: u32 = 200;
+const SCROLL_SWIPE_STEPS: u32 = 10;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WheelAction {
+    Swipe, // Vertical swipe as long as the scroll
+    Page,  // Tap the right edge for down, the left one for up
+    Off,
+}
+
+// Both fingers and ms since the first step
+pub type PinchStep = (Pos2, Pos2, u32);
+
+// Two fingers side by side around center
+fn pinch_fingers(center: Pos2, spread: f32) -> (Pos2, Pos2) {
+    let half = spread.max(PINCH_MIN) / 2.0;
+    (center - vec2(half, 0.0), center + vec2(half, 0.0))
+}
+
+// Pointer positions from press to release, with ms since the press
+pub struct Stroke {
+    started: Instant,
+    points: Vec<(Pos2, u32)>,
+    pub holding: bool, // TouchDown was sent already
+    pub pinch: bool,   // ctrl was down on press
+}
+
+impl Stroke {
+    pub fn new(pos: Pos2) -> Self {
+        Stroke {
+            started: Instant::now(),
+            points: vec![(pos, 0)],
+            holding: false,
+            pinch: false,
+        }
+    }
+
+    pub fn elapsed_ms(&self) -> u32 {
+        self.started.elapsed().as_millis() as u32
+    }
+
+    // Called every frame while the button is down, only keeps actual moves
+    pub fn sample(&mut self, pos: Pos2) {
+        let time_ms = self.elapsed_ms();
+        self.sample_at(pos, time_ms);
+    }
+
+    fn sample_at(&mut self, pos: Pos2, time_ms: u32) {
+        if self.points.last().is_some_and(|(last, _)| *last != pos) {
+            self.points.push((pos, time_ms));
+        }
+    }
+
+    // The release, always kept so the stroke lasts as long as the drag did
+    pub fn end(&mut self, pos: Pos2) {
+        let time_ms = self.elapsed_ms();
+        self.end_at(pos, time_ms);
+    }
+
+    fn end_at(&mut self, pos: Pos2, time_ms: u32) {
+        match self.points.last() {
+            Some((last, last_ms)) if *last == pos && *last_ms == time_ms => (),
+            _ => self.points.push((pos, time_ms)),
+        }
+    }
+
+    pub fn origin(&self) -> Pos2 {
+        self.points[0].0
+    }
+
+    pub fn points(&self) -> &[(Pos2, u32)] {
+        &self.points
+    }
+
+    // Last point, the release once end() was called
+    pub fn last(&self) -> (Pos2, u32) {
+        self.points[self.points.len() - 1]
+    }
+
+    // True once, when a press turns into a long press
+    pub fn starts_hold(&self, elapsed_ms: u32) -> bool {
+        !self.holding && !self.pinch && elapsed_ms >= HOLD_MS && self.is_tap()
+    }
+
+    // For ctrl+drag, dragging right or up spreads the fingers, left or down brings them together
+    pub fn pinch_steps(&self) -> Vec<PinchStep> {
+        let origin = self.origin();
+        self.points
+            .iter()
+            .map(|(pos, time_ms)| {
+                let drag = (pos.x - origin.x) - (pos.y - origin.y);
+                let (first, second) = pinch_fingers(origin, PINCH_START + 2.0 * drag);
+                (first, second, *time_ms)
+            })
+            .collect()
+    }
+
+    pub fn is_tap(&self) -> bool {
+        let origin = self.origin();
+        self.points
+            .iter()
+            .all(|(pos, _)| pos.distance(origin) < TAP_SLOP)
+    }
+}
+
+// ctrl+scroll adds up until the wheel stops
+pub struct Zoom {
+    pub center: Pos2,
+    pub factor: f32,
+    last: Instant,
+}
+
+impl Zoom {
+    pub fn new(center: Pos2) -> Self {
+        Zoom {
+            center,
+            factor: 1.0,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, factor: f32) {
+        // Way beyond this the fingers leave the screen anyway
+        self.factor = (self.factor * factor).clamp(0.25, 4.0);
+        self.last = Instant::now();
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.last.elapsed() >= WHEEL_SETTLE
+    }
+
+    // One smooth pinch from PINCH_START to factor times that
+    pub fn pinch_steps(&self) -> Vec<PinchStep> {
+        (0..=ZOOM_PINCH_STEPS)
+            .map(|n| {
+                let t = n as f32 / ZOOM_PINCH_STEPS as f32;
+                let spread = PINCH_START * (1.0 + (self.factor - 1.0) * t);
+                let (first, second) = pinch_fingers(self.center, spread);
+                (first, second, ZOOM_PINCH_MS * n / ZOOM_PINCH_STEPS)
+            })
+            .collect()
+    }
+}
+
+// Wheel turns add up until it stops, like Zoom
+pub struct Scroll {
+    pub center: Pos2,
+    pub delta: Vec2,
+    last: Instant,
+}
+
+impl Scroll {
+    pub fn new(center: Pos2) -> Self {
+        Scroll {
+            center,
+            delta: Vec2::ZERO,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, delta: Vec2) {
+        self.delta += delta;
+        self.last = Instant::now();
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.last.elapsed() >= WHEEL_SETTLE
+    }
+
+    // The finger drags the content, so it moves with the delta, centered on the pointer
+    pub fn swipe_points(&self, max_length: f32) -> Vec<(Pos2, u32)> {
+        let length = self.delta.y.clamp(-max_length, max_length);
+        let start = self.center - vec2(0.0, length / 2.0);
+        (0..=SCROLL_SWIPE_STEPS)
+            .map(|n| {
+                let t = n as f32 / SCROLL_SWIPE_STEPS as f32;
+                (
+                    start + vec2(0.0, length * t),
+                    SCROLL_SWIPE_MS * n / SCROLL_SWIPE_STEPS,
+                )
+            })
+            .collect()
+    }
+
+    // Scrolling down, the delta is negative, goes to the next page
+    pub fn page_tap(&self, app_size: Vec2) -> Pos2 {
+        let x = if self.delta.y < 0.0 { 0.9 } else { 0.1 };
+        pos2(app_size.x * x, app_size.y / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_moves_and_the_end() {
+        let mut stroke = Stroke::new(pos2(10.0, 10.0));
+        stroke.sample_at(pos2(10.0, 10.0), 16);
+        stroke.sample_at(pos2(40.0, 10.0), 32);
+        stroke.sample_at(pos2(40.0, 10.0), 48);
+        stroke.end_at(pos2(40.0, 10.0), 300);
+        assert_eq!(
+            stroke.points(),
+            [
+                (pos2(10.0, 10.0), 0),
+                (pos2(40.0, 10.0), 32),
+                (pos2(40.0, 10.0), 300)
+            ]
+        );
+        assert!(!stroke.is_tap());
+    }
+
+    #[test]
+    fn small_wobble_is_a_tap() {
+        let mut stroke = Stroke::new(pos2(10.0, 10.0));
+        stroke.sample_at(pos2(13.0, 12.0), 16);
+        stroke.end_at(pos2(12.0, 11.0), 90);
+        assert!(stroke.is_tap());
+        assert_eq!(stroke.origin(), pos2(10.0, 10.0));
+        assert_eq!(stroke.last(), (pos2(12.0, 11.0), 90));
+    }
+
+    #[test]
+    fn long_press_starts_once() {
+        let mut stroke = Stroke::new(pos2(10.0, 10.0));
+        assert!(!stroke.starts_hold(HOLD_MS - 1));
+        assert!(stroke.starts_hold(HOLD_MS));
+        stroke.holding = true;
+        assert!(!stroke.starts_hold(HOLD_MS + 100));
+        // A drag never becomes a long press, a pinch neither
+        let mut stroke = Stroke::new(pos2(10.0, 10.0));
+        stroke.sample_at(pos2(60.0, 10.0), 100);
+        assert!(!stroke.starts_hold(HOLD_MS));
+        let mut stroke = Stroke::new(pos2(10.0, 10.0));
+        stroke.pinch = true;
+        assert!(!stroke.starts_hold(HOLD_MS));
+    }
+
+    #[test]
+    fn ctrl_drag_spreads_fingers() {
+        let mut stroke = Stroke::new(pos2(200.0, 100.0));
+        stroke.pinch = true;
+        stroke.sample_at(pos2(220.0, 100.0), 50);
+        stroke.sample_at(pos2(100.0, 100.0), 100);
+        assert_eq!(
+            stroke.pinch_steps(),
+            [
+                (pos2(160.0, 100.0), pos2(240.0, 100.0), 0),
+                (pos2(140.0, 100.0), pos2(260.0, 100.0), 50),
+                // Never closer than PINCH_MIN
+                (pos2(190.0, 100.0), pos2(210.0, 100.0), 100),
+            ]
+        );
+    }
+
+    #[test]
+    fn scroll_becomes_a_centered_swipe() {
+        let mut scroll = Scroll::new(pos2(200.0, 300.0));
+        scroll.add(vec2(0.0, -50.0));
+        scroll.add(vec2(0.0, -50.0));
+        let points = scroll.swipe_points(1000.0);
+        assert_eq!(points.len(), SCROLL_SWIPE_STEPS as usize + 1);
+        assert_eq!(points[0], (pos2(200.0, 350.0), 0));
+        assert_eq!(
+            points[points.len() - 1],
+            (pos2(200.0, 250.0), SCROLL_SWIPE_MS)
+        );
+        // Never longer than the screen allows
+        let points = scroll.swipe_points(40.0);
+        assert_eq!(points[0].0, pos2(200.0, 320.0));
+    }
+
+    #[test]
+    fn scroll_direction_picks_the_page() {
+        let size = vec2(400.0, 600.0);
+        let mut scroll = Scroll::new(pos2(200.0, 300.0));
+        scroll.add(vec2(0.0, -50.0));
+        assert_eq!(scroll.page_tap(size), pos2(360.0, 300.0));
+        scroll.add(vec2(0.0, 100.0));
+        assert_eq!(scroll.page_tap(size), pos2(40.0, 300.0));
+    }
+
+    #[test]
+    fn zoom_becomes_one_pinch() {
+        let mut zoom = Zoom::new(pos2(200.0, 100.0));
+        zoom.add(1.5);
+        zoom.add(2.0);
+        assert_eq!(zoom.factor, 3.0);
+        zoom.add(10.0);
+        assert_eq!(zoom.factor, 4.0);
+        let steps = zoom.pinch_steps();
+        assert_eq!(steps.len(), ZOOM_PINCH_STEPS as usize + 1);
+        assert_eq!(steps[0], (pos2(160.0, 100.0), pos2(240.0, 100.0), 0));
+        assert_eq!(
+            steps[steps.len() - 1],
+            (pos2(40.0, 100.0), pos2(360.0, 100.0), ZOOM_PINCH_MS)
+        );
+    }
+}
@@ -1,36 +1,79 @@
-mod api;
+mod calibration;
+mod config;
+mod delivery;
+mod gesture;
+mod pacing;
+mod profile;
+mod rotation;
+mod settings;
 mod server;
+mod stats;
 
 // Gui
 use eframe::egui;
-use egui::Vec2;
-use egui_extras::RetainedImage;
+use egui::{Color32, ColorImage, Pos2, TextureHandle, TextureOptions, Vec2};
+use calibration::Calibration;
+use delivery::{Delivery, InputTracker};
+use gesture::{PinchStep, Scroll, Stroke, WheelAction, Zoom};
+use stats::FrameStats;
 
 // Logging
 use log::{debug, error, info, warn};
 
 // Network
-pub use api::{FromClientMessage, FromServerMessage};
-use message_io::network::{Endpoint, ResourceId, Transport, SendStatus};
+pub use mir_kobo_proto::{KEY_HOME, KEY_LIGHT, KEY_PAGE_BACK, KEY_PAGE_FORWARD, KEY_POWER};
+pub use mir_kobo_proto::{Capabilities, Rect, FromClientMessage, FromServerMessage, FrameEncoding, Input, InputKind, Message, PinchPoint, TouchPoint};
+use message_io::network::{Endpoint, Transport, SendStatus};
 use message_io::node::{self, NodeHandler};
 use std::net::ToSocketAddrs;
 
 // Threads
 use std::sync::mpsc::{self, Receiver};
-use std::sync::Arc;
+use pacing::FramePacer;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 use std::{thread, time};
 
 // Arguments
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use profile::{Profile, Profiles, TouchDevice, DEFAULT_PROFILE_PATH};
+use std::ffi::OsString;
+use std::path::PathBuf;
 
 // Other
 use rand::Rng;
 
 pub enum ThreadCom {
     ConnectionActive(bool),
-    ClientConnected(Endpoint, ResourceId),
-    Screen(Vec<u8>),
+    ClientConnected(Endpoint, Capabilities, Option<String>), // After a successful Hello, with what both sides support and the model
+    Screen((u32, u32), Vec<u8>), // x, y and 8 bit gray pixels
+    Patch(Vec<(Rect, Vec<u8>)>), // Changed parts of the last Screen
+    Received(usize, Option<u64>), // After a Screen or Patch, the message size and when it was requested, or captured when streamed
     ScreenSize((u32, u32)),
+    Rotation(u32), // Of the device screen, comes after ScreenSize
+    TargetTouched((u32, u32)), // Raw touchscreen x, y of a tap on the device while calibrating
+    Error(String), // Shown in the window
+}
+
+// What this build of the host can decode and send
+pub fn host_capabilities() -> Capabilities {
+    Capabilities {
+        encodings: vec![
+            FrameEncoding::Zstd,
+            FrameEncoding::Lz4,
+            FrameEncoding::Raw,
+            FrameEncoding::Png,
+        ],
+        inputs: vec![
+            InputKind::Click,
+            InputKind::Gesture,
+            InputKind::Pinch,
+            InputKind::Key,
+            InputKind::Text,
+        ],
+        draw_targets: true,
+    }
 }
 
 fn main() -> Result<(), eframe::Error> {
@@ -47,18 +90,61 @@ fn main() -> Result<(), eframe::Error> {
     eframe::run_native("mirKobo", options, Box::new(|_cc| Box::<MyApp>::default()))
 }
 
+fn gray_image(x: u32, y: u32, pixels: &[u8]) -> ColorImage {
+    ColorImage {
+        size: [x as usize, y as usize],
+        pixels: pixels.iter().map(|value| Color32::from_gray(*value)).collect(),
+    }
+}
+
+// Host keys for the device buttons
+fn device_key(key: egui::Key) -> Option<u16> {
+    match key {
+        egui::Key::F1 => Some(KEY_POWER),
+        egui::Key::F2 => Some(KEY_HOME),
+        egui::Key::F3 => Some(KEY_LIGHT),
+        egui::Key::PageUp => Some(KEY_PAGE_BACK),
+        egui::Key::PageDown => Some(KEY_PAGE_FORWARD),
+        _ => None,
+    }
+}
+
 struct GuiVars {
-    cursor_count: i32, // For some reason it reports 3 events, so let's ignore them
-    image: Option<RetainedImage>,
+    stroke: Option<Stroke>, // While the mouse button is down
+    zoom: Option<Zoom>,     // While ctrl+scroll goes on
+    scroll: Option<Scroll>, // While the wheel turns
+    typing: bool,           // Keyboard goes to the device as text
+    settings: bool,         // The side panel is open
+    status: bool,           // The status bar is shown
+    status_height: f32,     // Of the status bar, the window is that much taller than the screen
+    connected: bool,
+    stats: FrameStats,
+    rotation: Option<u32>,  // Of the device screen
+    first_rotation: Option<u32>, // Since connecting, the touch options are for it unless they say otherwise
+    calibration: Option<Calibration>, // While the calibration targets are clicked
+    image: Option<TextureHandle>,
     image_size: Option<Vec2>,
+    error: Option<String>,
 }
 
 impl GuiVars {
     pub fn new() -> Self {
         GuiVars {
-            cursor_count: 0,
+            stroke: None,
+            zoom: None,
+            scroll: None,
+            typing: false,
+            settings: false,
+            status: true,
+            status_height: 0.0,
+            connected: false,
+            stats: FrameStats::default(),
+            rotation: None,
+            first_rotation: None,
+            calibration: None,
             image: None,
             image_size: None,
+            error: None,
         }
     }
 }
@@ -74,6 +160,69 @@ struct InputOptions {
     randomise_input_offset: u32,
     repeat_click: u32,
     input_repeat_delay_ms: u32,
+    wheel_action: WheelAction,
+    rotation: Option<u32>, // Screen rotation the options above are for
+}
+
+impl InputOptions {
+    fn profile(&self) -> Profile {
+        Profile {
+            add_to_x: Some(self.add_to_x),
+            add_to_y: Some(self.add_to_y),
+            invert_x: Some(self.invert_x),
+            invert_y: Some(self.invert_y),
+            reverse_coordinates: Some(self.reverse_coordinates),
+            randomise_input_offset: Some(self.randomise_input_offset),
+            repeat_click: Some(self.repeat_click),
+            input_repeat_delay_ms: Some(self.input_repeat_delay_ms),
+            wheel_action: Some(self.wheel_action),
+            rotation: self.rotation,
+        }
+    }
+
+    // What the profile doesn't set stays
+    fn apply(&mut self, profile: &Profile) {
+        self.add_to_x = profile.add_to_x.unwrap_or(self.add_to_x);
+        self.add_to_y = profile.add_to_y.unwrap_or(self.add_to_y);
+        self.invert_x = profile.invert_x.unwrap_or(self.invert_x);
+        self.invert_y = profile.invert_y.unwrap_or(self.invert_y);
+        self.reverse_coordinates = profile.reverse_coordinates.unwrap_or(self.reverse_coordinates);
+        self.randomise_input_offset = profile.randomise_input_offset.unwrap_or(self.randomise_input_offset);
+        self.repeat_click = profile.repeat_click.unwrap_or(self.repeat_click);
+        self.input_repeat_delay_ms = profile.input_repeat_delay_ms.unwrap_or(self.input_repeat_delay_ms);
+        self.wheel_action = profile.wheel_action.unwrap_or(self.wheel_action);
+        self.rotation = profile.rotation.or(self.rotation);
+    }
+}
+
+// Between screen refreshes
+struct RefreshDelays {
+    screen_delay_ms: u32,
+    min_screen_delay_ms: u32,
+    fixed_screen_delay: bool,
+}
+
+impl RefreshDelays {
+    fn new(args: &Args) -> Self {
+        RefreshDelays {
+            screen_delay_ms: args.screen_delay_ms,
+            min_screen_delay_ms: args.min_screen_delay_ms,
+            fixed_screen_delay: args.fixed_screen_delay,
+        }
+    }
+
+    // Shortest and longest, for the pacer
+    fn bounds(&self) -> (u32, u32) {
+        // 1100 uses 30% of cpu
+        // 400 uses 100%
+        // Using native fbink should help ;p
+        // Now it adapts, the device can also cap its cpu use with --cpu-budget
+        let min_screen_delay_ms = match self.fixed_screen_delay {
+            true => self.screen_delay_ms,
+            false => self.min_screen_delay_ms.min(self.screen_delay_ms),
+        };
+        (min_screen_delay_ms, self.screen_delay_ms)
+    }
 }
 
 struct MyApp {
@@ -82,15 +231,27 @@ struct MyApp {
     endpoint: Option<Endpoint>,
     gui: GuiVars,
     input_options: InputOptions,
-    screen_delay_ms: u32,
+    delays: RefreshDelays,
+    pacer: Arc<Mutex<FramePacer>>, // Shared with the server thread, which measures the frames
+    refresh: Arc<AtomicU32>, // Generation of the screen refresh thread, bumping it stops that thread
+    inputs: Arc<Mutex<InputTracker>>, // Shared with the server thread, which gets the acks
+    streaming: Option<(u32, u32)>, // Min and max interval for the device to check the screen
     initial_screen_size: Option<(u32, u32)>,
+    capabilities: Option<Capabilities>,
+    profiles: Profiles,
+    profile_path: PathBuf, // Where the calibration goes
+    arguments: Profile,    // Every input option from the arguments, under the profiles
+    given: Profile,        // Only the ones typed on the command line, over the profiles
+    model: Option<String>, // Of the connected device
+    config: Option<config::Watcher>, // Reloaded when it changes
+    argv: Vec<OsString>,             // The command line goes over the reloaded config too
+    started: time::Instant,          // Screen requests are stamped from it
 }
 
 impl MyApp {
     pub fn send_network(&self, message: FromServerMessage) {
         if let Some(endpoint) = self.endpoint {
-            let output_data = bincode::serialize(&message).unwrap();
-            let status = self.network_handler.network().send(endpoint, &output_data);
+            let status = self.network_handler.network().send(endpoint, &message.encode());
             debug!("Status of message {:?} is {:?}", message, status);
             if status != SendStatus::Sent {
                 error!("Packet not send?");
@@ -99,6 +260,485 @@ impl MyApp {
             error!("Failed to send network message: missing endpoint");
         }
     }
+
+    // Quarter turns between the screen now and the screen the touch options were made for
+    fn touch_turns(&self) -> u32 {
+        let base = self.input_options.rotation.or(self.gui.first_rotation);
+        match (self.gui.rotation, base) {
+            (Some(now), Some(base)) => rotation::turns(now, base),
+            _ => 0,
+        }
+    }
+
+    // From window coordinates to what the touchscreen expects
+    fn device_position(&self, pos: Pos2, app_size: Vec2, randomise: bool) -> Pos2 {
+        let mut pos_final = pos;
+        pos_final.y += self.input_options.add_to_y;
+        pos_final.x += self.input_options.add_to_x;
+
+        // Adjust input
+        if let Some(image_size) = &self.gui.image_size {
+            // Map the value to the size...
+            debug!("App size: {:?}", app_size);
+            let scale_x = image_size.x / app_size.x;
+            let scale_y = image_size.y / app_size.y;
+            debug!("scale_x:{} scale_y:{}", scale_x, scale_y);
+            pos_final.x *= scale_x;
+            pos_final.y *= scale_y;
+
+            // Shift randomise
+            if randomise && self.input_options.randomise_input_offset != 0 {
+                debug!(
+                    "Before randomised shifting: x:{} y:{}",
+                    pos_final.x, pos_final.y
+                );
+                let mut rng = rand::thread_rng();
+                pos_final.x = rng.gen_range(
+                    pos_final.x - self.input_options.randomise_input_offset as f32
+                        ..pos_final.x
+                            + self.input_options.randomise_input_offset as f32
+                            + 1.0,
+                ); // +1 is because rust is stupid and does -1
+                pos_final.y = rng.gen_range(
+                    pos_final.y - self.input_options.randomise_input_offset as f32
+                        ..pos_final.y
+                            + self.input_options.randomise_input_offset as f32
+                            + 1.0,
+                );
+                debug!(
+                    "After randomised shifting: x:{} y:{}",
+                    pos_final.x, pos_final.y
+                );
+            }
+
+            // The touchscreen didn't turn with the screen
+            let (turned, image_size) = rotation::unturn(pos_final, *image_size, self.touch_turns());
+            pos_final = turned;
+
+            if self.input_options.invert_x {
+                pos_final.x = image_size.x - pos_final.x;
+            }
+            if self.input_options.invert_y {
+                pos_final.y = image_size.y - pos_final.y;
+            }
+
+            // Off the screen, like a pinch that started near the edge. Only the low side
+            // would be clamped by the cast, and without a word
+            let last = (image_size - Vec2::splat(1.0)).max(Vec2::ZERO).to_pos2();
+            let clamped = pos_final.clamp(Pos2::ZERO, last);
+            if clamped != pos_final {
+                debug!("{:?} is off the screen, using {:?}", pos_final, clamped);
+                pos_final = clamped;
+            }
+        } else {
+            error!("Failed to adjust input, screen size is missing");
+        }
+
+        if self.input_options.reverse_coordinates {
+            std::mem::swap(&mut pos_final.x, &mut pos_final.y);
+        }
+        pos_final
+    }
+
+    // Gets an id and waits for the ack, pos is where to show if it landed
+    fn send_input(&self, input: Input, pos: Option<Pos2>) {
+        let message = self.inputs.lock().unwrap().send(input, pos, time::Instant::now());
+        self.send_network(message);
+    }
+
+    fn send_touch_down(&self, pos: Pos2, app_size: Vec2, randomise: bool) -> Pos2 {
+        let pos_final = self.device_position(pos, app_size, randomise);
+        self.send_input(Input::TouchDown(pos_final.x as u16, pos_final.y as u16), None);
+        pos_final
+    }
+
+    fn send_touch_up(&self, pos: Pos2, pos_final: Pos2, held_ms: u32) {
+        let input = Input::TouchUp {
+            x: pos_final.x as u16,
+            y: pos_final.y as u16,
+            held_ms,
+        };
+        self.send_input(input, Some(pos));
+    }
+
+    // Down and up right away, the device keeps the finger down for held_ms
+    fn send_click(&self, pos: Pos2, app_size: Vec2, held_ms: u32) {
+        if !self.supports_input(InputKind::Click) {
+            warn!("Ignoring click, the device didn't agree on clicks (no Hello yet?)");
+            return;
+        }
+        for repeat in 0..self.input_options.repeat_click {
+            debug!("Repeat number: {}", repeat);
+            debug!("Cursor clicked at: {:?}", pos);
+            let pos_final = self.send_touch_down(pos, app_size, true);
+            self.send_touch_up(pos, pos_final, held_ms);
+            std::thread::sleep(time::Duration::from_millis(self.input_options.input_repeat_delay_ms.into()));
+        }
+    }
+
+    // A single tap right there, repeats or a random shift would turn more than one page
+    fn send_tap(&self, pos: Pos2, app_size: Vec2) {
+        if !self.supports_input(InputKind::Click) {
+            warn!("Ignoring tap, the device didn't agree on clicks (no Hello yet?)");
+            return;
+        }
+        let pos_final = self.send_touch_down(pos, app_size, false);
+        self.send_touch_up(pos, pos_final, 0);
+    }
+
+    // The button is still down, so the finger goes down now and up on release
+    fn start_hold(&self, stroke: &Stroke, app_size: Vec2) -> bool {
+        if !self.supports_input(InputKind::Click) {
+            return false;
+        }
+        debug!("Long press at: {:?}", stroke.origin());
+        self.send_touch_down(stroke.origin(), app_size, false);
+        true
+    }
+
+    // Short and still strokes are clicks, long and still ones were sent as a hold
+    // already, the rest gets replayed on the device
+    fn send_stroke(&self, stroke: &Stroke, app_size: Vec2) {
+        let (end, held_ms) = stroke.last();
+        if stroke.holding {
+            let pos_final = self.device_position(end, app_size, false);
+            self.send_touch_up(end, pos_final, held_ms);
+            return;
+        }
+        if stroke.is_tap() {
+            self.send_click(stroke.origin(), app_size, held_ms);
+            return;
+        }
+        if stroke.pinch {
+            self.send_pinch(&stroke.pinch_steps(), app_size);
+            return;
+        }
+        self.send_gesture(stroke.points(), app_size);
+    }
+
+    fn send_gesture(&self, points: &[(Pos2, u32)], app_size: Vec2) {
+        if !self.supports_input(InputKind::Gesture) {
+            warn!("Ignoring drag, the device can't replay gestures (touch_emulate?)");
+            return;
+        }
+        let end = points.last().map(|(pos, _)| *pos);
+        let points = points
+            .iter()
+            .map(|(pos, time_ms)| {
+                let pos_final = self.device_position(*pos, app_size, false);
+                TouchPoint {
+                    x: pos_final.x as u16,
+                    y: pos_final.y as u16,
+                    time_ms: *time_ms,
+                }
+            })
+            .collect();
+        self.send_input(Input::Gesture(points), end);
+    }
+
+    // Mouse wheel, after it stopped turning
+    fn send_scroll(&self, scroll: &Scroll, app_size: Vec2) {
+        // Up and down cancelled out, a swipe of nothing would be a tap
+        if scroll.delta.y == 0.0 {
+            return;
+        }
+        match self.input_options.wheel_action {
+            WheelAction::Swipe => self.send_gesture(&scroll.swipe_points(app_size.y * 0.8), app_size),
+            WheelAction::Page => self.send_tap(scroll.page_tap(app_size), app_size),
+            WheelAction::Off => (),
+        }
+    }
+
+    // Both fingers go through the same transforms
+    fn send_pinch(&self, steps: &[PinchStep], app_size: Vec2) {
+        if !self.supports_input(InputKind::Pinch) {
+            warn!("Ignoring pinch, the device can't replay it (single touch or touch_emulate?)");
+            return;
+        }
+        let points = steps
+            .iter()
+            .map(|(first, second, time_ms)| {
+                let first = self.device_position(*first, app_size, false);
+                let second = self.device_position(*second, app_size, false);
+                PinchPoint {
+                    first: (first.x as u16, first.y as u16),
+                    second: (second.x as u16, second.y as u16),
+                    time_ms: *time_ms,
+                }
+            })
+            .collect();
+        let center = steps.last().map(|(first, second, _)| first.lerp(*second, 0.5));
+        self.send_input(Input::Pinch(points), center);
+    }
+
+    // Held keys are held on the device too, a long power press is a power off
+    fn send_key(&self, code: u16, pressed: bool) {
+        if !self.supports_input(InputKind::Key) {
+            warn!("Ignoring key {}, the device has no keys to press", code);
+            return;
+        }
+        self.send_input(Input::Key(code, pressed), None);
+    }
+
+    fn send_text(&self, text: String) {
+        if !self.supports_input(InputKind::Text) {
+            warn!("Ignoring typed text, the device can't type");
+            return;
+        }
+        self.send_input(Input::Text(text), None);
+    }
+
+    // F5, the device draws targets to click one by one
+    fn toggle_calibration(&mut self) {
+        if self.gui.calibration.take().is_some() {
+            info!("Calibration cancelled");
+            self.send_network(FromServerMessage::DrawTarget(None));
+            return;
+        }
+        if !self
+            .capabilities
+            .as_ref()
+            .is_some_and(|capabilities| capabilities.draw_targets)
+        {
+            warn!("Can't calibrate, the device can't draw targets");
+            return;
+        }
+        let Some(image_size) = self.gui.image_size else {
+            warn!("Can't calibrate before the screen size is known");
+            return;
+        };
+        let calibration = Calibration::default();
+        self.send_network(FromServerMessage::DrawTarget(calibration.next_target(image_size)));
+        self.gui.calibration = Some(calibration);
+        info!("Calibrating, tap the targets on the device and click them here");
+    }
+
+    // Arguments, then the profile for every model and the one for this model, then
+    // what was given on the command line
+    fn apply_profile(&mut self) {
+        match self.profiles.profile(self.model.as_deref()) {
+            Ok(profile) => {
+                let profile = self.arguments.over(profile).over(self.given);
+                info!("Input options for model {:?}: {:?}", self.model, profile);
+                self.input_options.apply(&profile);
+            }
+            Err(err) => {
+                let text = format!("Bad profile in {:?}, using the arguments: {}", self.profile_path, err);
+                error!("{}", text);
+                self.gui.error = Some(text);
+            }
+        }
+    }
+
+    // The device opens its touchscreen before it knows its profile, so it's told after Hello
+    fn send_touch_device(&mut self) {
+        match self.profiles.touch_device(self.model.as_deref()) {
+            Ok(TouchDevice { path: None, protocol: None }) => (),
+            Ok(TouchDevice { path, protocol }) => {
+                info!("Touch device for model {:?}: {:?} with protocol {:?}", self.model, path, protocol);
+                self.send_network(FromServerMessage::TouchDevice { path, protocol });
+            }
+            Err(err) => {
+                let text = format!("Bad profile in {:?}, leaving the touch device to the device: {}", self.profile_path, err);
+                error!("{}", text);
+                self.gui.error = Some(text);
+            }
+        }
+    }
+
+    // Input options and refresh delays follow the config file as it's saved
+    fn reload_config(&mut self) {
+        let Some(path) = self.config.as_ref().map(|watcher| watcher.path().to_path_buf()) else {
+            return;
+        };
+        let parsed = config::load(&path).and_then(|values| {
+            let matches = config::apply(Args::command(), &values)
+                .try_get_matches_from(&self.argv)
+                .map_err(|err| err.to_string())?;
+            let args = Args::from_arg_matches(&matches).map_err(|err| err.to_string())?;
+            Ok((args, matches))
+        });
+        let (args, matches) = match parsed {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                let text = format!("Bad config {:?}, keeping the old one: {}", path, err);
+                error!("{}", text);
+                self.gui.error = Some(text);
+                return;
+            }
+        };
+        info!("Reloaded the config {:?}", path);
+        (self.arguments, self.given) = argument_profiles(&args, &matches);
+        self.apply_profile();
+        self.delays = RefreshDelays::new(&args);
+        let (min_screen_delay_ms, screen_delay_ms) = self.delays.bounds();
+        self.pacer.lock().unwrap().set_intervals(min_screen_delay_ms, screen_delay_ms);
+        let streaming = args
+            .streaming
+            .then_some((args.stream_min_interval_ms, args.stream_max_interval_ms));
+        match (streaming, self.endpoint) {
+            // The device can't be told to stop pushing frames, it stops when it connects again
+            (None, Some(_)) if self.streaming.is_some() => {
+                info!("Streaming is turned off when the device connects again");
+            }
+            (Some((min_interval_ms, max_interval_ms)), Some(_)) if streaming != self.streaming => {
+                // Asking for frames too would only double them
+                self.stop_refresh();
+                self.send_network(FromServerMessage::StartStreaming {
+                    min_interval_ms,
+                    max_interval_ms,
+                    host_us: pacing::stamp(self.started, time::Instant::now()),
+                });
+            }
+            _ => (),
+        }
+        self.streaming = streaming;
+    }
+
+    // Connection, frames and the last error, in one line
+    fn show_status(&mut self, ui: &mut egui::Ui) {
+        let now = time::Instant::now();
+        ui.horizontal(|ui| {
+            match (self.gui.connected, self.endpoint) {
+                (true, Some(endpoint)) => ui.label(format!("Connected to {}", endpoint.addr())),
+                (true, None) => ui.label("Connecting"),
+                (false, Some(endpoint)) => ui.label(format!("Disconnected from {}", endpoint.addr())),
+                (false, None) => ui.label("Waiting for the device"),
+            };
+            ui.separator();
+            let stats = &mut self.gui.stats;
+            ui.label(format!("{:.1} fps", stats.fps(now)));
+            ui.separator();
+            match stats.latency() {
+                Some(latency) => ui.label(format!("{} ms", latency.as_millis())),
+                None => ui.label("- ms"),
+            }
+            .on_hover_text("From asking for a frame to showing it");
+            ui.separator();
+            ui.label(format!("{}/s", stats::human_bytes(stats.bandwidth(now))));
+            ui.separator();
+            match stats.bytes_per_frame(now) {
+                Some(bytes) => ui.label(format!("{} per frame", stats::human_bytes(bytes as f32))),
+                None => ui.label("- per frame"),
+            };
+            if let Some(error) = &self.gui.error {
+                ui.separator();
+                ui.colored_label(Color32::RED, error);
+            }
+        });
+    }
+
+    // What the settings panel shows goes into the config, the rest of the file stays
+    fn save_config(&mut self) {
+        let Some(watcher) = &mut self.config else {
+            return;
+        };
+        // The rotation comes from the device, not from the panel
+        let profile = Profile {
+            rotation: None,
+            ..self.input_options.profile()
+        };
+        let delays = &self.delays;
+        let saved = config::save(watcher.path(), |table| {
+            profile.write(table);
+            table["screen_delay_ms"] = toml_edit::value(delays.screen_delay_ms as i64);
+            table["min_screen_delay_ms"] = toml_edit::value(delays.min_screen_delay_ms as i64);
+            table["fixed_screen_delay"] = toml_edit::value(delays.fixed_screen_delay);
+        });
+        match saved {
+            Ok(()) => {
+                info!("Saved the settings to {:?}", watcher.path());
+                // Reloading would put the profiles back over what was just set
+                watcher.saved();
+            }
+            Err(err) => {
+                let text = format!("Failed to save the settings to {:?}: {}", watcher.path(), err);
+                error!("{}", text);
+                self.gui.error = Some(text);
+            }
+        }
+    }
+
+    // After the last target the transform is solved, used and saved
+    fn calibration_click(&mut self, pos: Pos2, app_size: Vec2) {
+        let (Some(mut calibration), Some(image_size)) = (self.gui.calibration.take(), self.gui.image_size) else {
+            return;
+        };
+        calibration.click(pos);
+        if let Some(target) = calibration.next_target(image_size) {
+            self.send_network(FromServerMessage::DrawTarget(Some(target)));
+            self.gui.calibration = Some(calibration);
+            return;
+        }
+        self.send_network(FromServerMessage::DrawTarget(None));
+        match calibration.solve(app_size, image_size, self.gui.rotation) {
+            Ok(profile) => {
+                info!("Calibrated: {:?}", profile);
+                self.input_options.apply(&profile);
+                self.profiles.set(self.model.as_deref(), &profile);
+                match self.profiles.save(&self.profile_path) {
+                    Ok(()) => info!("Saved the touch profile for model {:?} to {:?}", self.model, self.profile_path),
+                    Err(err) => {
+                        self.gui.error = Some(format!(
+                            "Failed to save the touch profile to {:?}: {}",
+                            self.profile_path, err
+                        ))
+                    }
+                }
+            }
+            Err(text) => {
+                error!("{}", text);
+                self.gui.error = Some(text);
+            }
+        }
+    }
+
+    // Only true after a successful Hello where both sides agreed on this input
+    pub fn supports_input(&self, input: InputKind) -> bool {
+        self.capabilities
+            .as_ref()
+            .is_some_and(|capabilities| capabilities.supports_input(input))
+    }
+
+    // Asks for the screen every pacer.interval(), until the connection changes
+    fn start_refresh(&mut self, endpoint: Endpoint) {
+        debug!("Creating screen refresh thread");
+        let generation = self.refresh.fetch_add(1, Ordering::SeqCst) + 1;
+        let refresh = self.refresh.clone();
+        let network_handler_image_delay = self.network_handler.clone();
+        let pacer = self.pacer.clone();
+        let started = self.started;
+
+        thread::spawn(move || {
+            loop {
+                if refresh.load(Ordering::SeqCst) != generation {
+                    debug!("Screen refresh thread stopped");
+                    break;
+                }
+                // TODO: sync, add thread to client for launching fbgrab, sync it too
+                let delay = {
+                    let mut pacer = pacer.lock().unwrap();
+                    let now = time::Instant::now();
+                    if pacer.can_request(now) {
+                        debug!("Refreshing screen");
+                        let requested_us = pacing::stamp(started, now);
+                        let data = FromServerMessage::RequestScreen { requested_us }.encode();
+                        network_handler_image_delay.network().send(endpoint, &data);
+                        pacer.request_sent(requested_us, now);
+                    } else {
+                        debug!("Device is still busy, not asking for the screen");
+                    }
+                    pacer.interval()
+                };
+                thread::sleep(delay);
+            }
+        });
+    }
+
+    // It notices before its next request
+    fn stop_refresh(&self) {
+        self.refresh.fetch_add(1, Ordering::SeqCst);
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -106,6 +746,11 @@ impl MyApp {
 pub struct Args {
     #[arg(short, long, help = "Network port to use", default_value_t = 24356)]
     port: u16,
+    #[arg(
+        long,
+        help = "Config file with the same keys as the arguments, like add_to_x = -8. The arguments go over it, changes are picked up while running [default: $XDG_CONFIG_HOME/mirkobo/host.toml]"
+    )]
+    config: Option<PathBuf>,
     #[arg(short, long, help = "Shift x in pixels (compensate for window frame for example), in touch", default_value_t = -8.0)]
     add_to_x: f32,
     #[arg(short, long, help = "Shift y in pixels (compensate for window frame for example), in touch", default_value_t = -9.0)]
@@ -153,13 +798,61 @@ pub struct Args {
         default_value_t = 1
     )]
     repeat_click: u32,
+    #[arg(
+        long,
+        value_enum,
+        help = "What the mouse wheel does on the device, swipe scrolls lists, page taps the edges of the screen to turn pages in readers",
+        default_value_t = WheelAction::Swipe
+    )]
+    wheel_action: WheelAction,
+    #[arg(
+        long,
+        help = "Profiles for each device model, loaded over the input options when the device connects. Options given here still win. F5 calibrates and saves into it [default: mirkobo-profile.toml]"
+    )]
+    profile: Option<PathBuf>,
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u32).range(0..=3),
+        help = "Screen rotation of the device the touch options are for, 0 to 3 like its framebuffer reports. Touches turn with the screen from there. By default the rotation when the device connects"
+    )]
+    rotation: Option<u32>,
     #[arg(
         short,
         long,
-        help = "Delay between screen refreshes in ms, 400 is fast but takes too much cpu, 1100 is good enough - if you want to go as fast as possible, look for \'Request for screen ignored, it's already in make\' errors on the client - it indicates that grabbing the framebuffer / network speed is the bottleneck",
+        help = "Longest delay between screen refreshes in ms. The delay adapts to how fast the device captures and the network transfers frames, between min_screen_delay_ms and this",
         default_value_t = 1100
     )]
     screen_delay_ms: u32,
+    #[arg(
+        long,
+        help = "Shortest delay between screen refreshes in ms",
+        default_value_t = 100
+    )]
+    min_screen_delay_ms: u32,
+    #[arg(
+        long,
+        help = "Always wait screen_delay_ms between screen refreshes, like older versions did",
+        default_value_t = false
+    )]
+    fixed_screen_delay: bool,
+    #[arg(
+        long,
+        help = "Let the device send frames when its screen changes, instead of asking every screen_delay_ms. Only cheap when the device reads its framebuffer, with fbgrab every check is a whole capture",
+        default_value_t = false
+    )]
+    streaming: bool,
+    #[arg(
+        long,
+        help = "With streaming, how often the device checks the screen right after a change, in ms",
+        default_value_t = 100
+    )]
+    stream_min_interval_ms: u32,
+    #[arg(
+        long,
+        help = "With streaming, how often the device checks the screen at most when nothing changes, in ms",
+        default_value_t = 2000
+    )]
+    stream_max_interval_ms: u32,
     #[arg(
         short,
         long,
@@ -176,10 +869,47 @@ pub struct Args {
     initial_screen_y: u32,
 }
 
+// Every input option from the arguments, and only the ones given on the command line
+fn argument_profiles(args: &Args, matches: &ArgMatches) -> (Profile, Profile) {
+    let arguments = Profile {
+        add_to_x: Some(args.add_to_x),
+        add_to_y: Some(args.add_to_y),
+        invert_x: Some(args.invert_x),
+        invert_y: Some(args.invert_y),
+        reverse_coordinates: Some(args.reverse_coordinates),
+        randomise_input_offset: Some(args.randomise_input_offset),
+        repeat_click: Some(args.repeat_click),
+        input_repeat_delay_ms: Some(args.input_repeat_delay_ms),
+        wheel_action: Some(args.wheel_action),
+        rotation: args.rotation,
+    };
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let given = Profile {
+        add_to_x: arguments.add_to_x.filter(|_| given("add_to_x")),
+        add_to_y: arguments.add_to_y.filter(|_| given("add_to_y")),
+        invert_x: arguments.invert_x.filter(|_| given("invert_x")),
+        invert_y: arguments.invert_y.filter(|_| given("invert_y")),
+        reverse_coordinates: arguments.reverse_coordinates.filter(|_| given("reverse_coordinates")),
+        randomise_input_offset: arguments.randomise_input_offset.filter(|_| given("randomise_input_offset")),
+        repeat_click: arguments.repeat_click.filter(|_| given("repeat_click")),
+        input_repeat_delay_ms: arguments.input_repeat_delay_ms.filter(|_| given("input_repeat_delay_ms")),
+        wheel_action: arguments.wheel_action.filter(|_| given("wheel_action")),
+        rotation: arguments.rotation.filter(|_| given("rotation")),
+    };
+    (arguments, given)
+}
+
 impl Default for MyApp {
     fn default() -> Self {
         // Arguments
-        let args = Args::parse();
+        let argv: Vec<OsString> = std::env::args_os().collect();
+        let (config_path, values) =
+            config::initial(&argv, config::default_path()).unwrap_or_else(|err| {
+                error!("{}", err);
+                std::process::exit(1)
+            });
+        let matches = config::apply(Args::command(), &values).get_matches_from(&argv);
+        let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
 
         let port = args.port;
         let input_options = InputOptions {
@@ -191,11 +921,29 @@ impl Default for MyApp {
             randomise_input_offset: args.randomise_input_offset,
             repeat_click: args.repeat_click,
             input_repeat_delay_ms: args.input_repeat_delay_ms,
+            wheel_action: args.wheel_action,
+            rotation: args.rotation,
         };
-        // 1100 uses 30% of cpu
-        // 400 uses 100%
-        // Using native fbink should help ;p
-        let screen_delay_ms = args.screen_delay_ms;
+        let (arguments, given) = argument_profiles(&args, &matches);
+        let profile_path = args
+            .profile
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_PROFILE_PATH));
+        let profiles = match Profiles::load(&profile_path) {
+            Ok(profiles) => profiles,
+            // Nothing calibrated yet
+            Err(_) if args.profile.is_none() && !profile_path.exists() => Profiles::default(),
+            Err(err) => {
+                error!("Failed to load the profiles from {:?}: {}", profile_path, err);
+                Profiles::default()
+            }
+        };
+        let delays = RefreshDelays::new(&args);
+        let (min_screen_delay_ms, screen_delay_ms) = delays.bounds();
+        let pacer = Arc::new(Mutex::new(FramePacer::new(min_screen_delay_ms, screen_delay_ms)));
+        let streaming = args
+            .streaming
+            .then_some((args.stream_min_interval_ms, args.stream_max_interval_ms));
         let mut initial_screen_size = None;
         if args.initial_screen_x != 0 && args.initial_screen_y != 0 {
             initial_screen_size = Some((args.initial_screen_x, args.initial_screen_y));
@@ -215,152 +963,399 @@ impl Default for MyApp {
         }
 
         let network_handler_server = network_handler.clone();
+        let pacer_server = pacer.clone();
+        let inputs = Arc::new(Mutex::new(InputTracker::default()));
+        let inputs_server = inputs.clone();
         thread::spawn(move || {
             tx_to_gui.send(ThreadCom::ConnectionActive(false)).unwrap();
-            server::run(network_handler_server, listener, tx_to_gui); // Enable websockets
+            server::run(network_handler_server, listener, tx_to_gui, pacer_server, inputs_server); // Enable websockets
         });
 
-        Self {
+        let mut app = Self {
             rx_to_gui,
             network_handler: network_handler.clone(),
             endpoint: None,
             gui: GuiVars::new(),
             input_options,
-            screen_delay_ms,
+            delays,
+            pacer,
+            refresh: Arc::new(AtomicU32::new(0)),
+            inputs,
+            streaming,
             initial_screen_size,
-        }
+            capabilities: None,
+            profiles,
+            profile_path,
+            arguments,
+            given,
+            model: None,
+            config: config_path.map(|path| config::Watcher::new(path, time::Instant::now())),
+            argv,
+            started: time::Instant::now(),
+        };
+        app.apply_profile();
+        app
     }
 }
 
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        // F6, before the central panel so it gets its space
+        if ctx.input(|i| i.key_pressed(egui::Key::F6)) {
+            self.gui.settings = !self.gui.settings;
+        }
+        let before = self.input_options.profile();
+        let edits = egui::SidePanel::right("settings")
+            .show_animated(ctx, self.gui.settings, |ui| {
+                settings::show(
+                    ui,
+                    &mut self.input_options,
+                    &mut self.delays,
+                    self.config.as_ref().map(|watcher| watcher.path()),
+                )
+            })
+            .map(|response| response.inner)
+            .unwrap_or_default();
+        if edits.input {
+            // Like typed on the command line, profiles of devices that connect later don't undo it
+            let changed = Profile::changed(before, self.input_options.profile());
+            debug!("Settings changed: {:?}", changed);
+            self.given = self.given.over(changed);
+        }
+        if edits.delays {
+            let (min_screen_delay_ms, screen_delay_ms) = self.delays.bounds();
+            self.pacer.lock().unwrap().set_intervals(min_screen_delay_ms, screen_delay_ms);
+        }
+        if edits.save {
+            self.save_config();
+        }
+
+        // F7, the bottom panel needs to be there before the central one too
+        if ctx.input(|i| i.key_pressed(egui::Key::F7)) {
+            self.gui.status = !self.gui.status;
+        }
+        self.gui.status_height = egui::TopBottomPanel::bottom("status")
+            .show_animated(ctx, self.gui.status, |ui| self.show_status(ui))
+            .map(|response| response.response.rect.height())
+            .unwrap_or(0.0);
+
         egui::CentralPanel::default().show(ctx, |ui| {
             //info!("Running events");
-            if let Ok(event) = self.rx_to_gui.try_recv() {
+            while let Ok(event) = self.rx_to_gui.try_recv() {
                 match event {
                     ThreadCom::ConnectionActive(status) => {
                         info!("Gui received connection status: {}", status);
+                        self.gui.connected = status;
+                        if !status {
+                            self.stop_refresh();
+                        }
                     }
-                    ThreadCom::ClientConnected(endpoint, _resource_id) => {
-                        info!("Gui received: ClientConnected");
+                    ThreadCom::ClientConnected(endpoint, capabilities, model) => {
+                        info!("Gui received: ClientConnected with {:?}, model {:?}", capabilities, model);
                         self.endpoint = Some(endpoint);
-                        debug!("Creating screen refresh thread");
-                        let network_handler_image_delay = self.network_handler.clone();
-
-                        let delay = self.screen_delay_ms as u64;
-
-                        thread::spawn(move || {
-                            loop {
-                                // TODO: sync, make clicks deliver always, add thread to client for launching fbgrab, sync it too
-                                thread::sleep(time::Duration::from_millis(delay));
-                                debug!("Refreshing screen");
-                                let data =
-                                    bincode::serialize(&FromServerMessage::RequestScreen).unwrap();
-                                network_handler_image_delay.network().send(endpoint, &data);
-                            }
-                        });
+                        self.capabilities = Some(capabilities);
+                        self.gui.error = None;
+                        self.inputs.lock().unwrap().reset(time::Instant::now());
+                        self.stop_refresh();
+                        self.pacer.lock().unwrap().reset();
+                        self.gui.stats.reset();
+                        self.gui.rotation = None;
+                        self.gui.first_rotation = None;
+                        if model != self.model {
+                            self.model = model;
+                            self.apply_profile();
+                        }
+                        self.send_touch_device();
+                        if let Some((min_interval_ms, max_interval_ms)) = self.streaming {
+                            info!("Asking the device to stream its screen");
+                            self.send_network(FromServerMessage::StartStreaming {
+                                min_interval_ms,
+                                max_interval_ms,
+                                host_us: pacing::stamp(self.started, time::Instant::now()),
+                            });
+                            continue;
+                        }
+                        self.start_refresh(endpoint);
                     }
-                    ThreadCom::Screen(file) => {
+                    ThreadCom::Screen((x, y), pixels) => {
                         //debug!("ThreadCom screen called");
-                        if let Ok(image) = RetainedImage::from_image_bytes("png", &file) {
-                            self.gui.image = Some(image);
+                        let image = gray_image(x, y, &pixels);
+                        match &mut self.gui.image {
+                            Some(texture) => texture.set(image, TextureOptions::default()),
+                            None => {
+                                self.gui.image =
+                                    Some(ctx.load_texture("screen", image, TextureOptions::default()))
+                            }
+                        }
+                    }
+                    ThreadCom::Patch(patches) => {
+                        if let Some(texture) = &mut self.gui.image {
+                            for (rect, pixels) in patches {
+                                let image = gray_image(rect.width, rect.height, &pixels);
+                                texture.set_partial(
+                                    [rect.x as usize, rect.y as usize],
+                                    image,
+                                    TextureOptions::default(),
+                                );
+                            }
                         } else {
-                            warn!("Failed to get image from bytes");
+                            warn!("Received a patch before the first frame");
                         }
                     }
+                    ThreadCom::Received(bytes, stamp_us) => {
+                        // The texture was just updated, it's shown this frame
+                        let latency = stamp_us
+                            .and_then(|stamp_us| pacing::since_stamp(self.started, stamp_us, time::Instant::now()));
+                        self.gui.stats.frame(time::Instant::now(), bytes, latency);
+                    }
                     ThreadCom::ScreenSize((x, y)) => {
                         debug!("Setting ui size... x:{}, y:{}", x, y);
                         let vec = Vec2::new(x as f32, y as f32);
                         if let Some(size) = self.initial_screen_size {
-                            _frame.set_window_size(Vec2::new(size.0 as f32, size.1 as f32));
+                            // Turned like the screen, it changes when the device is turned
+                            let mut size = Vec2::new(size.0 as f32, size.1 as f32);
+                            if (size.x > size.y) != (vec.x > vec.y) {
+                                size = Vec2::new(size.y, size.x);
+                            }
+                            _frame.set_window_size(size + Vec2::new(0.0, self.gui.status_height));
                         } else {
-                            _frame.set_window_size(vec);
+                            _frame.set_window_size(vec + Vec2::new(0.0, self.gui.status_height));
                         }
                         ui.set_max_size(vec);
                         ui.set_min_size(vec);
                         self.gui.image_size = Some(vec);
                     }
+                    ThreadCom::Rotation(rotation) => {
+                        info!("Device screen rotation is {}", rotation);
+                        self.gui.first_rotation.get_or_insert(rotation);
+                        self.gui.rotation = Some(rotation);
+                    }
+                    ThreadCom::TargetTouched((x, y)) => match &mut self.gui.calibration {
+                        Some(calibration) => calibration.touched(Pos2::new(x as f32, y as f32)),
+                        None => debug!("Device was tapped at x:{} y:{} without a target", x, y),
+                    },
+                    ThreadCom::Error(text) => {
+                        self.gui.error = Some(text);
+                    }
                 }
             }
 
-            if let Some(pos) = ctx.input(|i| i.pointer.press_origin()) {
-                if self.gui.cursor_count == 0 {
-                    for repeat in 0..self.input_options.repeat_click {
-                        debug!("Repeat number: {}", repeat);
-                        debug!("Cursor clicked at: {:?}", pos);
-                        let mut pos_final = pos;
-                        pos_final.y += self.input_options.add_to_y;
-                        pos_final.x += self.input_options.add_to_x;
-
-                        // Adjust input
-                        if let Some(image_size) = &self.gui.image_size {
-                            // Map the value to the size...
-                            let app_size = ui.available_size();
-                            debug!("App size: {:?}", app_size);
-                            let scale_x = image_size.x / app_size.x;
-                            let scale_y = image_size.y / app_size.y;
-                            debug!("scale_x:{} scale_y:{}", scale_x, scale_y);
-                            pos_final.x *= scale_x;
-                            pos_final.y *= scale_y;
-
-                            // Shift randomise
-                            if self.input_options.randomise_input_offset != 0 {
-                                debug!(
-                                    "Before randomised shifting: x:{} y:{}",
-                                    pos_final.x, pos_final.y
-                                );
-                                let mut rng = rand::thread_rng();
-                                pos_final.x = rng.gen_range(
-                                    pos_final.x - self.input_options.randomise_input_offset as f32
-                                        ..pos_final.x
-                                            + self.input_options.randomise_input_offset as f32
-                                            + 1.0,
-                                ); // +1 is because rust is stupid and does -1
-                                pos_final.y = rng.gen_range(
-                                    pos_final.y - self.input_options.randomise_input_offset as f32
-                                        ..pos_final.y
-                                            + self.input_options.randomise_input_offset as f32
-                                            + 1.0,
-                                );
-                                debug!(
-                                    "After randomised shifting: x:{} y:{}",
-                                    pos_final.x, pos_final.y
-                                );
-                            }
+            // Otherwise it's in the status bar
+            if let Some(error) = self.gui.error.as_ref().filter(|_| !self.gui.status) {
+                ui.colored_label(egui::Color32::RED, error);
+            }
 
-                            if self.input_options.invert_x {
-                                pos_final.x = image_size.x - pos_final.x;
-                            }
-                            if self.input_options.invert_y {
-                                pos_final.y = image_size.y - pos_final.y;
-                            }
-                        } else {
-                            error!("Failed to adjust input, screen size is missing");
+            // Not while a text field here has the keyboard, but releases still go so nothing stays held
+            let focused = ctx.wants_keyboard_input();
+
+            // F1 power, F2 home, F3 light, page up and down turn pages
+            let keys: Vec<(u16, bool)> = ctx.input(|i| {
+                i.events
+                    .iter()
+                    .filter_map(|event| match event {
+                        egui::Event::Key { key, pressed, repeat: false, .. } => {
+                            Some((device_key(*key)?, *pressed))
                         }
+                        _ => None,
+                    })
+                    .filter(|(_, pressed)| !focused || !pressed)
+                    .collect()
+            });
+            for (code, pressed) in keys {
+                self.send_key(code, pressed);
+            }
 
-                        if self.input_options.reverse_coordinates {
-                            std::mem::swap(&mut pos_final.x, &mut pos_final.y);
+            // F4 starts and stops typing, the text goes to whatever has focus on the device
+            let (toggle_typing, typed) = ctx.input(|i| {
+                let typed: String = i
+                    .events
+                    .iter()
+                    .filter_map(|event| match event {
+                        egui::Event::Text(text) | egui::Event::Paste(text) => Some(text.as_str()),
+                        egui::Event::Key { key: egui::Key::Enter, pressed: true, .. } => Some("\n"),
+                        egui::Event::Key { key: egui::Key::Backspace, pressed: true, .. } => {
+                            Some("\u{8}")
                         }
+                        _ => None,
+                    })
+                    .collect();
+                (i.key_pressed(egui::Key::F4), typed)
+            });
+            if ctx.input(|i| i.key_pressed(egui::Key::F5)) {
+                self.toggle_calibration();
+            }
+            if toggle_typing {
+                self.gui.typing = !self.gui.typing;
+                info!("Typing to the device: {}", self.gui.typing);
+            }
+            if self.gui.typing && !focused && !typed.is_empty() {
+                self.send_text(typed);
+            }
+
+            if self
+                .config
+                .as_mut()
+                .is_some_and(|watcher| watcher.changed(time::Instant::now()))
+            {
+                self.reload_config();
+            }
+
+            let (again, failed) = self.inputs.lock().unwrap().retries(time::Instant::now());
+            for message in again {
+                self.send_network(message);
+            }
+            if failed.is_some() {
+                self.gui.error = failed;
+            }
 
-                        self.send_network(FromServerMessage::Click(
-                            pos_final.x as u16,
-                            pos_final.y as u16,
-                        ));
-                        std::thread::sleep(time::Duration::from_millis(self.input_options.input_repeat_delay_ms.into()));
+            let app_size = ui.available_size();
+            // Clicks on the settings panel aren't for the device
+            let area = ui.max_rect();
+            let (pressed, released, pos, ctrl, zoom_delta, scroll_delta) = ctx.input(|i| {
+                (
+                    i.pointer.primary_pressed(),
+                    i.pointer.primary_released(),
+                    i.pointer.interact_pos(),
+                    i.modifiers.ctrl,
+                    i.zoom_delta(),
+                    i.scroll_delta,
+                )
+            });
+            let inside = pos.filter(|pos| area.contains(*pos));
+            if pressed {
+                if let Some(pos) = inside {
+                    if self.gui.calibration.is_some() {
+                        // Only finds the target, nothing goes to the device
+                        self.calibration_click(pos, app_size);
+                    } else {
+                        let mut stroke = Stroke::new(pos);
+                        stroke.pinch = ctrl;
+                        self.gui.stroke = Some(stroke);
                     }
                 }
-                self.gui.cursor_count += 1;
-                if self.gui.cursor_count >= 3 {
-                    self.gui.cursor_count = 0;
+            } else if let (Some(stroke), Some(pos)) = (&mut self.gui.stroke, pos) {
+                if released {
+                    stroke.end(pos);
+                } else {
+                    stroke.sample(pos);
+                }
+            }
+            if released {
+                if let Some(stroke) = self.gui.stroke.take() {
+                    self.send_stroke(&stroke, app_size);
+                }
+            }
+            // ctrl+scroll
+            if zoom_delta != 1.0 {
+                if let Some(pos) = inside {
+                    self.gui.zoom.get_or_insert_with(|| Zoom::new(pos)).add(zoom_delta);
+                }
+            }
+            // The wheel, with ctrl it's a zoom. Sideways scrolling has nothing to do on the device
+            if scroll_delta.y != 0.0 && !ctrl {
+                if let Some(pos) = inside {
+                    self.gui.scroll.get_or_insert_with(|| Scroll::new(pos)).add(scroll_delta);
+                }
+            }
+            if let Some(scroll) = self.gui.scroll.take() {
+                if scroll.is_done() {
+                    debug!("Scrolled by {:?} at {:?}", scroll.delta, scroll.center);
+                    self.send_scroll(&scroll, app_size);
+                } else {
+                    self.gui.scroll = Some(scroll);
+                    ctx.request_repaint_after(time::Duration::from_millis(50));
+                }
+            }
+            if let Some(zoom) = self.gui.zoom.take() {
+                if zoom.is_done() {
+                    debug!("Zooming by {} at {:?}", zoom.factor, zoom.center);
+                    self.send_pinch(&zoom.pinch_steps(), app_size);
+                } else {
+                    self.gui.zoom = Some(zoom);
+                    ctx.request_repaint_after(time::Duration::from_millis(50));
                 }
             }
+            if let Some(mut stroke) = self.gui.stroke.take() {
+                if stroke.starts_hold(stroke.elapsed_ms()) {
+                    stroke.holding = self.start_hold(&stroke, app_size);
+                }
+                self.gui.stroke = Some(stroke);
+                // Keep updating, nothing else wakes us up while the mouse is still
+                ctx.request_repaint_after(time::Duration::from_millis(20));
+            }
 
             if let Some(image) = &self.gui.image {
                 //debug!("Showing image");
-                image.show_size(ui, ui.available_size());
+                ui.image(image.id(), ui.available_size());
+            }
+            // Where the taps went, yellow until the device acks them
+            let mut inputs = self.inputs.lock().unwrap();
+            for mark in inputs.marks(time::Instant::now()) {
+                let color = match mark.delivery {
+                    Delivery::Pending => Color32::YELLOW,
+                    Delivery::Landed => Color32::GREEN,
+                    Delivery::Failed(_) => Color32::RED,
+                };
+                ui.painter().circle_stroke(mark.pos, 10.0, (2.0, color));
+            }
+            if !inputs.is_idle() {
+                ctx.request_repaint_after(time::Duration::from_millis(100));
+            }
+            drop(inputs);
+
+            let mut notes = Vec::new();
+            if self.gui.typing {
+                notes.push(String::from("Typing to the device, F4 to stop"));
+            }
+            if let Some(calibration) = &self.gui.calibration {
+                let (step, steps) = calibration.step();
+                let tap = match calibration.is_touched() {
+                    true => "",
+                    false => "tap it on the device, then ",
+                };
+                notes.push(format!(
+                    "Calibrating target {}/{}: {}click its middle here, F5 to cancel",
+                    step, steps, tap
+                ));
+            }
+            for (n, note) in notes.into_iter().enumerate() {
+                ui.painter().text(
+                    ui.max_rect().left_top() + Vec2::new(4.0, 4.0 + n as f32 * 20.0),
+                    egui::Align2::LEFT_TOP,
+                    note,
+                    egui::FontId::proportional(16.0),
+                    Color32::RED,
+                );
             }
 
-            ctx.request_repaint_after(time::Duration::from_millis(self.screen_delay_ms as u64 / 5));
+            ctx.request_repaint_after(self.pacer.lock().unwrap().interval() / 5);
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every input MyApp has a send_ method for
+    const SENT: [InputKind; 5] = [
+        InputKind::Click,
+        InputKind::Gesture,
+        InputKind::Pinch,
+        InputKind::Key,
+        InputKind::Text,
+    ];
+
+    #[test]
+    fn every_input_we_send_is_advertised() {
+        // A device that can do all of them, so only the host can drop one
+        let device = Capabilities {
+            inputs: SENT.to_vec(),
+            ..Capabilities::default()
+        };
+        let common = host_capabilities().common(&device);
+        for input in SENT {
+            assert!(common.supports_input(input), "{:?} isn't advertised", input);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// Logging
+use log::{debug, warn};
+
+// Network
+use mir_kobo_proto::FrameTiming;
+
+// Other
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// One frame being made on the device and one waiting in its queue, more only piles up
+const MAX_IN_FLIGHT: usize = 2;
+
+// Weight of a new measurement in the running averages
+const SMOOTHING: f64 = 0.25;
+
+pub fn smooth(average: Option<Duration>, sample: Duration) -> Duration {
+    match average {
+        Some(average) => average.mul_f64(1.0 - SMOOTHING) + sample.mul_f64(SMOOTHING),
+        None => sample,
+    }
+}
+
+// What goes into RequestScreen and StartStreaming, microseconds since the host started.
+// Only this host compares it, and a monotonic clock doesn't jump when the wall clock is set
+pub fn stamp(started: Instant, at: Instant) -> u64 {
+    at.saturating_duration_since(started).as_micros() as u64
+}
+
+// From the request to now, None if the stamp is later than now
+pub fn since_stamp(started: Instant, requested_us: u64, now: Instant) -> Option<Duration> {
+    stamp(started, now)
+        .checked_sub(requested_us)
+        .map(Duration::from_micros)
+}
+
+// Decides when to send the next RequestScreen. Frames go through two stages, the
+// device capturing and encoding, then the network. The slower one sets the pace
+pub struct FramePacer {
+    min: Duration,
+    max: Duration,
+    in_flight: VecDeque<(u64, Instant)>, // Stamp of each unanswered request and when it was sent
+    rtt: Option<Duration>,               // Request sent to frame received
+    device: Option<Duration>,            // Capture plus encode
+    device_min: Duration,                // The device cpu budget
+}
+
+impl FramePacer {
+    pub fn new(min_interval_ms: u32, max_interval_ms: u32) -> Self {
+        let min = Duration::from_millis(min_interval_ms.max(1) as u64);
+        FramePacer {
+            min,
+            max: Duration::from_millis(max_interval_ms as u64).max(min),
+            in_flight: VecDeque::new(),
+            rtt: None,
+            device: None,
+            device_min: Duration::ZERO,
+        }
+    }
+
+    // New bounds, like from a reloaded config. What was measured stays
+    pub fn set_intervals(&mut self, min_interval_ms: u32, max_interval_ms: u32) {
+        let bounds = FramePacer::new(min_interval_ms, max_interval_ms);
+        self.min = bounds.min;
+        self.max = bounds.max;
+    }
+
+    // Another connection, maybe to another device. Only the bounds stay
+    pub fn reset(&mut self) {
+        *self = FramePacer {
+            min: self.min,
+            max: self.max,
+            ..FramePacer::new(0, 0)
+        };
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn device_time(&self) -> Option<Duration> {
+        self.device
+    }
+
+    // What's left of the round trip after the device did its part
+    pub fn transfer_time(&self) -> Option<Duration> {
+        Some(self.rtt?.saturating_sub(self.device?))
+    }
+
+    // Time between requests. Until something is measured, start slow
+    pub fn interval(&self) -> Duration {
+        let (Some(device), Some(transfer)) = (self.device, self.transfer_time()) else {
+            return self.max;
+        };
+        device
+            .max(transfer)
+            .max(self.device_min)
+            .clamp(self.min, self.max)
+    }
+
+    // Requests the device dropped or frames lost on the way would block us forever
+    fn forget_lost(&mut self, now: Instant) {
+        let timeout = match self.rtt {
+            Some(rtt) => (rtt * 4).max(self.max),
+            None => self.max * 4,
+        };
+        while let Some((_, sent)) = self.in_flight.front() {
+            if now.duration_since(*sent) < timeout {
+                break;
+            }
+            warn!("No frame for a request after {:?}, forgetting it", timeout);
+            self.in_flight.pop_front();
+        }
+    }
+
+    pub fn can_request(&mut self, now: Instant) -> bool {
+        self.forget_lost(now);
+        self.in_flight.len() < MAX_IN_FLIGHT
+    }
+
+    pub fn request_sent(&mut self, requested_us: u64, at: Instant) {
+        self.in_flight.push_back((requested_us, at));
+    }
+
+    // Only for frames that answer a RequestScreen. The device answers in order, so the
+    // requests before this one were dropped
+    pub fn frame_received(&mut self, requested_us: u64, at: Instant) {
+        let Some(answered) = self
+            .in_flight
+            .iter()
+            .position(|(stamp, _)| *stamp == requested_us)
+        else {
+            debug!("Frame for a request that was forgotten, or sent before a reconnect");
+            return;
+        };
+        if let Some((_, sent)) = self.in_flight.drain(..=answered).next_back() {
+            self.rtt = Some(smooth(self.rtt, at.duration_since(sent)));
+        }
+    }
+
+    pub fn timing(&mut self, timing: &FrameTiming) {
+        let device = Duration::from_micros(timing.capture_us as u64 + timing.encode_us as u64);
+        self.device = Some(smooth(self.device, device));
+        self.device_min = Duration::from_micros(timing.min_interval_us as u64);
+        debug!(
+            "Frame {} took {:?} on the device, averages: device {:?}, rtt {:?}. Next request in {:?}",
+            timing.sequence,
+            device,
+            self.device_time(),
+            self.rtt(),
+            self.interval()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(device_ms: u32, min_interval_ms: u32) -> FrameTiming {
+        FrameTiming {
+            sequence: 0,
+            capture_us: device_ms * 1000 / 2,
+            encode_us: device_ms * 1000 / 2,
+            min_interval_us: min_interval_ms * 1000,
+        }
+    }
+
+    // Sends a request at start and gets the frame after rtt_ms
+    fn frame(pacer: &mut FramePacer, start: Instant, rtt_ms: u64, device_ms: u32, min_ms: u32) {
+        pacer.request_sent(7, start);
+        pacer.frame_received(7, start + Duration::from_millis(rtt_ms));
+        pacer.timing(&timing(device_ms, min_ms));
+    }
+
+    #[test]
+    fn starts_slow() {
+        let mut pacer = FramePacer::new(50, 3000);
+        assert_eq!(pacer.interval(), Duration::from_millis(3000));
+        pacer.set_intervals(50, 1000);
+        assert_eq!(pacer.interval(), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn follows_the_slower_stage() {
+        let start = Instant::now();
+        // Slow device
+        let mut pacer = FramePacer::new(50, 3000);
+        frame(&mut pacer, start, 500, 400, 0);
+        assert_eq!(pacer.transfer_time(), Some(Duration::from_millis(100)));
+        assert_eq!(pacer.interval(), Duration::from_millis(400));
+        // Slow network
+        let mut pacer = FramePacer::new(50, 3000);
+        frame(&mut pacer, start, 500, 100, 0);
+        assert_eq!(pacer.interval(), Duration::from_millis(400));
+        // Both fast, but not faster than min
+        let mut pacer = FramePacer::new(50, 3000);
+        frame(&mut pacer, start, 10, 5, 0);
+        assert_eq!(pacer.interval(), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn device_budget_wins() {
+        let mut pacer = FramePacer::new(50, 3000);
+        frame(&mut pacer, Instant::now(), 200, 100, 1000);
+        assert_eq!(pacer.interval(), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn averages_measurements() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(10, 3000);
+        frame(&mut pacer, start, 100, 100, 0);
+        frame(&mut pacer, start, 500, 500, 0);
+        assert_eq!(pacer.device_time(), Some(Duration::from_millis(200)));
+        assert_eq!(pacer.rtt(), Some(Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn in_flight_is_limited_and_lost_requests_expire() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(50, 1000);
+        for n in 0..MAX_IN_FLIGHT {
+            assert!(pacer.can_request(start));
+            pacer.request_sent(n as u64, start);
+        }
+        assert!(!pacer.can_request(start + Duration::from_millis(500)));
+        // Nothing measured yet, so 4 times max
+        assert!(pacer.can_request(start + Duration::from_millis(4000)));
+    }
+
+    #[test]
+    fn only_answers_are_measured() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(50, 3000);
+        pacer.request_sent(1, start);
+        pacer.request_sent(2, start + Duration::from_millis(100));
+        // A keyframe after RequestKeyframe, a streamed frame or a stale answer
+        pacer.frame_received(9, start + Duration::from_millis(150));
+        assert_eq!(pacer.rtt(), None);
+        // The first one was dropped on the device
+        pacer.frame_received(2, start + Duration::from_millis(400));
+        assert_eq!(pacer.rtt(), Some(Duration::from_millis(300)));
+        assert!(pacer.in_flight.is_empty());
+        pacer.request_sent(3, start);
+        pacer.reset();
+        assert!(pacer.in_flight.is_empty());
+        assert_eq!(pacer.rtt(), None);
+        assert_eq!(pacer.interval(), Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn stamps_come_back_as_latency() {
+        let started = Instant::now();
+        let requested = started + Duration::from_secs(2);
+        let shown = requested + Duration::from_millis(350);
+        assert_eq!(stamp(started, requested), 2_000_000);
+        assert_eq!(
+            since_stamp(started, stamp(started, requested), shown),
+            Some(Duration::from_millis(350))
+        );
+        assert_eq!(since_stamp(started, stamp(started, shown), requested), None);
+    }
+}
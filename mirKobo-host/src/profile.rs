@@ -0,0 +1,451 @@
+// Logging
+use log::{debug, warn};
+
+// Arguments
+use crate::gesture::WheelAction;
+use clap::ValueEnum;
+
+// Network
+use mir_kobo_proto::TouchProtocol;
+
+// Other
+use std::io;
+use std::path::Path;
+use toml_edit::{value, Document, Item, Table};
+
+// Where the profiles are read from and the calibration saved when there is no --profile
+pub const DEFAULT_PROFILE_PATH: &str = "mirkobo-profile.toml";
+
+const KEYS: [&str; 12] = [
+    "add_to_x",
+    "add_to_y",
+    "invert_x",
+    "invert_y",
+    "reverse_coordinates",
+    "randomise_input_offset",
+    "repeat_click",
+    "input_repeat_delay_ms",
+    "wheel_action",
+    "rotation",
+    "touch_device",
+    "touch_protocol",
+];
+
+// Input options for one device, None leaves it to whatever is under it. The
+// calibration finds the first five, and saves the rotation they are for
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Profile {
+    pub add_to_x: Option<f32>,
+    pub add_to_y: Option<f32>,
+    pub invert_x: Option<bool>,
+    pub invert_y: Option<bool>,
+    pub reverse_coordinates: Option<bool>,
+    pub randomise_input_offset: Option<u32>,
+    pub repeat_click: Option<u32>,
+    pub input_repeat_delay_ms: Option<u32>,
+    pub wheel_action: Option<WheelAction>,
+    pub rotation: Option<u32>, // Of the screen when the touch options were made, 0 to 3
+}
+
+// The touchscreen of the device, which opens it, the host only passes it on after Hello.
+// Not in Profile, nothing here uses it and a path isn't Copy
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TouchDevice {
+    pub path: Option<String>,
+    pub protocol: Option<TouchProtocol>,
+}
+
+impl TouchDevice {
+    fn from_table(table: &Table, name: &str) -> Result<TouchDevice, String> {
+        let error = |err: String| format!("In the {} profile: {}", name, err);
+        Ok(TouchDevice {
+            path: string(table, "touch_device").map_err(error)?,
+            protocol: touch_protocol(table, "touch_protocol").map_err(error)?,
+        })
+    }
+}
+
+fn float(table: &Table, key: &str) -> Result<Option<f32>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(item) => {
+            // 5 and 5.0 are both fine
+            let number = item
+                .as_float()
+                .or_else(|| item.as_integer().map(|n| n as f64));
+            number
+                .map(|n| Some(n as f32))
+                .ok_or_else(|| format!("{} should be a number", key))
+        }
+    }
+}
+
+fn boolean(table: &Table, key: &str) -> Result<Option<bool>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(item) => item
+            .as_bool()
+            .map(Some)
+            .ok_or_else(|| format!("{} should be true or false", key)),
+    }
+}
+
+fn unsigned(table: &Table, key: &str) -> Result<Option<u32>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(item) => item
+            .as_integer()
+            .and_then(|n| u32::try_from(n).ok())
+            .map(Some)
+            .ok_or_else(|| format!("{} should be a whole number, 0 or more", key)),
+    }
+}
+
+fn wheel_action(table: &Table, key: &str) -> Result<Option<WheelAction>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(item) => item
+            .as_str()
+            .and_then(|text| WheelAction::from_str(text, true).ok())
+            .map(Some)
+            .ok_or_else(|| format!("{} should be \"swipe\", \"page\" or \"off\"", key)),
+    }
+}
+
+fn string(table: &Table, key: &str) -> Result<Option<String>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(item) => item
+            .as_str()
+            .map(|text| Some(text.to_string()))
+            .ok_or_else(|| format!("{} should be a string", key)),
+    }
+}
+
+// Named like --touch-protocol on the device
+fn touch_protocol(table: &Table, key: &str) -> Result<Option<TouchProtocol>, String> {
+    match table.get(key).map(|item| item.as_str()) {
+        None => Ok(None),
+        Some(Some("a")) => Ok(Some(TouchProtocol::A)),
+        Some(Some("b")) => Ok(Some(TouchProtocol::B)),
+        Some(Some("single")) => Ok(Some(TouchProtocol::Single)),
+        Some(_) => Err(format!("{} should be \"a\", \"b\" or \"single\"", key)),
+    }
+}
+
+fn rotation(table: &Table, key: &str) -> Result<Option<u32>, String> {
+    match unsigned(table, key)? {
+        Some(rotation) if rotation > 3 => Err(format!("{} should be 0, 1, 2 or 3", key)),
+        rotation => Ok(rotation),
+    }
+}
+
+impl Profile {
+    // Values set in other win
+    pub fn over(self, other: Profile) -> Profile {
+        Profile {
+            add_to_x: other.add_to_x.or(self.add_to_x),
+            add_to_y: other.add_to_y.or(self.add_to_y),
+            invert_x: other.invert_x.or(self.invert_x),
+            invert_y: other.invert_y.or(self.invert_y),
+            reverse_coordinates: other.reverse_coordinates.or(self.reverse_coordinates),
+            randomise_input_offset: other.randomise_input_offset.or(self.randomise_input_offset),
+            repeat_click: other.repeat_click.or(self.repeat_click),
+            input_repeat_delay_ms: other.input_repeat_delay_ms.or(self.input_repeat_delay_ms),
+            wheel_action: other.wheel_action.or(self.wheel_action),
+            rotation: other.rotation.or(self.rotation),
+        }
+    }
+
+    // Just the values that differ between the two
+    pub fn changed(before: Profile, after: Profile) -> Profile {
+        Profile {
+            add_to_x: after.add_to_x.filter(|_| after.add_to_x != before.add_to_x),
+            add_to_y: after.add_to_y.filter(|_| after.add_to_y != before.add_to_y),
+            invert_x: after.invert_x.filter(|_| after.invert_x != before.invert_x),
+            invert_y: after.invert_y.filter(|_| after.invert_y != before.invert_y),
+            reverse_coordinates: after
+                .reverse_coordinates
+                .filter(|_| after.reverse_coordinates != before.reverse_coordinates),
+            randomise_input_offset: after
+                .randomise_input_offset
+                .filter(|_| after.randomise_input_offset != before.randomise_input_offset),
+            repeat_click: after
+                .repeat_click
+                .filter(|_| after.repeat_click != before.repeat_click),
+            input_repeat_delay_ms: after
+                .input_repeat_delay_ms
+                .filter(|_| after.input_repeat_delay_ms != before.input_repeat_delay_ms),
+            wheel_action: after
+                .wheel_action
+                .filter(|_| after.wheel_action != before.wheel_action),
+            rotation: after.rotation.filter(|_| after.rotation != before.rotation),
+        }
+    }
+
+    fn from_table(table: &Table, name: &str) -> Result<Profile, String> {
+        for (key, item) in table.iter() {
+            // At the top the tables are the models
+            let model = name == "top" && item.is_table();
+            if !model && !KEYS.contains(&key) {
+                warn!("Unknown key {} in the {} profile, ignoring it", key, name);
+            }
+        }
+        let error = |err: String| format!("In the {} profile: {}", name, err);
+        Ok(Profile {
+            add_to_x: float(table, "add_to_x").map_err(error)?,
+            add_to_y: float(table, "add_to_y").map_err(error)?,
+            invert_x: boolean(table, "invert_x").map_err(error)?,
+            invert_y: boolean(table, "invert_y").map_err(error)?,
+            reverse_coordinates: boolean(table, "reverse_coordinates").map_err(error)?,
+            randomise_input_offset: unsigned(table, "randomise_input_offset").map_err(error)?,
+            repeat_click: unsigned(table, "repeat_click").map_err(error)?,
+            input_repeat_delay_ms: unsigned(table, "input_repeat_delay_ms").map_err(error)?,
+            wheel_action: wheel_action(table, "wheel_action").map_err(error)?,
+            rotation: rotation(table, "rotation").map_err(error)?,
+        })
+    }
+
+    // Only what is set, the rest of the table stays
+    pub fn write(&self, table: &mut Table) {
+        if let Some(add_to_x) = self.add_to_x {
+            table["add_to_x"] = value(add_to_x as f64);
+        }
+        if let Some(add_to_y) = self.add_to_y {
+            table["add_to_y"] = value(add_to_y as f64);
+        }
+        if let Some(invert_x) = self.invert_x {
+            table["invert_x"] = value(invert_x);
+        }
+        if let Some(invert_y) = self.invert_y {
+            table["invert_y"] = value(invert_y);
+        }
+        if let Some(reverse_coordinates) = self.reverse_coordinates {
+            table["reverse_coordinates"] = value(reverse_coordinates);
+        }
+        if let Some(randomise_input_offset) = self.randomise_input_offset {
+            table["randomise_input_offset"] = value(randomise_input_offset as i64);
+        }
+        if let Some(repeat_click) = self.repeat_click {
+            table["repeat_click"] = value(repeat_click as i64);
+        }
+        if let Some(input_repeat_delay_ms) = self.input_repeat_delay_ms {
+            table["input_repeat_delay_ms"] = value(input_repeat_delay_ms as i64);
+        }
+        if let Some(wheel_action) = self.wheel_action {
+            if let Some(name) = wheel_action.to_possible_value() {
+                table["wheel_action"] = value(name.get_name());
+            }
+        }
+        if let Some(rotation) = self.rotation {
+            table["rotation"] = value(rotation as i64);
+        }
+    }
+}
+
+// Profiles for every model in one file. Keys at the top are for all of them,
+// a [model] table, named like the device reports itself, goes over them:
+//
+// invert_x = true
+// [n873]
+// add_to_y = -12.0
+//
+// Comments and order are kept when the calibration saves into it
+#[derive(Default)]
+pub struct Profiles {
+    document: Document,
+}
+
+impl Profiles {
+    pub fn parse(text: &str) -> Result<Profiles, String> {
+        let document = text.parse().map_err(|err| format!("{}", err))?;
+        Ok(Profiles { document })
+    }
+
+    pub fn load(path: &Path) -> Result<Profiles, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        Profiles::parse(&text)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.document.to_string())
+    }
+
+    // The keys for every model with the ones for this model over them
+    pub fn profile(&self, model: Option<&str>) -> Result<Profile, String> {
+        let profile = Profile::from_table(self.document.as_table(), "top")?;
+        let Some(model) = model else {
+            return Ok(profile);
+        };
+        match self.document.get(model) {
+            None => {
+                debug!("No profile for model {}", model);
+                Ok(profile)
+            }
+            Some(item) => match item.as_table() {
+                Some(table) => Ok(profile.over(Profile::from_table(table, model)?)),
+                None => Err(format!("{} should be a [{}] table", model, model)),
+            },
+        }
+    }
+
+    // Same layering as profile()
+    pub fn touch_device(&self, model: Option<&str>) -> Result<TouchDevice, String> {
+        let top = TouchDevice::from_table(self.document.as_table(), "top")?;
+        let Some((model, table)) = model.and_then(|model| {
+            let table = self.document.get(model)?.as_table()?;
+            Some((model, table))
+        }) else {
+            return Ok(top);
+        };
+        let touch_device = TouchDevice::from_table(table, model)?;
+        Ok(TouchDevice {
+            path: touch_device.path.or(top.path),
+            protocol: touch_device.protocol.or(top.protocol),
+        })
+    }
+
+    // Into the model's table, or for every model when it isn't known
+    pub fn set(&mut self, model: Option<&str>, profile: &Profile) {
+        let table = match model {
+            Some(model) => {
+                if !self.document.get(model).is_some_and(Item::is_table) {
+                    self.document[model] = Item::Table(Table::new());
+                }
+                self.document[model].as_table_mut().unwrap()
+            }
+            None => self.document.as_table_mut(),
+        };
+        profile.write(table);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "# Mine\ninvert_x = true\nadd_to_x = -8\n\n[n873]\nadd_to_x = 3.5\nwheel_action = \"page\"\n";
+
+    #[test]
+    fn models_go_over_the_top() {
+        let profiles = Profiles::parse(FILE).unwrap();
+        let top = Profile {
+            add_to_x: Some(-8.0),
+            invert_x: Some(true),
+            ..Profile::default()
+        };
+        assert_eq!(profiles.profile(None), Ok(top));
+        assert_eq!(profiles.profile(Some("n236")), Ok(top));
+        assert_eq!(
+            profiles.profile(Some("n873")),
+            Ok(Profile {
+                add_to_x: Some(3.5),
+                wheel_action: Some(WheelAction::Page),
+                ..top
+            })
+        );
+    }
+
+    #[test]
+    fn touch_device_of_the_model() {
+        let profiles = Profiles::parse(&format!(
+            "touch_protocol = \"b\"\n{}touch_device = \"/dev/input/event2\"\ntouch_protocol = \"a\"\n",
+            FILE
+        ))
+        .unwrap();
+        assert_eq!(
+            profiles.touch_device(Some("n873")),
+            Ok(TouchDevice {
+                path: Some(String::from("/dev/input/event2")),
+                protocol: Some(TouchProtocol::A),
+            })
+        );
+        assert_eq!(
+            profiles.touch_device(Some("n236")),
+            Ok(TouchDevice {
+                path: None,
+                protocol: Some(TouchProtocol::B),
+            })
+        );
+        assert_eq!(
+            Profiles::parse(FILE).unwrap().touch_device(None),
+            Ok(TouchDevice::default())
+        );
+    }
+
+    #[test]
+    fn saving_keeps_the_rest() {
+        let mut profiles = Profiles::parse(FILE).unwrap();
+        let calibrated = Profile {
+            add_to_x: Some(1.0),
+            invert_y: Some(true),
+            rotation: Some(3),
+            ..Profile::default()
+        };
+        profiles.set(Some("n873"), &calibrated);
+        profiles.set(Some("n306"), &calibrated);
+        let file = tempfile::NamedTempFile::new().unwrap();
+        profiles.save(file.path()).unwrap();
+        let text = std::fs::read_to_string(file.path()).unwrap();
+        assert!(text.starts_with("# Mine\n"));
+        let profiles = Profiles::load(file.path()).unwrap();
+        let n873 = profiles.profile(Some("n873")).unwrap();
+        assert_eq!(n873.add_to_x, Some(1.0));
+        assert_eq!(n873.invert_y, Some(true));
+        assert_eq!(n873.wheel_action, Some(WheelAction::Page));
+        assert_eq!(n873.rotation, Some(3));
+        assert_eq!(profiles.profile(Some("n306")).unwrap().add_to_x, Some(1.0));
+        assert_eq!(profiles.profile(None).unwrap().add_to_x, Some(-8.0));
+    }
+
+    #[test]
+    fn later_profiles_win() {
+        let arguments = Profile {
+            add_to_x: Some(-8.0),
+            repeat_click: Some(1),
+            ..Profile::default()
+        };
+        let file = Profile {
+            add_to_x: Some(2.0),
+            repeat_click: Some(3),
+            ..Profile::default()
+        };
+        let given = Profile {
+            repeat_click: Some(5),
+            ..Profile::default()
+        };
+        let profile = arguments.over(file).over(given);
+        assert_eq!(profile.add_to_x, Some(2.0));
+        assert_eq!(profile.repeat_click, Some(5));
+        // Edited in the settings panel, only that one goes over the rest
+        let edited = Profile {
+            invert_y: Some(true),
+            ..profile
+        };
+        assert_eq!(
+            Profile::changed(profile, edited),
+            Profile {
+                invert_y: Some(true),
+                ..Profile::default()
+            }
+        );
+    }
+
+    #[test]
+    fn bad_values_are_errors() {
+        let bad =
+            |text: &str| Profiles::parse(text).and_then(|profiles| profiles.profile(Some("n873")));
+        assert!(bad("invert_x = 1").is_err());
+        assert!(bad("add_to_x = \"left\"").is_err());
+        assert!(bad("repeat_click = -1").is_err());
+        assert!(bad("wheel_action = \"spin\"").is_err());
+        assert!(bad("rotation = 4").is_err());
+        let bad_touch = |text: &str| Profiles::parse(text).unwrap().touch_device(Some("n873"));
+        assert!(bad_touch("touch_protocol = \"c\"").is_err());
+        assert!(bad_touch("[n873]\ntouch_device = 1").is_err());
+        assert!(bad("n873 = 5").is_err());
+        assert!(bad("[n873]\ninvert_y = \"no\"").is_err());
+        assert!(bad("add_to_x = ").is_err());
+        assert!(Profiles::load(Path::new("/nonexistent/profile.toml")).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+// Gui
+use eframe::egui::{pos2, vec2, Pos2, Vec2};
+
+// How far the screen turned from where the touch options were made, in quarter turns
+// clockwise. Both are var.rotate values of the device framebuffer
+pub fn turns(now: u32, base: u32) -> u32 {
+    (now % 4 + 4 - base % 4) % 4
+}
+
+// The touchscreen doesn't turn with the screen. Takes a position on the turned image
+// back to the image the touch options were made for, and gives that image's size too
+pub fn unturn(pos: Pos2, image_size: Vec2, turns: u32) -> (Pos2, Vec2) {
+    let (mut pos, mut size) = (pos, image_size);
+    for _ in 0..turns % 4 {
+        // One quarter turn back, the top left corner was the top right one
+        pos = pos2(size.y - pos.y, pos.x);
+        size = vec2(size.y, size.x);
+    }
+    (pos, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_turns_both_ways() {
+        assert_eq!(turns(3, 3), 0);
+        assert_eq!(turns(0, 3), 1);
+        assert_eq!(turns(2, 3), 3);
+        assert_eq!(turns(1, 0), 1);
+    }
+
+    #[test]
+    fn corners_go_around() {
+        let size = vec2(1448.0, 1072.0); // Landscape, turned once from portrait
+        let (top_left, base) = unturn(pos2(0.0, 0.0), size, 1);
+        assert_eq!(base, vec2(1072.0, 1448.0));
+        assert_eq!(top_left, pos2(1072.0, 0.0));
+        let (top_right, _) = unturn(pos2(1448.0, 0.0), size, 1);
+        assert_eq!(top_right, pos2(1072.0, 1448.0));
+        // Upside down
+        let (pos, base) = unturn(pos2(10.0, 20.0), base, 2);
+        assert_eq!(base, vec2(1072.0, 1448.0));
+        assert_eq!(pos, pos2(1062.0, 1428.0));
+        // All the way around is nothing
+        assert_eq!(unturn(pos2(10.0, 20.0), size, 4), (pos2(10.0, 20.0), size));
+        assert_eq!(
+            unturn(unturn(pos2(10.0, 20.0), size, 1).0, base, 3).0,
+            pos2(10.0, 20.0)
+        );
+    }
+}
@@ -1,49 +1,157 @@
 // Logging
-use log::{debug, info};
+use log::{debug, error, info, warn};
 
 // Network
-use crate::api::{FromClientMessage, FromServerMessage};
-use message_io::network::NetEvent;
+use mir_kobo_proto::{DecodeError, FrameAssembler, FromClientMessage, FromServerMessage, Message, PROTOCOL_VERSION};
+use message_io::network::{Endpoint, NetEvent};
 use message_io::node::{NodeHandler, NodeListener};
 
 // Threads
 use std::sync::mpsc::Sender;
-use crate::ThreadCom;
-use std::sync::Arc;
+use crate::delivery::InputTracker;
+use crate::pacing::FramePacer;
+use crate::{host_capabilities, ThreadCom};
+use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use std::time::Instant;
 
-pub fn run(handler: Arc<NodeHandler<()>>, listener: NodeListener<()>, tx_to_gui: Sender<ThreadCom>) {
+fn version_mismatch(version: u32) -> String {
+    format!(
+        "Protocol version mismatch: host speaks {}, device speaks {}. Update mir_kobo_kobo or mir_kobo_host",
+        PROTOCOL_VERSION, version
+    )
+}
+
+pub fn run(
+    handler: Arc<NodeHandler<()>>,
+    listener: NodeListener<()>,
+    tx_to_gui: Sender<ThreadCom>,
+    pacer: Arc<Mutex<FramePacer>>,
+    inputs: Arc<Mutex<InputTracker>>,
+) {
 
 
+    let mut assembler = FrameAssembler::default();
+    // Deltas already on the way fail too, one request is enough until the keyframe comes
+    let mut keyframe_requested = false;
+    // One ProtocolError per connection, or two builds that can't read each other's answer forever
+    let mut reported: HashSet<Endpoint> = HashSet::new();
     listener.for_each(move |event| match event.network() {
         NetEvent::Connected(_, _) => (),
         NetEvent::Accepted(endpoint, _listener_id) => {
             // Only connection oriented protocols will generate this event
-            info!("Client ({}) connected", endpoint.addr());
-            tx_to_gui.send(ThreadCom::ClientConnected(endpoint, _listener_id)).unwrap();
+            info!("Client ({}) connected, waiting for Hello", endpoint.addr());
         }
         NetEvent::Message(endpoint, input_data) => {
             debug!("Received raw input data with length: {}", input_data.len());
-            let message: FromClientMessage = bincode::deserialize(input_data).unwrap();
+            let message = match FromClientMessage::decode(input_data) {
+                Ok(message) => message,
+                Err(DecodeError::VersionMismatch(version)) => {
+                    let text = version_mismatch(version);
+                    error!("{}", text);
+                    tx_to_gui.send(ThreadCom::Error(text)).unwrap();
+                    return;
+                }
+                Err(err) => {
+                    // Keep the connection, the next frame will probably be fine
+                    warn!("Failed to decode message from client ({}): {}", endpoint.addr(), err);
+                    if reported.insert(endpoint) {
+                        let reply = FromServerMessage::ProtocolError(err.to_string());
+                        handler.network().send(endpoint, &reply.encode());
+                    }
+                    return;
+                }
+            };
             match message {
-                FromClientMessage::Ping => {
-                    info!("Received Ping from client");
+                FromClientMessage::Hello { version, capabilities, model } => {
+                    info!("Received Hello from client, version {} with {:?}, model {:?}", version, capabilities, model);
+                    // Answer anyway, so the device can report the mismatch too
+                    let hello = FromServerMessage::Hello {
+                        version: PROTOCOL_VERSION,
+                        capabilities: host_capabilities(),
+                    };
+                    info!("Sending Hello");
+                    handler.network().send(endpoint, &hello.encode());
+                    if version != PROTOCOL_VERSION {
+                        let text = version_mismatch(version);
+                        error!("{}", text);
+                        tx_to_gui.send(ThreadCom::Error(text)).unwrap();
+                        return;
+                    }
                     tx_to_gui.send(ThreadCom::ConnectionActive(true)).unwrap();
-                    let output_data = bincode::serialize(&FromServerMessage::Pong).unwrap();
-                    info!("Sending Pong");
-                    handler.network().send(endpoint, &output_data);
+                    keyframe_requested = false;
+                    let common = host_capabilities().common(&capabilities);
+                    tx_to_gui.send(ThreadCom::ClientConnected(endpoint, common, model)).unwrap();
+                }
+                FromClientMessage::Frame { sequence, requested_us, captured_us, frame } => {
+                    debug!("Received keyframe {} {:?} from client", sequence, frame);
+                    if let Some(requested_us) = requested_us {
+                        pacer.lock().unwrap().frame_received(requested_us, Instant::now());
+                    }
+                    // Decompressing here keeps the gui responsive
+                    match assembler.keyframe(sequence, &frame) {
+                        Ok(()) => {
+                            keyframe_requested = false;
+                            tx_to_gui
+                                .send(ThreadCom::Screen(assembler.size(), assembler.pixels().to_vec()))
+                                .unwrap();
+                            tx_to_gui.send(ThreadCom::Received(input_data.len(), requested_us.or(captured_us))).unwrap();
+                        }
+                        Err(err) => warn!("Failed to decode frame: {}", err),
+                    }
                 }
-                FromClientMessage::Screen(file) => {
-                    debug!("Received Screen from client");
-                    tx_to_gui.send(ThreadCom::Screen(file)).unwrap();
+                FromClientMessage::Delta { sequence, requested_us, captured_us, patches } => {
+                    debug!("Received delta {} with {} patches from client", sequence, patches.len());
+                    if let Some(requested_us) = requested_us {
+                        pacer.lock().unwrap().frame_received(requested_us, Instant::now());
+                    }
+                    match assembler.delta(sequence, &patches) {
+                        Ok(patches) => {
+                            if !patches.is_empty() {
+                                tx_to_gui.send(ThreadCom::Patch(patches)).unwrap();
+                            }
+                            tx_to_gui.send(ThreadCom::Received(input_data.len(), requested_us.or(captured_us))).unwrap();
+                        }
+                        Err(err) if keyframe_requested => {
+                            debug!("Failed to apply delta {}: {}, the keyframe is already asked for", sequence, err);
+                        }
+                        Err(err) => {
+                            warn!("Failed to apply delta {}: {}, asking for a keyframe", sequence, err);
+                            handler.network().send(endpoint, &FromServerMessage::RequestKeyframe.encode());
+                            keyframe_requested = true;
+                        }
+                    }
+                }
+                FromClientMessage::Timing(timing) => {
+                    pacer.lock().unwrap().timing(&timing);
                 }
                 FromClientMessage::ScreenSize((x, y)) => {
                     debug!("Received Screen size from client");
                     tx_to_gui.send(ThreadCom::ScreenSize((x, y))).unwrap();
                 }
+                FromClientMessage::Rotation(rotation) => {
+                    tx_to_gui.send(ThreadCom::Rotation(rotation)).unwrap();
+                }
+                FromClientMessage::InputAck { id, error } => {
+                    if let Some(text) = inputs.lock().unwrap().ack(id, error, Instant::now()) {
+                        error!("{}", text);
+                        tx_to_gui.send(ThreadCom::Error(text)).unwrap();
+                    }
+                }
+                FromClientMessage::TargetTouched(position) => {
+                    tx_to_gui.send(ThreadCom::TargetTouched(position)).unwrap();
+                }
+                FromClientMessage::ProtocolError(reason) => {
+                    let text = format!("Device couldn't decode our message: {}", reason);
+                    error!("{}", text);
+                    tx_to_gui.send(ThreadCom::Error(text)).unwrap();
+                }
             }
         }
         NetEvent::Disconnected(endpoint) => {
             info!("Client ({}) disconnected", endpoint.addr(),);
+            reported.remove(&endpoint);
+            tx_to_gui.send(ThreadCom::ConnectionActive(false)).unwrap();
         }
     });
 }
@@ -0,0 +1,130 @@
+// Gui
+use eframe::egui::{ComboBox, DragValue, Grid, Ui};
+
+// Other
+use crate::gesture::WheelAction;
+use crate::{InputOptions, RefreshDelays};
+use std::path::Path;
+
+// What happened in the panel this frame
+#[derive(Default)]
+pub struct Edits {
+    pub input: bool,
+    pub delays: bool,
+    pub save: bool,
+}
+
+// The settings side panel, everything takes effect right away
+pub fn show(
+    ui: &mut Ui,
+    options: &mut InputOptions,
+    delays: &mut RefreshDelays,
+    config: Option<&Path>,
+) -> Edits {
+    let mut edits = Edits::default();
+    ui.heading("Settings");
+    ui.label("F6 hides this");
+    ui.separator();
+
+    ui.strong("Touch");
+    Grid::new("touch").num_columns(2).show(ui, |ui| {
+        ui.label("Shift x");
+        edits.input |= ui
+            .add(DragValue::new(&mut options.add_to_x).speed(0.5))
+            .changed();
+        ui.end_row();
+        ui.label("Shift y");
+        edits.input |= ui
+            .add(DragValue::new(&mut options.add_to_y).speed(0.5))
+            .changed();
+        ui.end_row();
+        ui.label("Randomise");
+        edits.input |= ui
+            .add(
+                DragValue::new(&mut options.randomise_input_offset)
+                    .clamp_range(0..=50)
+                    .suffix(" px"),
+            )
+            .on_hover_text("Some apps ignore a second tap on the exact same pixel")
+            .changed();
+        ui.end_row();
+        ui.label("Clicks");
+        edits.input |= ui
+            .add(DragValue::new(&mut options.repeat_click).clamp_range(1..=10))
+            .changed();
+        ui.end_row();
+        ui.label("Between clicks");
+        edits.input |= ui
+            .add(
+                DragValue::new(&mut options.input_repeat_delay_ms)
+                    .clamp_range(0..=2000)
+                    .suffix(" ms"),
+            )
+            .changed();
+        ui.end_row();
+        ui.label("Wheel");
+        ComboBox::from_id_source("wheel_action")
+            .selected_text(format!("{:?}", options.wheel_action))
+            .show_ui(ui, |ui| {
+                for action in [WheelAction::Swipe, WheelAction::Page, WheelAction::Off] {
+                    edits.input |= ui
+                        .selectable_value(
+                            &mut options.wheel_action,
+                            action,
+                            format!("{:?}", action),
+                        )
+                        .changed();
+                }
+            });
+        ui.end_row();
+    });
+    edits.input |= ui.checkbox(&mut options.invert_x, "Invert x").changed();
+    edits.input |= ui.checkbox(&mut options.invert_y, "Invert y").changed();
+    edits.input |= ui
+        .checkbox(&mut options.reverse_coordinates, "Swap x and y")
+        .changed();
+    ui.separator();
+
+    ui.strong("Screen refresh");
+    Grid::new("refresh").num_columns(2).show(ui, |ui| {
+        ui.label("Longest delay");
+        edits.delays |= ui
+            .add(
+                DragValue::new(&mut delays.screen_delay_ms)
+                    .clamp_range(10..=10000)
+                    .speed(10)
+                    .suffix(" ms"),
+            )
+            .changed();
+        ui.end_row();
+        ui.label("Shortest delay");
+        edits.delays |= ui
+            .add_enabled(
+                !delays.fixed_screen_delay,
+                DragValue::new(&mut delays.min_screen_delay_ms)
+                    .clamp_range(10..=delays.screen_delay_ms.max(10))
+                    .speed(10)
+                    .suffix(" ms"),
+            )
+            .changed();
+        ui.end_row();
+    });
+    edits.delays |= ui
+        .checkbox(&mut delays.fixed_screen_delay, "Always the longest delay")
+        .changed();
+    ui.separator();
+
+    match config {
+        Some(path) => {
+            edits.save = ui
+                .button("Save to the config")
+                .on_hover_text(path.display().to_string())
+                .clicked();
+            ui.small("A profile for the device model still goes over it on the next start");
+        }
+        None => {
+            ui.label("No config file to save to, pass --config");
+        }
+    }
+    edits
+}
@@ -0,0 +1,103 @@
+// Other
+use crate::pacing::smooth;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Frames and bytes are counted over this long
+const WINDOW: Duration = Duration::from_secs(5);
+
+// For the status bar
+#[derive(Default)]
+pub struct FrameStats {
+    shown: VecDeque<(Instant, usize)>, // When each frame was shown and its message size
+    latency: Option<Duration>,         // Request sent to frame shown, averaged
+}
+
+impl FrameStats {
+    pub fn frame(&mut self, now: Instant, bytes: usize, latency: Option<Duration>) {
+        self.shown.push_back((now, bytes));
+        if let Some(latency) = latency {
+            self.latency = Some(smooth(self.latency, latency));
+        }
+        self.forget_old(now);
+    }
+
+    fn forget_old(&mut self, now: Instant) {
+        while let Some((at, _)) = self.shown.front() {
+            if now.duration_since(*at) < WINDOW {
+                break;
+            }
+            self.shown.pop_front();
+        }
+    }
+
+    // Starting over for a new connection
+    pub fn reset(&mut self) {
+        *self = FrameStats::default();
+    }
+
+    pub fn fps(&mut self, now: Instant) -> f32 {
+        self.forget_old(now);
+        self.shown.len() as f32 / WINDOW.as_secs_f32()
+    }
+
+    // Bytes per second
+    pub fn bandwidth(&mut self, now: Instant) -> f32 {
+        self.forget_old(now);
+        self.bytes() as f32 / WINDOW.as_secs_f32()
+    }
+
+    pub fn bytes_per_frame(&mut self, now: Instant) -> Option<usize> {
+        self.forget_old(now);
+        (!self.shown.is_empty()).then(|| self.bytes() / self.shown.len())
+    }
+
+    fn bytes(&self) -> usize {
+        self.shown.iter().map(|(_, bytes)| bytes).sum()
+    }
+
+    // Streamed frames weren't asked for, so they don't count
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+}
+
+pub fn human_bytes(bytes: f32) -> String {
+    match bytes {
+        bytes if bytes >= 1024.0 * 1024.0 => format!("{:.1} MiB", bytes / 1024.0 / 1024.0),
+        bytes if bytes >= 1024.0 => format!("{:.1} KiB", bytes / 1024.0),
+        bytes => format!("{:.0} B", bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_the_last_frames() {
+        let start = Instant::now();
+        let mut stats = FrameStats::default();
+        assert_eq!(stats.fps(start), 0.0);
+        assert_eq!(stats.bytes_per_frame(start), None);
+        for n in 0..10 {
+            let latency = (n % 2 == 0).then_some(Duration::from_millis(200));
+            stats.frame(start + Duration::from_millis(n * 100), 1000, latency);
+        }
+        let now = start + Duration::from_secs(1);
+        assert_eq!(stats.fps(now), 2.0);
+        assert_eq!(stats.bandwidth(now), 2000.0);
+        assert_eq!(stats.bytes_per_frame(now), Some(1000));
+        assert_eq!(stats.latency(), Some(Duration::from_millis(200)));
+        // Nothing came for a while
+        let later = start + WINDOW * 2;
+        assert_eq!(stats.fps(later), 0.0);
+        assert_eq!(stats.bytes_per_frame(later), None);
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_bytes(512.0), "512 B");
+        assert_eq!(human_bytes(1536.0), "1.5 KiB");
+    }
+}
@@ -8,4 +8,4 @@ passwd="root"
 
 #sshpass -p $passwd ssh $servername "bash -c \"ifsctl mnt rootfs rw\""
 sshpass -p $passwd ssh $servername "bash -c \"rm /mir_kobo_kobo\""
-sshpass -p $passwd scp target/armv7-unknown-linux-musleabihf/release/mir_kobo_kobo $servername:/
+sshpass -p $passwd scp ../target/armv7-unknown-linux-musleabihf/release/mir_kobo_kobo $servername:/
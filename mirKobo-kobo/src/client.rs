@@ -2,35 +2,166 @@
 #![allow(clippy::single_match)]
 
 // Logging
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
 // Network
-use crate::api::{FromClientMessage, FromServerMessage};
-use message_io::network::{NetEvent, RemoteAddr, Transport};
-use message_io::node::{self, NodeEvent};
+use mir_kobo_proto::{
+    Capabilities, DecodeError, DeltaEncoder, FrameEncoding, FrameTiming, FromClientMessage, FromServerMessage, InputKind,
+    Input, Message, PROTOCOL_VERSION,
+};
+use message_io::network::{Endpoint, NetEvent, RemoteAddr, Transport};
+use message_io::node::{self, NodeEvent, NodeHandler};
 
 // Device
-use crate::device::{click, get_screen, get_screen_size};
+use crate::inject::Injector;
+use crate::keys::Keys;
+use crate::model;
+use crate::text;
+use crate::touch::{self, MtProtocol, TouchInput};
+use crate::screen::{self, ScreenBackend, ScreenSource};
+use crate::targets::Targets;
+use crate::touch_watch::TouchWatch;
+use crate::streaming::{budget_interval, ChangePoller, HostClock};
 
 // Other
+use std::sync::mpsc::{RecvTimeoutError, Sender, SyncSender};
 use std::sync::{mpsc, Arc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use crate::Args;
 
 // We don't allow to loose any of those events
 enum ImportantJobs {
-    SendClick(u16, u16),
+    Input(u32, Input), // id
+    DrawTarget(Option<(u32, u32)>),
+    Touch(Box<dyn TouchInput>), // Reopened for the host's profile
     Stop,
 }
 
 // We allow to loose those events
 enum LooseJobs {
-    SendScreen,
+    SendScreen(FrameEncoding, bool, Option<u64>), // The bool forces a keyframe, then the host time of the request
+    StartStreaming(ChangePoller, FrameEncoding, HostClock),
     Stop,
 }
 
+// What this build can capture, inputs depend on the touch backend
+fn device_capabilities(inputs: &[InputKind], draw_targets: bool) -> Capabilities {
+    Capabilities {
+        // Cheapest for our cpu first
+        encodings: vec![
+            FrameEncoding::Lz4,
+            FrameEncoding::Zstd,
+            FrameEncoding::Raw,
+            FrameEncoding::Png,
+        ],
+        inputs: inputs.to_vec(),
+        draw_targets,
+    }
+}
+
+fn hello(capabilities: &Capabilities, model: Option<String>) -> FromClientMessage {
+    FromClientMessage::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: capabilities.clone(),
+        model,
+    }
+}
+
+fn version_mismatch(version: u32) {
+    error!(
+        "Protocol version mismatch: device speaks {}, host speaks {}. Update mir_kobo_kobo or mir_kobo_host",
+        PROTOCOL_VERSION, version
+    );
+}
+
+// Taps on the device go to the host as they are, it works out the touch flips and swap
+// from where the targets were tapped
+fn watch_taps(path: Option<&str>, handler: &Arc<NodeHandler<FromClientMessage>>, server_id: Endpoint) -> Option<TouchWatch> {
+    let Some(path) = path else {
+        warn!("No touchscreen to read taps from, the host can only calibrate the shift");
+        return None;
+    };
+    let handler = handler.clone();
+    let tapped = move |position| {
+        let message = FromClientMessage::TargetTouched(position);
+        handler.network().send(server_id, &message.encode());
+    };
+    match TouchWatch::open(path, tapped) {
+        Ok(watch) => Some(watch),
+        Err(err) => {
+            warn!("Can't read taps from {}, the host can only calibrate the shift: {}", path, err);
+            None
+        }
+    }
+}
+
+// Stops the worker threads and the listener, main() will connect again
+fn restart(
+    handler: &NodeHandler<FromClientMessage>,
+    tx_to_loose: &SyncSender<LooseJobs>,
+    tx_to_imp: &Sender<ImportantJobs>,
+) {
+    info!("Retrying in 3 seconds...");
+    tx_to_loose.send(LooseJobs::Stop).unwrap();
+    tx_to_imp.send(ImportantJobs::Stop).unwrap();
+    thread::sleep(Duration::from_secs(3));
+    handler.stop();
+}
+
 pub fn run(transport: Transport, remote_addr: RemoteAddr, args: &Args) {
+    let mut source = match screen::open(args) {
+        Ok(source) => source,
+        Err(err) => {
+            error!("Failed to open screen source {:?}: {}", args.screen_source, err);
+            thread::sleep(Duration::from_secs(3));
+            return;
+        }
+    };
+    let screen_size = match source.size() {
+        Ok(size) => size,
+        Err(err) => {
+            error!("Failed to get screen size: {}", err);
+            thread::sleep(Duration::from_secs(3));
+            return;
+        }
+    };
+    let rotation = source.rotation();
+
+    let touch = match touch::open(args) {
+        Ok(touch) => touch,
+        Err(err) => {
+            error!("Failed to open touch input {:?}: {}", args.touch_backend, err);
+            thread::sleep(Duration::from_secs(3));
+            return;
+        }
+    };
+
+    let text_input = match text::open(args) {
+        Ok(text_input) => text_input,
+        Err(err) => {
+            error!("Failed to open text input {:?}: {}", args.text_backend, err);
+            thread::sleep(Duration::from_secs(3));
+            return;
+        }
+    };
+
+    let mut injector = Injector::new(touch, Keys::open(args), text_input);
+
+    // Replayed frames don't come from the framebuffer, targets there wouldn't show up
+    let mut targets = match args.screen_source {
+        ScreenBackend::Replay => None,
+        _ => match Targets::open(&args.framebuffer_path) {
+            Ok(targets) => Some(targets),
+            Err(err) => {
+                warn!("Can't draw calibration targets into {}: {}", args.framebuffer_path, err);
+                None
+            }
+        },
+    };
+    let ours = device_capabilities(&injector.inputs(), targets.is_some());
+    let model = model::detect(args);
+
     let (handler_regular, listener) = node::split();
     let handler = Arc::new(handler_regular);
 
@@ -40,15 +171,38 @@ pub fn run(transport: Transport, remote_addr: RemoteAddr, args: &Args) {
         .unwrap();
 
     let (tx_to_imp, rx_to_imp) = mpsc::channel(); // We want not synced because we don't want to loose any input
-    let touch_emulate_path = args.touch_emulate_path.clone();
+    let handler_imp = handler.clone();
+    let mut watch: Option<TouchWatch> = None; // Taps on the device while targets are drawn
     thread::spawn(move || loop {
         if let Ok(event) = rx_to_imp.recv() {
             match event {
-                ImportantJobs::SendClick(x, y) => {
-                    info!("Received Click from server: x:{} y:{}", x, y);
-                    click(x, y, &touch_emulate_path);
+                ImportantJobs::Input(id, input) => {
+                    let error = injector.handle(id, input);
+                    if let Some(err) = &error {
+                        error!("Failed to inject input {}: {}", id, err);
+                    }
+                    let ack = FromClientMessage::InputAck { id, error };
+                    handler_imp.network().send(server_id, &ack.encode());
+                }
+                ImportantJobs::DrawTarget(target) => match (&mut targets, target) {
+                    (Some(targets), Some((x, y))) => {
+                        targets.draw(x, y);
+                        if watch.is_none() {
+                            watch = watch_taps(injector.touch_path(), &handler_imp, server_id);
+                        }
+                    }
+                    (Some(targets), None) => {
+                        targets.clear();
+                        watch = None;
+                    }
+                    (None, _) => warn!("Host asked for a calibration target, but they can't be drawn"),
+                },
+                ImportantJobs::Touch(touch) => {
+                    injector.set_touch(touch);
+                    watch = None; // Reopened on the new touchscreen with the next target
                 }
                 ImportantJobs::Stop => {
+                    injector.lift();
                     break;
                 }
             }
@@ -57,23 +211,110 @@ pub fn run(transport: Transport, remote_addr: RemoteAddr, args: &Args) {
 
     let (tx_to_loose, rx_to_loose) = mpsc::sync_channel(1); // We want synced channel because of try_send
     let handler_thread = handler.clone();
-    let fbgrab_path = args.fbgrab_path.clone();
-    thread::spawn(move || loop {
-        if let Ok(event) = rx_to_loose.recv() {
-            match event {
-                LooseJobs::SendScreen => {
-                    let message = FromClientMessage::Screen(get_screen(&fbgrab_path));
-                    let output_data = bincode::serialize(&message).unwrap();
-                    debug!("Sending raw screen data with length: {}", output_data.len());
-                    handler_thread.network().send(server_id, &output_data);
+    let mut delta_encoder = DeltaEncoder::new(args.keyframe_interval);
+    let cpu_budget = args.cpu_budget;
+    thread::spawn(move || {
+        let mut streaming: Option<(ChangePoller, FrameEncoding, HostClock)> = None;
+        let mut screen_size = screen_size; // Changes when the screen is turned
+        // Returns how long to wait before the next frame
+        // captured is a frame the source already gave us while looking for changes, clock
+        // stamps streamed frames
+        let mut send_screen = |source: &mut dyn ScreenSource, encoding: FrameEncoding, keyframe: bool, requested_us: Option<u64>, captured: Option<Vec<u8>>, clock: Option<HostClock>| {
+            let started = Instant::now();
+            let captured_us = clock.map(|clock| clock.stamp(started));
+            // The host turns its window and touches with it
+            let keyframe = match source.turned() {
+                Ok(Some((size, rotation))) => {
+                    info!("Screen turned, now {:?} with rotation {}", size, rotation);
+                    screen_size = size;
+                    let size = FromClientMessage::ScreenSize(size);
+                    handler_thread.network().send(server_id, &size.encode());
+                    let rotation = FromClientMessage::Rotation(rotation);
+                    handler_thread.network().send(server_id, &rotation.encode());
+                    true
                 }
-                LooseJobs::Stop => {
-                    break;
+                Ok(None) => keyframe,
+                Err(err) => {
+                    debug!("Can't check if the screen turned: {}", err);
+                    keyframe
+                }
+            };
+            // A turned screen has a new size, what was captured before is no good
+            let captured = captured.filter(|_| !keyframe);
+            let pixels = match captured.map_or_else(|| source.frame(), Ok) {
+                Ok(pixels) => pixels,
+                Err(err) => {
+                    error!("Failed to capture screen: {}", err);
+                    return Duration::ZERO;
+                }
+            };
+            let captured = Instant::now();
+            if keyframe {
+                delta_encoder.request_keyframe();
+            }
+            let (width, height) = screen_size;
+            let message = match delta_encoder.encode(width, height, pixels, encoding, requested_us, captured_us) {
+                Ok(message) => message,
+                Err(err) => {
+                    error!("Failed to encode screen as {:?}: {}", encoding, err);
+                    return Duration::ZERO;
+                }
+            };
+            let sequence = match &message {
+                FromClientMessage::Frame { sequence, .. } | FromClientMessage::Delta { sequence, .. } => *sequence,
+                _ => 0,
+            };
+            let output_data = message.encode();
+            let encoded = Instant::now();
+            debug!("Sending raw screen data with length: {}", output_data.len());
+            handler_thread.network().send(server_id, &output_data);
+
+            let min_interval = budget_interval(encoded - started, cpu_budget);
+            let timing = FromClientMessage::Timing(FrameTiming {
+                sequence,
+                capture_us: (captured - started).as_micros() as u32,
+                encode_us: (encoded - captured).as_micros() as u32,
+                min_interval_us: min_interval.as_micros() as u32,
+            });
+            handler_thread.network().send(server_id, &timing.encode());
+            min_interval.saturating_sub(started.elapsed())
+        };
+
+        let mut pause = Duration::ZERO; // What the cpu budget asks for after the last frame
+        loop {
+            // While streaming, waking up without a job means it's time to look at the screen
+            let timeout = match &streaming {
+                Some((poller, _, _)) => poller.interval().max(pause),
+                None => Duration::from_secs(60),
+            };
+            pause = Duration::ZERO;
+            match rx_to_loose.recv_timeout(timeout) {
+                Ok(LooseJobs::SendScreen(encoding, keyframe, requested_us)) => {
+                    send_screen(source.as_mut(), encoding, keyframe, requested_us, None, None);
+                }
+                Ok(LooseJobs::StartStreaming(poller, encoding, clock)) => {
+                    info!("Streaming frames as the screen changes");
+                    streaming = Some((poller, encoding, clock));
+                }
+                Ok(LooseJobs::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some((poller, encoding, clock)) = &mut streaming {
+                        match source.fingerprint(poller.full_check()) {
+                            Ok((fingerprint, captured)) => {
+                                if poller.poll(fingerprint) {
+                                    pause = send_screen(source.as_mut(), *encoding, false, None, captured, Some(*clock));
+                                }
+                            }
+                            Err(err) => error!("Failed to check the screen for changes: {}", err),
+                        }
+                    }
                 }
             }
         }
     });
 
+    let mut encoding = FrameEncoding::Png; // Until the host tells us what it can decode
+    let mut reported = false; // One ProtocolError per connection, or two builds that can't read each other's answer forever
     listener.for_each(move |event| match event {
         NodeEvent::Network(net_event) => match net_event {
             NetEvent::Connected(_, established) => {
@@ -84,57 +325,108 @@ pub fn run(transport: Transport, remote_addr: RemoteAddr, args: &Args) {
                         transport
                     );
                     info!("Client identified by local port: {}", local_addr.port());
-                    handler.signals().send(FromClientMessage::Ping);
+                    handler.signals().send(hello(&ours, model.clone()));
                 } else {
                     info!(
                         "Cannot connect to server at {} by {}",
                         remote_addr, transport
                     );
-                    info!("Retrying in 3 seconds...");
-                    tx_to_loose.send(LooseJobs::Stop).unwrap();
-                    tx_to_imp.send(ImportantJobs::Stop).unwrap();
-                    thread::sleep(Duration::from_secs(3));
-                    handler.stop();
+                    restart(&handler, &tx_to_loose, &tx_to_imp);
                 }
             }
             NetEvent::Accepted(_, _) => unreachable!(), // Only generated when a listener accepts
             NetEvent::Message(_, input_data) => {
                 debug!("Received raw input data with length: {}", input_data.len());
-                let message: FromServerMessage = bincode::deserialize(input_data).unwrap();
+                let message = match FromServerMessage::decode(input_data) {
+                    Ok(message) => message,
+                    Err(DecodeError::VersionMismatch(version)) => {
+                        version_mismatch(version);
+                        restart(&handler, &tx_to_loose, &tx_to_imp);
+                        return;
+                    }
+                    Err(err) => {
+                        // Keep the connection, the host will see what went wrong
+                        warn!("Failed to decode message from server: {}", err);
+                        if !reported {
+                            reported = true;
+                            let reply = FromClientMessage::ProtocolError(err.to_string());
+                            handler.network().send(server_id, &reply.encode());
+                        }
+                        return;
+                    }
+                };
                 match message {
-                    FromServerMessage::Pong => {
-                        info!("Received Pong from server, sending screen size");
-                        let message = FromClientMessage::ScreenSize(get_screen_size(&args.busybox_path));
-                        let output_data = bincode::serialize(&message).unwrap();
-                        handler.network().send(server_id, &output_data);
+                    FromServerMessage::Hello { version, capabilities } => {
+                        if version != PROTOCOL_VERSION {
+                            version_mismatch(version);
+                            restart(&handler, &tx_to_loose, &tx_to_imp);
+                            return;
+                        }
+                        let common = ours.common(&capabilities);
+                        info!("Received Hello from server with {:?}, sending screen size", common);
+                        match common.encodings.first() {
+                            Some(first) => encoding = *first,
+                            None => warn!("No frame encoding in common with the host, trying {:?}", encoding),
+                        }
+                        info!("Sending frames as {:?}", encoding);
+                        let message = FromClientMessage::ScreenSize(screen_size);
+                        handler.network().send(server_id, &message.encode());
+                        let message = FromClientMessage::Rotation(rotation);
+                        handler.network().send(server_id, &message.encode());
+                    }
+                    FromServerMessage::Input { id, input } => {
+                        tx_to_imp.send(ImportantJobs::Input(id, input)).unwrap();
                     }
-                    FromServerMessage::Click(x, y) => {
-                        tx_to_imp.send(ImportantJobs::SendClick(x, y)).unwrap();
+                    FromServerMessage::DrawTarget(target) => {
+                        tx_to_imp.send(ImportantJobs::DrawTarget(target)).unwrap();
                     }
-                    FromServerMessage::RequestScreen => {
+                    FromServerMessage::RequestScreen { requested_us } => {
                         debug!("Received screen request");
                         // Avoid launching many threads...
-                        if tx_to_loose.try_send(LooseJobs::SendScreen).is_err() {
+                        if tx_to_loose.try_send(LooseJobs::SendScreen(encoding, false, Some(requested_us))).is_err() {
                             error!("Request for screen ignored, it's already in make");
                         }
                     }
+                    FromServerMessage::RequestKeyframe => {
+                        info!("Host lost track of the screen, sending a keyframe");
+                        // Not try_send, this one can't be lost
+                        if let Err(err) = tx_to_loose.send(LooseJobs::SendScreen(encoding, true, None)) {
+                            error!("Failed to ask for a keyframe, the screen thread is gone: {}", err);
+                        }
+                    }
+                    FromServerMessage::StartStreaming { min_interval_ms, max_interval_ms, host_us } => {
+                        info!("Host asked for streaming, checking the screen every {}-{} ms", min_interval_ms, max_interval_ms);
+                        let poller = ChangePoller::new(min_interval_ms, max_interval_ms);
+                        let clock = HostClock::new(host_us, Instant::now());
+                        if let Err(err) = tx_to_loose.send(LooseJobs::StartStreaming(poller, encoding, clock)) {
+                            error!("Failed to start streaming, the screen thread is gone: {}", err);
+                        }
+                    }
+                    FromServerMessage::TouchDevice { path, protocol } => {
+                        // Our own arguments still win over the host's profile. Inputs were
+                        // already told in Hello, ones the new touchscreen can't do fail in InputAck
+                        let path = args.touch_device.clone().or(path);
+                        let protocol = args.touch_protocol.or(protocol.map(MtProtocol::from));
+                        info!("Host profile picks touch device {:?} with protocol {:?}", path, protocol);
+                        match touch::open_device(args, path, protocol) {
+                            Ok(touch) => tx_to_imp.send(ImportantJobs::Touch(touch)).unwrap(),
+                            Err(err) => error!("Failed to open the touch device of the profile, keeping the old one: {}", err),
+                        }
+                    }
+                    FromServerMessage::ProtocolError(reason) => {
+                        error!("Host couldn't decode our message: {}", reason);
+                    }
                 }
             }
             NetEvent::Disconnected(_) => {
                 info!("Server is disconnected");
-                info!("Retrying in 3 seconds...");
-                tx_to_loose.send(LooseJobs::Stop).unwrap();
-                tx_to_imp.send(ImportantJobs::Stop).unwrap();
-                thread::sleep(Duration::from_secs(3));
-                handler.stop();
+                restart(&handler, &tx_to_loose, &tx_to_imp);
             }
         },
         NodeEvent::Signal(signal) => match signal {
-            FromClientMessage::Ping => {
-                info!("Sending Ping");
-                let message = FromClientMessage::Ping;
-                let output_data = bincode::serialize(&message).unwrap();
-                handler.network().send(server_id, &output_data);
+            hello @ FromClientMessage::Hello { .. } => {
+                info!("Sending Hello");
+                handler.network().send(server_id, &hello.encode());
                 //handler.signals().send_with_timer(Signal::Greet, Duration::from_secs(1));
             }
             _ => {}
@@ -0,0 +1,11 @@
+// Other
+use std::path::PathBuf;
+
+// Reading, parsing and applying it is shared with the host
+pub use mir_kobo_proto::config::{apply, initial};
+
+// mirkobo.toml next to the binary, it's where everything lives on the device anyway
+pub fn default_path() -> Option<PathBuf> {
+    let binary = std::env::current_exe().ok()?;
+    Some(binary.parent()?.join("mirkobo.toml"))
+}
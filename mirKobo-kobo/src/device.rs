@@ -1,12 +1,12 @@
 // Logging
 use log::debug;
 use std::process::Command;
-pub fn click(x: u16, y: u16, bin_path: &str) {
+pub fn click(x: u16, y: u16, bin_path: &str, device_path: &str) {
     debug!("Launching click");
 
     Command::new(bin_path)
         .arg("touch")
-        .arg("/dev/input/event1")
+        .arg(device_path)
         .arg(x.to_string())
         .arg(y.to_string())
         .status()
@@ -0,0 +1,248 @@
+// Logging
+use log::debug;
+
+// Device
+use crate::pixel_format;
+use crate::streaming::FrameHasher;
+use std::hash::Hasher;
+use memmap2::{Mmap, MmapOptions};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+// From linux/fb.h
+const FBIOGET_VSCREENINFO: u32 = 0x4600;
+const FBIOGET_FSCREENINFO: u32 = 0x4602;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FbBitfield {
+    pub offset: u32,
+    pub length: u32,
+    pub msb_right: u32,
+}
+
+// struct fb_var_screeninfo
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VarScreenInfo {
+    pub xres: u32,
+    pub yres: u32,
+    pub xres_virtual: u32,
+    pub yres_virtual: u32,
+    pub xoffset: u32,
+    pub yoffset: u32,
+    pub bits_per_pixel: u32,
+    pub grayscale: u32,
+    pub red: FbBitfield,
+    pub green: FbBitfield,
+    pub blue: FbBitfield,
+    pub transp: FbBitfield,
+    pub nonstd: u32,
+    pub activate: u32,
+    pub height: u32,
+    pub width: u32,
+    pub accel_flags: u32,
+    pub pixclock: u32,
+    pub left_margin: u32,
+    pub right_margin: u32,
+    pub upper_margin: u32,
+    pub lower_margin: u32,
+    pub hsync_len: u32,
+    pub vsync_len: u32,
+    pub sync: u32,
+    pub vmode: u32,
+    pub rotate: u32,
+    pub colorspace: u32,
+    pub reserved: [u32; 4],
+}
+
+// struct fb_fix_screeninfo
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixScreenInfo {
+    pub id: [u8; 16],
+    pub smem_start: libc::c_ulong,
+    pub smem_len: u32,
+    pub type_: u32,
+    pub type_aux: u32,
+    pub visual: u32,
+    pub xpanstep: u16,
+    pub ypanstep: u16,
+    pub ywrapstep: u16,
+    pub line_length: u32,
+    pub mmio_start: libc::c_ulong,
+    pub mmio_len: u32,
+    pub accel: u32,
+    pub capabilities: u16,
+    pub reserved: [u16; 2],
+}
+
+// Asks a real framebuffer device about its layout
+pub fn screeninfo(file: &File) -> io::Result<(VarScreenInfo, FixScreenInfo)> {
+    let mut var = VarScreenInfo::default();
+    let mut fix = FixScreenInfo::default();
+    // The request type is c_ulong on glibc and c_int on musl, hence the "as _"
+    if unsafe { libc::ioctl(file.as_raw_fd(), FBIOGET_VSCREENINFO as _, &mut var) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::ioctl(file.as_raw_fd(), FBIOGET_FSCREENINFO as _, &mut fix) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((var, fix))
+}
+
+pub struct Framebuffer {
+    map: Mmap,
+    file: File,
+    pub var: VarScreenInfo,
+    pub fix: FixScreenInfo,
+}
+
+impl Framebuffer {
+    // Opens a real framebuffer device, like /dev/fb0
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let (var, fix) = screeninfo(&file)?;
+        debug!("Framebuffer {}: {:?} {:?}", path, var, fix);
+        Self::from_file(&file, var, fix)
+    }
+
+    // Maps anything that looks like a framebuffer, a plain file works too
+    pub fn from_file(file: &File, var: VarScreenInfo, fix: FixScreenInfo) -> io::Result<Self> {
+        let needed = fix.line_length as usize * var.yres_virtual.max(var.yres) as usize;
+        let len = (fix.smem_len as usize).max(needed);
+        // Safety: nobody truncates /dev/fb0 under us. It changes all the time,
+        // but that only means we get a torn frame sometimes, like fbgrab does
+        let map = unsafe { MmapOptions::new().len(len).map(file)? };
+        Ok(Framebuffer {
+            map,
+            file: file.try_clone()?,
+            var,
+            fix,
+        })
+    }
+
+    // Asks the device again, true if the layout changed, like when the screen was turned.
+    // Only works on a real framebuffer device
+    pub fn refresh(&mut self) -> io::Result<bool> {
+        let (var, fix) = screeninfo(&self.file)?;
+        let layout = |var: &VarScreenInfo, fix: &FixScreenInfo| {
+            (var.xres, var.yres, var.xoffset, var.yoffset, var.bits_per_pixel, var.rotate, fix.line_length)
+        };
+        if layout(&var, &fix) == layout(&self.var, &self.fix) {
+            return Ok(false);
+        }
+        debug!("Framebuffer changed: {:?} {:?}", var, fix);
+        *self = Self::from_file(&self.file, var, fix)?;
+        Ok(true)
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.var.xres, self.var.yres)
+    }
+
+    pub fn supported(&self) -> bool {
+        pixel_format::supported(self.var.bits_per_pixel)
+    }
+
+    // The visible part of the screen, one byte of gray per pixel
+    pub fn gray(&self) -> io::Result<Vec<u8>> {
+        pixel_format::to_gray(&self.map, &self.var, self.fix.line_length)
+    }
+
+    // Hash of every line_step line of the visible area, without converting anything
+    pub fn fingerprint(&self, line_step: usize) -> u64 {
+        let bytes_per_pixel = (self.var.bits_per_pixel as usize).div_ceil(8);
+        let stride = self.fix.line_length as usize;
+        let start = self.var.yoffset as usize * stride + self.var.xoffset as usize * bytes_per_pixel;
+        let line_bytes = self.var.xres as usize * bytes_per_pixel;
+        let mut hasher = FrameHasher::default();
+        for y in (0..self.var.yres as usize).step_by(line_step.max(1)) {
+            let line_start = start + y * stride;
+            if let Some(line) = self.map.get(line_start..line_start + line_bytes) {
+                hasher.write(line);
+            }
+        }
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+
+    // 8bpp, 5x3 visible with 3 bytes of padding per line
+    fn fake_framebuffer() -> (tempfile::NamedTempFile, VarScreenInfo, FixScreenInfo) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for y in 0..3u8 {
+            let mut line: Vec<u8> = (0..5u8).map(|x| y * 16 + x).collect();
+            line.extend_from_slice(&[0xAA; 3]);
+            file.write_all(&line).unwrap();
+        }
+        let var = VarScreenInfo {
+            xres: 5,
+            yres: 3,
+            xres_virtual: 8,
+            yres_virtual: 3,
+            bits_per_pixel: 8,
+            grayscale: 1,
+            ..Default::default()
+        };
+        let fix = FixScreenInfo {
+            smem_len: 24,
+            line_length: 8,
+            ..Default::default()
+        };
+        (file, var, fix)
+    }
+
+    #[test]
+    fn screeninfo_matches_kernel_layout() {
+        assert_eq!(std::mem::size_of::<VarScreenInfo>(), 160);
+        let long = std::mem::size_of::<libc::c_ulong>();
+        assert_eq!(
+            std::mem::size_of::<FixScreenInfo>(),
+            if long == 8 { 80 } else { 68 }
+        );
+    }
+
+    #[test]
+    fn gray_skips_line_padding() {
+        let (file, var, fix) = fake_framebuffer();
+        let framebuffer = Framebuffer::from_file(file.as_file(), var, fix).unwrap();
+        assert_eq!(framebuffer.size(), (5, 3));
+        assert_eq!(
+            framebuffer.gray().unwrap(),
+            vec![0, 1, 2, 3, 4, 16, 17, 18, 19, 20, 32, 33, 34, 35, 36]
+        );
+    }
+
+    #[test]
+    fn fingerprint_follows_sampled_lines() {
+        let (file, var, fix) = fake_framebuffer();
+        let framebuffer = Framebuffer::from_file(file.as_file(), var, fix).unwrap();
+        let every_line = framebuffer.fingerprint(1);
+        let every_other = framebuffer.fingerprint(2);
+        // Padding isn't part of the screen
+        file.as_file().write_all_at(&[0x55], 6).unwrap();
+        assert_eq!(framebuffer.fingerprint(1), every_line);
+        // Line 1 is only seen when every line is sampled
+        file.as_file().write_all_at(&[0x55], 9).unwrap();
+        assert_ne!(framebuffer.fingerprint(1), every_line);
+        assert_eq!(framebuffer.fingerprint(2), every_other);
+        file.as_file().write_all_at(&[0x55], 17).unwrap();
+        assert_ne!(framebuffer.fingerprint(2), every_other);
+    }
+
+    #[test]
+    fn unsupported_depth_is_an_error() {
+        let (file, mut var, fix) = fake_framebuffer();
+        var.bits_per_pixel = 4;
+        let framebuffer = Framebuffer::from_file(file.as_file(), var, fix).unwrap();
+        assert!(!framebuffer.supported());
+        assert!(framebuffer.gray().is_err());
+    }
+}
@@ -0,0 +1,203 @@
+// Logging
+use log::{debug, info, warn};
+
+// Device
+use crate::keys::Keys;
+use crate::text::TextInput;
+use crate::touch::TouchInput;
+use std::io;
+
+// Network
+use mir_kobo_proto::{Input, InputKind};
+
+// Other
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// Ids to remember for spotting retries, the host retries one input at a time so a few are plenty
+const DONE_IDS: usize = 64;
+
+// Every way of putting input into the device, behind one id checked door
+pub struct Injector {
+    touch: Box<dyn TouchInput>,
+    keys: Keys,
+    text: Option<Box<dyn TextInput>>,
+    done: VecDeque<(u32, Option<String>)>, // Ids with what came out of them
+}
+
+impl Injector {
+    pub fn new(touch: Box<dyn TouchInput>, keys: Keys, text: Option<Box<dyn TextInput>>) -> Self {
+        Injector {
+            touch,
+            keys,
+            text,
+            done: VecDeque::new(),
+        }
+    }
+
+    // For Hello
+    pub fn inputs(&self) -> Vec<InputKind> {
+        let mut inputs = self.touch.inputs();
+        if self.keys.available() {
+            inputs.push(InputKind::Key);
+        }
+        if self.text.is_some() {
+            inputs.push(InputKind::Text);
+        }
+        inputs
+    }
+
+    // Another touchscreen, from the host's profile. A finger held on the old one is lifted first
+    pub fn set_touch(&mut self, touch: Box<dyn TouchInput>) {
+        self.lift();
+        self.touch = touch;
+    }
+
+    // The connection is gone, nothing should stay pressed
+    pub fn lift(&mut self) {
+        if let Err(err) = self.touch.lift() {
+            warn!("Failed to lift the finger: {}", err);
+        }
+    }
+
+    pub fn touch_path(&self) -> Option<&str> {
+        self.touch.device_path()
+    }
+
+    // Injects it, unless it's a retry of something already done. None means it worked,
+    // the error goes back to the host in InputAck
+    pub fn handle(&mut self, id: u32, input: Input) -> Option<String> {
+        if let Some((_, result)) = self.done.iter().find(|(done, _)| *done == id) {
+            debug!("Input {} was done already, the host retried it", id);
+            return result.clone();
+        }
+        let result = self.inject(input).err().map(|err| err.to_string());
+        self.done.push_back((id, result.clone()));
+        if self.done.len() > DONE_IDS {
+            self.done.pop_front();
+        }
+        result
+    }
+
+    fn inject(&mut self, input: Input) -> io::Result<()> {
+        match input {
+            Input::TouchDown(x, y) => {
+                info!("Received TouchDown from server: x:{} y:{}", x, y);
+                self.touch.down(x, y)
+            }
+            Input::TouchUp { x, y, held_ms } => {
+                info!(
+                    "Received TouchUp from server: x:{} y:{} after {} ms",
+                    x, y, held_ms
+                );
+                self.touch.up(x, y, Duration::from_millis(held_ms as u64))
+            }
+            Input::Gesture(points) => {
+                info!("Received Gesture from server with {} points", points.len());
+                self.touch.stroke(&points)
+            }
+            Input::Pinch(points) => {
+                info!("Received Pinch from server with {} points", points.len());
+                self.touch.pinch(&points)
+            }
+            Input::Key(code, pressed) => {
+                info!("Received Key from server: {} pressed: {}", code, pressed);
+                self.keys.key(code, pressed)
+            }
+            Input::Text(text) => {
+                info!(
+                    "Received Text from server, {} characters",
+                    text.chars().count()
+                );
+                match &mut self.text {
+                    Some(typer) => typer.type_text(&text, self.touch.as_mut()),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "no way to type text, no uinput and no --keyboard-layout",
+                    )),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Touches = Arc<Mutex<Vec<(u16, u16)>>>;
+
+    // Remembers the touches that are still down
+    struct FakeTouch(Touches);
+
+    impl TouchInput for FakeTouch {
+        fn down(&mut self, x: u16, y: u16) -> io::Result<()> {
+            self.0.lock().unwrap().push((x, y));
+            Ok(())
+        }
+
+        fn up(&mut self, _x: u16, _y: u16, _held: Duration) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn lift(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().clear();
+            Ok(())
+        }
+    }
+
+    fn injector() -> (Injector, Touches) {
+        let touches = Arc::new(Mutex::new(Vec::new()));
+        let touch = Box::new(FakeTouch(touches.clone()));
+        let injector = Injector::new(touch, Keys::new(None, Vec::new()), None);
+        (injector, touches)
+    }
+
+    #[test]
+    fn retries_are_not_injected_twice() {
+        let (mut injector, touches) = injector();
+        assert_eq!(injector.handle(1, Input::TouchDown(10, 20)), None);
+        assert_eq!(injector.handle(2, Input::TouchDown(30, 40)), None);
+        assert_eq!(injector.handle(1, Input::TouchDown(10, 20)), None);
+        assert_eq!(*touches.lock().unwrap(), [(10, 20), (30, 40)]);
+        // Long forgotten ids are new again
+        for id in 3..3 + DONE_IDS as u32 {
+            injector.handle(
+                id,
+                Input::TouchUp {
+                    x: 0,
+                    y: 0,
+                    held_ms: 0,
+                },
+            );
+        }
+        injector.handle(1, Input::TouchDown(10, 20));
+        assert_eq!(touches.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn failures_are_reported() {
+        let (mut injector, _) = injector();
+        assert_eq!(injector.inputs(), [InputKind::Click]);
+        assert!(injector.handle(1, Input::Key(116, true)).is_some());
+        assert!(injector.handle(2, Input::Text(String::from("a"))).is_some());
+        assert!(injector.handle(3, Input::Gesture(Vec::new())).is_some());
+        // A retry of a failed one gets the same answer
+        assert_eq!(
+            injector.handle(1, Input::Key(116, true)),
+            injector.handle(1, Input::Key(0, false))
+        );
+    }
+
+    #[test]
+    fn another_touchscreen_lifts_the_old_one() {
+        let (mut injector, old) = injector();
+        injector.handle(1, Input::TouchDown(10, 20));
+        let new = Arc::new(Mutex::new(Vec::new()));
+        injector.set_touch(Box::new(FakeTouch(new.clone())));
+        assert!(old.lock().unwrap().is_empty());
+        injector.handle(2, Input::TouchDown(30, 40));
+        assert_eq!(*new.lock().unwrap(), [(30, 40)]);
+    }
+}
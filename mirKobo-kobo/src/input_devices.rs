@@ -0,0 +1,276 @@
+// Logging
+use log::debug;
+
+// Device
+use crate::touch::{
+    MtProtocol, ABS_MT_POSITION_X, ABS_MT_POSITION_Y, ABS_MT_SLOT, ABS_X, ABS_Y, BTN_TOUCH, EV_ABS,
+    EV_KEY,
+};
+use std::io;
+use std::path::Path;
+
+// From linux/input-event-codes.h, the device is on top of a screen
+const INPUT_PROP_DIRECT: u16 = 0x01;
+
+// One block of /proc/bus/input/devices
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputDevice {
+    pub name: String,
+    pub handlers: Vec<String>, // event1, kbd, mouse0...
+    ev: Vec<u64>,              // Bitmaps, lowest bits first
+    abs: Vec<u64>,
+    key: Vec<u64>,
+    prop: Vec<u64>,
+}
+
+// The kernel prints bitmaps as hex longs, most significant first. Longs are 32 bits
+// on the Kobo kernels, 64 on a pc
+fn parse_bitmap(text: &str, word_bits: u32) -> Vec<u64> {
+    let words: Vec<u64> = text
+        .split_whitespace()
+        .rev()
+        .map(|word| u64::from_str_radix(word, 16).unwrap_or(0))
+        .collect();
+    if word_bits == 64 {
+        return words;
+    }
+    words
+        .chunks(2)
+        .map(|pair| pair[0] | pair.get(1).map_or(0, |high| high << 32))
+        .collect()
+}
+
+fn has_bit(bitmap: &[u64], bit: u16) -> bool {
+    bitmap
+        .get(bit as usize / 64)
+        .is_some_and(|word| word & (1 << (bit % 64)) != 0)
+}
+
+impl InputDevice {
+    pub fn has_abs(&self, code: u16) -> bool {
+        has_bit(&self.ev, EV_ABS) && has_bit(&self.abs, code)
+    }
+
+    pub fn has_key(&self, code: u16) -> bool {
+        has_bit(&self.ev, EV_KEY) && has_bit(&self.key, code)
+    }
+
+    pub fn is_multitouch(&self) -> bool {
+        self.has_abs(ABS_MT_POSITION_X) && self.has_abs(ABS_MT_POSITION_Y)
+    }
+
+    // Accelerometers have absolute x and y too, without touches or being on the screen
+    // it's not a touchscreen
+    pub fn is_touchscreen(&self) -> bool {
+        let touches = self.has_key(BTN_TOUCH) || has_bit(&self.prop, INPUT_PROP_DIRECT);
+        self.is_multitouch() || (self.has_abs(ABS_X) && self.has_abs(ABS_Y) && touches)
+    }
+
+    // Slots mean protocol B
+    pub fn mt_protocol(&self) -> MtProtocol {
+        if !self.is_multitouch() {
+            MtProtocol::Single
+        } else if self.has_abs(ABS_MT_SLOT) {
+            MtProtocol::B
+        } else {
+            MtProtocol::A
+        }
+    }
+
+    // /dev/input/eventN
+    pub fn device_path(&self) -> Option<String> {
+        self.handlers
+            .iter()
+            .find(|handler| handler.starts_with("event"))
+            .map(|event| format!("/dev/input/{}", event))
+    }
+}
+
+pub fn parse(text: &str, word_bits: u32) -> Vec<InputDevice> {
+    let mut devices = Vec::new();
+    for block in text.split("\n\n") {
+        let mut device = InputDevice::default();
+        for line in block.lines() {
+            let Some((kind, rest)) = line.split_once(": ") else {
+                continue;
+            };
+            let Some((key, value)) = rest.split_once('=') else {
+                continue;
+            };
+            match (kind, key) {
+                ("N", "Name") => device.name = value.trim_matches('"').to_string(),
+                ("H", "Handlers") => {
+                    device.handlers = value.split_whitespace().map(String::from).collect()
+                }
+                ("B", "EV") => device.ev = parse_bitmap(value, word_bits),
+                ("B", "ABS") => device.abs = parse_bitmap(value, word_bits),
+                ("B", "KEY") => device.key = parse_bitmap(value, word_bits),
+                ("B", "PROP") => device.prop = parse_bitmap(value, word_bits),
+                _ => (),
+            }
+        }
+        if !device.handlers.is_empty() {
+            devices.push(device);
+        }
+    }
+    devices
+}
+
+// Every input device, proc_root is /proc or a fake one for tests
+pub fn list(proc_root: &Path) -> io::Result<Vec<InputDevice>> {
+    let text = std::fs::read_to_string(proc_root.join("bus/input/devices"))?;
+    let devices = parse(&text, libc::c_ulong::BITS);
+    debug!("Input devices: {:?}", devices);
+    Ok(devices)
+}
+
+// Multitouch panels first, then single touch ones. None leaves it to touch_emulate
+pub fn find_touchscreen(devices: &[InputDevice]) -> Option<&InputDevice> {
+    let with_event = |device: &&InputDevice| device.device_path().is_some();
+    devices
+        .iter()
+        .filter(with_event)
+        .find(|device| device.is_multitouch())
+        .or_else(|| {
+            devices
+                .iter()
+                .filter(with_event)
+                .find(|device| device.is_touchscreen())
+        })
+}
+
+// The first one with an event device that has this key, writes to others are dropped by the kernel
+pub fn find_key(devices: &[InputDevice], code: u16) -> Option<&InputDevice> {
+    devices
+        .iter()
+        .find(|device| device.has_key(code) && device.device_path().is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A Kobo Clara HD like layout, 32 bit longs
+    const KOBO: &str = r#"I: Bus=0019 Vendor=0001 Product=0001 Version=0100
+N: Name="gpio-keys"
+P: Phys=gpio-keys/input0
+S: Sysfs=/devices/platform/gpio-keys/input/input0
+U: Uniq=
+H: Handlers=kbd event0
+B: PROP=0
+B: EV=3
+B: KEY=100000 0 0 0
+
+I: Bus=0019 Vendor=0000 Product=0000 Version=0000
+N: Name="mma8x5x"
+P: Phys=
+S: Sysfs=/devices/virtual/input/input2
+U: Uniq=
+H: Handlers=event2
+B: PROP=0
+B: EV=9
+B: ABS=7
+
+I: Bus=0018 Vendor=0000 Product=0000 Version=0000
+N: Name="cyttsp5_mt"
+P: Phys=cyttsp5_mt
+S: Sysfs=/devices/virtual/input/input1
+U: Uniq=
+H: Handlers=event1
+B: PROP=2
+B: EV=b
+B: KEY=400 0 0 0 0 0 0 0 0 0 0
+B: ABS=2658000 3
+"#;
+
+    #[test]
+    fn bitmaps_of_both_word_sizes() {
+        let short = parse_bitmap("2658000 3", 32);
+        let long = parse_bitmap("265800000000003", 64);
+        assert_eq!(short, long);
+        for bit in [
+            ABS_X,
+            ABS_Y,
+            ABS_MT_SLOT,
+            ABS_MT_POSITION_X,
+            ABS_MT_POSITION_Y,
+        ] {
+            assert!(has_bit(&short, bit), "bit {}", bit);
+        }
+        assert!(!has_bit(&short, 2));
+        assert!(!has_bit(&short, 200));
+    }
+
+    #[test]
+    fn parses_every_device() {
+        let devices = parse(KOBO, 32);
+        let names: Vec<&str> = devices.iter().map(|device| device.name.as_str()).collect();
+        assert_eq!(names, ["gpio-keys", "mma8x5x", "cyttsp5_mt"]);
+        assert_eq!(devices[0].handlers, ["kbd", "event0"]);
+        assert!(!devices[0].is_touchscreen());
+        // An accelerometer has absolute x and y too, but no touches
+        assert!(!devices[1].is_touchscreen());
+        assert!(!devices[1].is_multitouch());
+        assert!(devices[2].is_touchscreen());
+        // A single touch panel
+        let single = KOBO.replace(
+            "B: EV=9\nB: ABS=7",
+            "B: EV=b\nB: KEY=400 0 0 0 0 0 0 0 0 0 0\nB: ABS=7",
+        );
+        assert!(parse(&single, 32)[1].is_touchscreen());
+    }
+
+    #[test]
+    fn finds_the_multitouch_panel() {
+        let devices = parse(KOBO, 32);
+        let found = find_touchscreen(&devices).unwrap();
+        assert_eq!(found.name, "cyttsp5_mt");
+        assert_eq!(found.device_path().as_deref(), Some("/dev/input/event1"));
+        assert_eq!(found.mt_protocol(), MtProtocol::B);
+        // Writing touches to the accelerometer would do nothing
+        assert!(find_touchscreen(&devices[..2]).is_none());
+        assert!(find_touchscreen(&devices[..1]).is_none());
+    }
+
+    #[test]
+    fn finds_the_keys() {
+        let devices = parse(KOBO, 32);
+        // KEY_POWER
+        assert_eq!(find_key(&devices, 116).unwrap().name, "gpio-keys");
+        assert_eq!(find_key(&devices, BTN_TOUCH).unwrap().name, "cyttsp5_mt");
+        // No page turn buttons on a Clara
+        assert!(find_key(&devices, 194).is_none());
+    }
+
+    #[test]
+    fn lists_a_fake_proc() {
+        // The fixture is from a 32 bit kernel, a 64 bit one prints one long
+        let text = match libc::c_ulong::BITS {
+            64 => KOBO
+                .replace("ABS=2658000 3", "ABS=265800000000003")
+                .replace("KEY=100000 0 0 0", "KEY=10000000000000 0")
+                .replace("KEY=400 0 0 0 0 0 0 0 0 0 0", "KEY=400 0 0 0 0 0"),
+            _ => KOBO.to_string(),
+        };
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("bus/input")).unwrap();
+        std::fs::write(root.path().join("bus/input/devices"), text).unwrap();
+        let devices = list(root.path()).unwrap();
+        assert_eq!(devices, parse(KOBO, 32));
+        assert!(list(&root.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn protocols() {
+        let device = |abs: &str| InputDevice {
+            ev: parse_bitmap("b", 64),
+            abs: parse_bitmap(abs, 32),
+            handlers: vec![String::from("event3")],
+            ..Default::default()
+        };
+        // Positions and touch major without slots
+        assert_eq!(device("610000 3").mt_protocol(), MtProtocol::A);
+        assert_eq!(device("3").mt_protocol(), MtProtocol::Single);
+        assert!(!device("0").is_touchscreen());
+    }
+}
@@ -0,0 +1,156 @@
+// Logging
+use log::{debug, warn};
+
+// Device
+use crate::input_devices::{self, InputDevice};
+use crate::touch::{write_events, InputEvent, EV_KEY, EV_SYN, SYN_REPORT};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+// Network
+use mir_kobo_proto::{KEY_HOME, KEY_LIGHT, KEY_PAGE_BACK, KEY_PAGE_FORWARD, KEY_POWER};
+
+// Other
+use crate::Args;
+
+const PROC_PATH: &str = "/proc";
+
+// Everything the host sends
+const DEVICE_KEYS: [u16; 5] = [
+    KEY_POWER,
+    KEY_HOME,
+    KEY_LIGHT,
+    KEY_PAGE_BACK,
+    KEY_PAGE_FORWARD,
+];
+
+// Power, home, light and page turn buttons. They are spread over a few devices,
+// gpio-keys and friends, so every key goes to the one that has it
+pub struct Keys {
+    device: Option<String>, // --key-device, everything goes there
+    devices: Vec<InputDevice>,
+}
+
+impl Keys {
+    pub fn new(device: Option<String>, devices: Vec<InputDevice>) -> Self {
+        Keys { device, devices }
+    }
+
+    pub fn open(args: &Args) -> Self {
+        let devices = match input_devices::list(Path::new(PROC_PATH)) {
+            Ok(devices) => devices,
+            Err(err) => {
+                warn!("Failed to list input devices, no hardware keys: {}", err);
+                Vec::new()
+            }
+        };
+        Keys::new(args.key_device.clone(), devices)
+    }
+
+    // One of the buttons the host can press is there, for Hello
+    pub fn available(&self) -> bool {
+        self.device.is_some()
+            || DEVICE_KEYS
+                .iter()
+                .any(|code| input_devices::find_key(&self.devices, *code).is_some())
+    }
+
+    pub fn device_path(&self, code: u16) -> Option<String> {
+        match &self.device {
+            Some(path) => Some(path.clone()),
+            None => input_devices::find_key(&self.devices, code)?.device_path(),
+        }
+    }
+
+    pub fn key(&self, code: u16, pressed: bool) -> io::Result<()> {
+        let Some(path) = self.device_path(code) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no input device has key {}", code),
+            ));
+        };
+        debug!(
+            "Key {} {} on {}",
+            code,
+            if pressed { "down" } else { "up" },
+            path
+        );
+        // Opened every time, keys are rare. Append doesn't matter for a device, but keeps
+        // a plain file from being overwritten
+        let mut out = OpenOptions::new().append(true).open(&path)?;
+        press(&mut out, code, pressed)
+    }
+}
+
+pub fn press(out: &mut impl Write, code: u16, pressed: bool) -> io::Result<()> {
+    write_events(
+        out,
+        &[
+            InputEvent::new(EV_KEY, code, pressed as i32),
+            InputEvent::new(EV_SYN, SYN_REPORT, 0),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek};
+
+    const DEVICES: &str = r#"N: Name="gpio-keys"
+H: Handlers=kbd event0
+B: EV=3
+B: KEY=100000 0 0 0
+
+N: Name="ntx_event0"
+H: Handlers=kbd event3
+B: EV=3
+B: KEY=6 0 0 0 0 0 0
+"#;
+
+    #[test]
+    fn every_key_goes_to_its_device() {
+        let keys = Keys::new(None, input_devices::parse(DEVICES, 32));
+        assert!(keys.available());
+        assert_eq!(keys.device_path(116).as_deref(), Some("/dev/input/event0"));
+        assert_eq!(keys.device_path(194).as_deref(), Some("/dev/input/event3"));
+        assert!(keys.device_path(102).is_none());
+        assert!(keys.key(102, true).is_err());
+        assert!(!Keys::new(None, Vec::new()).available());
+        // Only BTN_TOUCH, no button the host knows about
+        let touch =
+            "N: Name=\"cyttsp5_mt\"\nH: Handlers=event1\nB: EV=b\nB: KEY=400 0 0 0 0 0 0 0 0 0 0\n";
+        assert!(!Keys::new(None, input_devices::parse(touch, 32)).available());
+    }
+
+    #[test]
+    fn key_device_gets_everything() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_str().unwrap().to_string();
+        let keys = Keys::new(Some(path), Vec::new());
+        keys.key(116, true).unwrap();
+        keys.key(116, false).unwrap();
+        let mut bytes = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut bytes).unwrap();
+        let events: Vec<(u16, u16, i32)> = bytes
+            .chunks_exact(std::mem::size_of::<libc::input_event>())
+            .map(|chunk| {
+                // Safety: the chunk is exactly one input_event
+                let event: libc::input_event =
+                    unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const _) };
+                (event.type_, event.code, event.value)
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (EV_KEY, 116, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, 116, 0),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+    }
+}
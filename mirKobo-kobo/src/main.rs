@@ -1,23 +1,66 @@
-mod api;
 mod client;
+mod config;
 mod device;
+mod framebuffer;
+mod inject;
+mod input_devices;
+mod keys;
+mod model;
+mod pixel_format;
+mod screen;
+mod streaming;
+mod targets;
+mod text;
+mod touch;
+mod touch_watch;
 
 // Logging
-use log::info;
+use log::{error, info};
 
 // Network
 use message_io::network::{ToRemoteAddr, Transport};
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use screen::ScreenBackend;
+use text::TextBackend;
+use touch::{MtProtocol, TouchBackend};
+use std::ffi::OsString;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 pub struct Args {
     #[arg(short, long, help = "Address and port of mirKobo-host using syntax address:port, the default is default InkBox OS usbnet settings", default_value_t = String::from("192.168.2.3:24356"))]
     remote_addr: String,
-    #[arg(short, long, help = "Path to fbgrab binary", default_value_t = String::from("/usr/bin/fbgrab"))]
+    #[arg(long, help = "Config file with the same keys as the arguments, like remote_addr = \"192.168.2.3:24356\". The arguments go over it [default: mirkobo.toml next to this binary]")]
+    config: Option<PathBuf>,
+    #[arg(short, long, help = "Path to fbgrab binary, used when the framebuffer can't be read directly", default_value_t = String::from("/usr/bin/fbgrab"))]
     fbgrab_path: String,
-    #[arg(short, long, help = "Path to touch_emulate binary", default_value_t = String::from("./touch_emulate.bin"))]
+    #[arg(long, value_enum, help = "Where to take the screen from, auto reads the framebuffer directly and falls back to fbgrab", default_value_t = ScreenBackend::Auto)]
+    screen_source: ScreenBackend,
+    #[arg(long, help = "Framebuffer device to capture from", default_value_t = String::from("/dev/fb0"))]
+    framebuffer_path: String,
+    #[arg(long, help = "Directory with png images to loop over, for the replay screen source")]
+    replay_dir: Option<PathBuf>,
+    #[arg(long, help = "Send a full frame every this many frames, only the changed parts are sent in between. 1 sends only full frames", default_value_t = 30)]
+    keyframe_interval: u32,
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100), help = "Percent of one cpu capturing and encoding frames may use, the host slows down its requests to match. No limit by default")]
+    cpu_budget: Option<u8>,
+    #[arg(long, value_enum, help = "How to inject touches, auto writes to the touchscreen directly and falls back to touch_emulate", default_value_t = TouchBackend::Auto)]
+    touch_backend: TouchBackend,
+    #[arg(long, help = "Touchscreen event device, like /dev/input/event1. Detected from /proc/bus/input/devices by default")]
+    touch_device: Option<String>,
+    #[arg(long, value_enum, help = "Multitouch protocol of the touchscreen, older Kobos use a, newer ones b. Detected with the device by default")]
+    touch_protocol: Option<MtProtocol>,
+    #[arg(long, help = "Event device for the power, home and page turn buttons. By default every key goes to the device from /proc/bus/input/devices that has it")]
+    key_device: Option<String>,
+    #[arg(long, value_enum, help = "How to type text from the host, auto uses a virtual keyboard and falls back to tapping --keyboard-layout", default_value_t = TextBackend::Auto)]
+    text_backend: TextBackend,
+    #[arg(long, help = "File with the positions of the on screen keyboard keys in raw touchscreen coordinates, for typing without a virtual keyboard. Only right for the rotation it was written for. See text.rs for the format")]
+    keyboard_layout: Option<PathBuf>,
+    #[arg(long, help = "Model reported to the host, which loads the profile for it. Read from /opt/inkbox_device or the Kobo version file by default")]
+    model: Option<String>,
+    #[arg(short, long, help = "Path to touch_emulate binary, used when the touchscreen can't be written directly", default_value_t = String::from("./touch_emulate.bin"))]
     touch_emulate_path: String,
     #[arg(short, long, help = "Path to busybox binary (we need fbset for screen size reporting)", default_value_t = String::from("/bin/busybox"))]
     busybox_path: String,
@@ -29,7 +72,13 @@ fn main() {
     );
 
     // Arguments
-    let args = Args::parse();
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let (_, values) = config::initial(&argv, config::default_path()).unwrap_or_else(|err| {
+        error!("{}", err);
+        std::process::exit(1)
+    });
+    let matches = config::apply(Args::command(), &values).get_matches_from(&argv);
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
     //let remote_addr = "192.168.2.3:24356";
     //let remote_addr = "127.0.0.1:24356";
 
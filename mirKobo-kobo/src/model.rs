@@ -0,0 +1,59 @@
+// Logging
+use log::{debug, info};
+
+// Device
+use std::path::Path;
+
+// Other
+use crate::Args;
+
+// InkBox writes the model codename here, like n873
+const INKBOX_DEVICE: &str = "/opt/inkbox_device";
+// Stock firmware, the last field ends with the product id
+const KOBO_VERSION: &str = "/mnt/onboard/.kobo/version";
+
+fn inkbox_model(text: &str) -> Option<String> {
+    let model = text.trim();
+    (!model.is_empty()).then(|| model.to_string())
+}
+
+// Like N249...,4.1.15,4.38.21908,4.1.15,4.1.15,00000000-0000-0000-0000-000000000387
+fn kobo_model(text: &str) -> Option<String> {
+    let id = text.trim().rsplit(',').next()?.rsplit('-').next()?;
+    let id = id.parse::<u32>().ok()?;
+    Some(format!("kobo-{}", id))
+}
+
+fn read(path: &str, parse: fn(&str) -> Option<String>) -> Option<String> {
+    let text = std::fs::read_to_string(Path::new(path)).ok()?;
+    let model = parse(&text);
+    debug!("Model from {}: {:?}", path, model);
+    model
+}
+
+// Goes in the Hello, the host picks its profile with it
+pub fn detect(args: &Args) -> Option<String> {
+    let model = args
+        .model
+        .clone()
+        .or_else(|| read(INKBOX_DEVICE, inkbox_model))
+        .or_else(|| read(KOBO_VERSION, kobo_model));
+    info!("Device model: {:?}", model);
+    model
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_model_files() {
+        assert_eq!(inkbox_model("n873\n"), Some(String::from("n873")));
+        assert_eq!(inkbox_model("\n"), None);
+        assert_eq!(
+            kobo_model("N249210000000,4.1.15,4.38.21908,4.1.15,4.1.15,00000000-0000-0000-0000-000000000387\n"),
+            Some(String::from("kobo-387"))
+        );
+        assert_eq!(kobo_model("garbage"), None);
+    }
+}
@@ -0,0 +1,268 @@
+// Device
+use crate::framebuffer::{FbBitfield, VarScreenInfo};
+use std::io;
+
+// Other
+use mir_kobo_proto::luma; // The same gray as png frames get on the host
+
+// var.grayscale from mxcfb.h, panels that store white as 0
+const GRAYSCALE_8BIT_INVERTED: u32 = 0x2;
+
+pub fn supported(bits_per_pixel: u32) -> bool {
+    matches!(bits_per_pixel, 8 | 16 | 24 | 32)
+}
+
+// Drivers that fill in the bitfields are the norm, but guess for the ones that don't
+fn channels(var: &VarScreenInfo) -> (FbBitfield, FbBitfield, FbBitfield) {
+    if var.red.length != 0 || var.green.length != 0 || var.blue.length != 0 {
+        return (var.red, var.green, var.blue);
+    }
+    let field = |offset, length| FbBitfield {
+        offset,
+        length,
+        msb_right: 0,
+    };
+    match var.bits_per_pixel {
+        16 => (field(11, 5), field(5, 6), field(0, 5)), // RGB565
+        _ => (field(16, 8), field(8, 8), field(0, 8)),  // XRGB8888 and RGB888
+    }
+}
+
+// Scales a channel of any length to 0..=255
+fn channel(pixel: u32, field: &FbBitfield) -> u32 {
+    if field.length == 0 {
+        return 0;
+    }
+    let max = (1u32 << field.length.min(31)) - 1;
+    let value = (pixel >> field.offset) & max;
+    (value * 255 + max / 2) / max
+}
+
+// Turns the raw framebuffer memory into xres * yres bytes of 8 bit gray, honoring
+// the line padding and the panning offsets
+pub fn to_gray(raw: &[u8], var: &VarScreenInfo, line_length: u32) -> io::Result<Vec<u8>> {
+    if !supported(var.bits_per_pixel) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} bits per pixel is not supported", var.bits_per_pixel),
+        ));
+    }
+    let bytes_per_pixel = var.bits_per_pixel as usize / 8;
+    let (width, height) = (var.xres as usize, var.yres as usize);
+    let stride = line_length as usize;
+    let start = var.yoffset as usize * stride + var.xoffset as usize * bytes_per_pixel;
+    let needed = match height {
+        0 => start,
+        _ => start + (height - 1) * stride + width * bytes_per_pixel,
+    };
+    if needed > raw.len() || width * bytes_per_pixel > stride {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "framebuffer is {} bytes, {}x{} at {} bpp with line length {} needs {}",
+                raw.len(),
+                width,
+                height,
+                var.bits_per_pixel,
+                stride,
+                needed
+            ),
+        ));
+    }
+
+    let mut gray = Vec::with_capacity(width * height);
+    let (red, green, blue) = channels(var);
+    let inverted = var.grayscale == GRAYSCALE_8BIT_INVERTED;
+    for y in 0..height {
+        let line = &raw[start + y * stride..start + y * stride + width * bytes_per_pixel];
+        if bytes_per_pixel == 1 && inverted {
+            gray.extend(line.iter().map(|value| 255 - value));
+            continue;
+        }
+        if bytes_per_pixel == 1 {
+            gray.extend_from_slice(line);
+            continue;
+        }
+        for pixel in line.chunks_exact(bytes_per_pixel) {
+            let mut value = 0u32;
+            for (i, byte) in pixel.iter().enumerate() {
+                value |= (*byte as u32) << (i * 8);
+            }
+            gray.push(luma(
+                channel(value, &red),
+                channel(value, &green),
+                channel(value, &blue),
+            ));
+        }
+    }
+    Ok(gray)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every golden image is 2x2: white, black / red, gray
+    const GOLDEN: [u8; 4] = [255, 0, 77, 128];
+
+    fn var(bits_per_pixel: u32) -> VarScreenInfo {
+        VarScreenInfo {
+            xres: 2,
+            yres: 2,
+            xres_virtual: 2,
+            yres_virtual: 2,
+            bits_per_pixel,
+            ..Default::default()
+        }
+    }
+
+    // Lays out pixels in lines of line_length, starting at the panned offset
+    fn raw(
+        pixels: &[&[u8]],
+        bytes_per_pixel: usize,
+        line_length: usize,
+        xoffset: usize,
+        yoffset: usize,
+    ) -> Vec<u8> {
+        let lines = yoffset + 2;
+        let mut raw = vec![0xEE; line_length * lines];
+        for (i, pixel) in pixels.iter().enumerate() {
+            let (x, y) = (i % 2 + xoffset, i / 2 + yoffset);
+            let at = y * line_length + x * bytes_per_pixel;
+            raw[at..at + bytes_per_pixel].copy_from_slice(pixel);
+        }
+        raw
+    }
+
+    #[test]
+    fn gray8() {
+        let raw = raw(&[&[255], &[0], &[77], &[128]], 1, 2, 0, 0);
+        assert_eq!(to_gray(&raw, &var(8), 2).unwrap(), GOLDEN);
+    }
+
+    #[test]
+    fn gray8_inverted() {
+        let raw = raw(&[&[0], &[255], &[178], &[127]], 1, 2, 0, 0);
+        let mut var = var(8);
+        var.grayscale = GRAYSCALE_8BIT_INVERTED;
+        assert_eq!(to_gray(&raw, &var, 2).unwrap(), GOLDEN);
+    }
+
+    #[test]
+    fn gray8_with_padding_and_panning() {
+        let raw = raw(&[&[255], &[0], &[77], &[128]], 1, 7, 3, 2);
+        let mut var = var(8);
+        var.xoffset = 3;
+        var.yoffset = 2;
+        assert_eq!(to_gray(&raw, &var, 7).unwrap(), GOLDEN);
+    }
+
+    #[test]
+    fn rgb565() {
+        // Little endian 0xFFFF, 0x0000, 0xF800, 0x8410. 565 gray is slightly green, so 131
+        let raw = raw(
+            &[&[0xFF, 0xFF], &[0, 0], &[0x00, 0xF8], &[0x10, 0x84]],
+            2,
+            6,
+            0,
+            0,
+        );
+        let mut var = var(16);
+        var.red = FbBitfield {
+            offset: 11,
+            length: 5,
+            msb_right: 0,
+        };
+        var.green = FbBitfield {
+            offset: 5,
+            length: 6,
+            msb_right: 0,
+        };
+        var.blue = FbBitfield {
+            offset: 0,
+            length: 5,
+            msb_right: 0,
+        };
+        assert_eq!(to_gray(&raw, &var, 6).unwrap(), [255, 0, 77, 131]);
+        // Same without bitfields, RGB565 is the guess for 16 bpp
+        assert_eq!(to_gray(&raw, &self::var(16), 6).unwrap(), [255, 0, 77, 131]);
+    }
+
+    #[test]
+    fn rgb888() {
+        let raw = raw(
+            &[&[255, 255, 255], &[0, 0, 0], &[0, 0, 255], &[128, 128, 128]],
+            3,
+            6,
+            0,
+            0,
+        );
+        assert_eq!(to_gray(&raw, &var(24), 6).unwrap(), GOLDEN);
+    }
+
+    #[test]
+    fn xrgb8888_panned() {
+        let raw = raw(
+            &[
+                &[255, 255, 255, 0],
+                &[0, 0, 0, 0],
+                &[0, 0, 255, 0],
+                &[128, 128, 128, 0],
+            ],
+            4,
+            16,
+            1,
+            1,
+        );
+        let mut var = var(32);
+        var.xoffset = 1;
+        var.yoffset = 1;
+        assert_eq!(to_gray(&raw, &var, 16).unwrap(), GOLDEN);
+    }
+
+    #[test]
+    fn bgr8888_bitfields() {
+        // Red in the low byte
+        let raw = raw(
+            &[
+                &[255, 255, 255, 255],
+                &[0, 0, 0, 255],
+                &[255, 0, 0, 255],
+                &[128, 128, 128, 255],
+            ],
+            4,
+            8,
+            0,
+            0,
+        );
+        let mut var = var(32);
+        var.red = FbBitfield {
+            offset: 0,
+            length: 8,
+            msb_right: 0,
+        };
+        var.green = FbBitfield {
+            offset: 8,
+            length: 8,
+            msb_right: 0,
+        };
+        var.blue = FbBitfield {
+            offset: 16,
+            length: 8,
+            msb_right: 0,
+        };
+        var.transp = FbBitfield {
+            offset: 24,
+            length: 8,
+            msb_right: 0,
+        };
+        assert_eq!(to_gray(&raw, &var, 8).unwrap(), GOLDEN);
+    }
+
+    #[test]
+    fn too_small_or_unsupported() {
+        assert!(to_gray(&[0; 3], &var(8), 2).is_err());
+        assert!(to_gray(&[0; 16], &var(32), 4).is_err()); // line shorter than a row
+        assert!(to_gray(&[0; 64], &var(4), 8).is_err());
+    }
+}
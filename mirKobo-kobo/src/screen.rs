@@ -0,0 +1,243 @@
+// Logging
+use log::{info, warn};
+
+// Device
+use crate::device::{get_screen, get_screen_size};
+use crate::framebuffer::Framebuffer;
+use crate::streaming::fingerprint;
+
+// Other
+use crate::Args;
+use mir_kobo_proto::png_to_gray;
+use clap::ValueEnum;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Lines hashed by the checks between the full ones. A cursor or an underline can be a
+// single line tall, the full checks catch what this misses
+const FINGERPRINT_LINE_STEP: usize = 8;
+
+// Where the frames come from
+pub trait ScreenSource: Send {
+    // The whole screen, size().0 * size().1 bytes of 8 bit gray
+    fn frame(&mut self) -> io::Result<Vec<u8>>;
+    // x, y
+    fn size(&mut self) -> io::Result<(u32, u32)>;
+    // Changes whenever the screen does, or only when the sampled part does if full is
+    // false. Sources that can't do it cheaper than frame() give the frame too, so a
+    // changed screen isn't captured twice. fbgrab can't, every check is a whole capture
+    fn fingerprint(&mut self, _full: bool) -> io::Result<(u64, Option<Vec<u8>>)> {
+        let frame = self.frame()?;
+        Ok((fingerprint(&frame), Some(frame)))
+    }
+    // Quarter turns clockwise, like var.rotate
+    fn rotation(&mut self) -> u32 {
+        0
+    }
+    // The new size and rotation if the screen was turned since the last call
+    fn turned(&mut self) -> io::Result<Option<((u32, u32), u32)>> {
+        Ok(None)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScreenBackend {
+    Auto,        // Framebuffer if it can be read, fbgrab otherwise
+    Framebuffer, // Read the framebuffer device directly
+    Fbgrab,      // Launch fbgrab for every frame, and every check for changes when streaming
+    Replay,      // Loop over images from a directory, for tests and demos
+}
+
+pub struct FbgrabSource {
+    fbgrab_path: String,
+    busybox_path: String,
+}
+
+impl FbgrabSource {
+    pub fn new(fbgrab_path: &str, busybox_path: &str) -> Self {
+        FbgrabSource {
+            fbgrab_path: fbgrab_path.to_string(),
+            busybox_path: busybox_path.to_string(),
+        }
+    }
+}
+
+impl ScreenSource for FbgrabSource {
+    fn frame(&mut self) -> io::Result<Vec<u8>> {
+        let (_, _, gray) = png_to_gray(&get_screen(&self.fbgrab_path)).map_err(io::Error::other)?;
+        Ok(gray)
+    }
+
+    fn size(&mut self) -> io::Result<(u32, u32)> {
+        Ok(get_screen_size(&self.busybox_path))
+    }
+}
+
+impl ScreenSource for Framebuffer {
+    fn frame(&mut self) -> io::Result<Vec<u8>> {
+        self.gray()
+    }
+
+    fn size(&mut self) -> io::Result<(u32, u32)> {
+        Ok(Framebuffer::size(self))
+    }
+
+    fn fingerprint(&mut self, full: bool) -> io::Result<(u64, Option<Vec<u8>>)> {
+        let line_step = if full { 1 } else { FINGERPRINT_LINE_STEP };
+        Ok((Framebuffer::fingerprint(self, line_step), None))
+    }
+
+    fn rotation(&mut self) -> u32 {
+        self.var.rotate
+    }
+
+    fn turned(&mut self) -> io::Result<Option<((u32, u32), u32)>> {
+        if !self.refresh()? {
+            return Ok(None);
+        }
+        Ok(Some((Framebuffer::size(self), self.var.rotate)))
+    }
+}
+
+// PNG files from a directory, sorted by name, looping forever
+pub struct ReplaySource {
+    frames: Vec<PathBuf>,
+    next: usize,
+}
+
+impl ReplaySource {
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        let mut frames: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+            })
+            .collect();
+        if frames.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no png files in {}", dir.display()),
+            ));
+        }
+        frames.sort();
+        Ok(ReplaySource { frames, next: 0 })
+    }
+}
+
+impl ScreenSource for ReplaySource {
+    fn frame(&mut self) -> io::Result<Vec<u8>> {
+        let data = std::fs::read(&self.frames[self.next])?;
+        self.next = (self.next + 1) % self.frames.len();
+        let (_, _, gray) = png_to_gray(&data).map_err(io::Error::other)?;
+        Ok(gray)
+    }
+
+    // Of the first image, the rest should match it
+    fn size(&mut self) -> io::Result<(u32, u32)> {
+        let file = std::fs::File::open(&self.frames[0])?;
+        let reader = png::Decoder::new(file)
+            .read_info()
+            .map_err(io::Error::other)?;
+        let info = reader.info();
+        Ok((info.width, info.height))
+    }
+}
+
+pub fn open(args: &Args) -> io::Result<Box<dyn ScreenSource>> {
+    let framebuffer_path = args.framebuffer_path.as_str();
+    let fbgrab_path = args.fbgrab_path.as_str();
+    let busybox_path = args.busybox_path.as_str();
+    match args.screen_source {
+        ScreenBackend::Auto => match Framebuffer::open(framebuffer_path) {
+            Ok(framebuffer) if framebuffer.supported() => {
+                info!("Capturing {} directly", framebuffer_path);
+                Ok(Box::new(framebuffer))
+            }
+            Ok(framebuffer) => {
+                warn!(
+                    "{} has {} bits per pixel which we can't convert, falling back to fbgrab",
+                    framebuffer_path, framebuffer.var.bits_per_pixel
+                );
+                Ok(Box::new(FbgrabSource::new(fbgrab_path, busybox_path)))
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to open {}: {}, falling back to fbgrab",
+                    framebuffer_path, err
+                );
+                Ok(Box::new(FbgrabSource::new(fbgrab_path, busybox_path)))
+            }
+        },
+        ScreenBackend::Framebuffer => {
+            let framebuffer = Framebuffer::open(framebuffer_path)?;
+            if !framebuffer.supported() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "{} bits per pixel is not supported",
+                        framebuffer.var.bits_per_pixel
+                    ),
+                ));
+            }
+            Ok(Box::new(framebuffer))
+        }
+        ScreenBackend::Fbgrab => Ok(Box::new(FbgrabSource::new(fbgrab_path, busybox_path))),
+        ScreenBackend::Replay => {
+            let dir = args.replay_dir.as_deref().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "replay needs --replay-dir")
+            })?;
+            info!("Replaying images from {}", dir.display());
+            Ok(Box::new(ReplaySource::new(dir)?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn write_png(path: &Path, width: u32, height: u32, value: u8) {
+        let file = std::fs::File::create(path).unwrap();
+        let mut encoder = png::Encoder::new(file, width, height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(&vec![value; (width * height) as usize])
+            .unwrap();
+    }
+
+    #[test]
+    fn replay_loops_in_name_order() {
+        let dir = tempfile::tempdir().unwrap();
+        write_png(&dir.path().join("b.png"), 4, 2, 200);
+        write_png(&dir.path().join("a.png"), 4, 2, 100);
+        std::fs::write(dir.path().join("notes.txt"), "not a frame").unwrap();
+
+        let dir_arg = dir.path().to_str().unwrap();
+        let args = Args::parse_from([
+            "mir_kobo_kobo",
+            "--screen-source",
+            "replay",
+            "--replay-dir",
+            dir_arg,
+        ]);
+        let mut source = open(&args).unwrap();
+        assert_eq!(source.size().unwrap(), (4, 2));
+        let a = vec![100; 8];
+        let b = vec![200; 8];
+        assert_eq!(source.frame().unwrap(), a);
+        assert_eq!(source.frame().unwrap(), b);
+        assert_eq!(source.frame().unwrap(), a);
+    }
+
+    #[test]
+    fn replay_needs_images() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(ReplaySource::new(dir.path()).is_err());
+        let args = Args::parse_from(["mir_kobo_kobo", "--screen-source", "replay"]);
+        assert!(open(&args).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+// Logging
+use log::debug;
+
+// Other
+use std::hash::Hasher;
+use std::time::{Duration, Instant};
+
+// Fast and good enough to notice a changed frame, nothing else
+pub struct FrameHasher(u64);
+
+impl Default for FrameHasher {
+    fn default() -> Self {
+        FrameHasher(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for FrameHasher {
+    fn write(&mut self, data: &[u8]) {
+        const PRIME: u64 = 0x100000001b3;
+        let mut chunks = data.chunks_exact(8);
+        for chunk in &mut chunks {
+            let word = u64::from_le_bytes(chunk.try_into().unwrap());
+            self.0 = (self.0 ^ word).wrapping_mul(PRIME).rotate_left(31);
+        }
+        for byte in chunks.remainder() {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub fn fingerprint(data: &[u8]) -> u64 {
+    let mut hasher = FrameHasher::default();
+    hasher.write(data);
+    hasher.finish()
+}
+
+// Every this many checks hash the whole screen, the ones between only sample it
+const FULL_CHECK_EVERY: u32 = 4;
+
+// Decides how often to look at the screen. Right after a change it checks every
+// min interval, while nothing happens it backs off up to the max interval
+pub struct ChangePoller {
+    min: Duration,
+    max: Duration,
+    interval: Duration,
+    last: Option<u64>,      // Last sampled fingerprint
+    last_full: Option<u64>, // Last full fingerprint
+    polls: u32,
+}
+
+impl ChangePoller {
+    pub fn new(min_interval_ms: u32, max_interval_ms: u32) -> Self {
+        let min = Duration::from_millis(min_interval_ms.max(1) as u64);
+        let max = Duration::from_millis(max_interval_ms as u64).max(min);
+        ChangePoller {
+            min,
+            max,
+            interval: min,
+            last: None,
+            last_full: None,
+            polls: 0,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    // Whether the next poll wants a fingerprint of the whole screen
+    pub fn full_check(&self) -> bool {
+        self.polls.is_multiple_of(FULL_CHECK_EVERY)
+    }
+
+    // True if the screen changed since the last poll of the same kind, the first poll
+    // always counts as a change. Sampled ones start comparing after the first one
+    pub fn poll(&mut self, fingerprint: u64) -> bool {
+        let first = self.polls == 0;
+        let last = match self.full_check() {
+            true => &mut self.last_full,
+            false => &mut self.last,
+        };
+        let changed = match *last {
+            Some(last) => last != fingerprint,
+            None => first,
+        };
+        *last = Some(fingerprint);
+        self.polls = self.polls.wrapping_add(1);
+        if changed {
+            self.interval = self.min;
+        } else {
+            self.interval = (self.interval * 2).min(self.max);
+        }
+        debug!(
+            "Screen changed: {}, next check in {:?}",
+            changed, self.interval
+        );
+        changed
+    }
+}
+
+// The host clock from StartStreaming, streamed frames say when they were captured in it.
+// It's behind by the time StartStreaming took to get here, so the latency the host shows
+// misses that much
+#[derive(Clone, Copy)]
+pub struct HostClock {
+    host_us: u64,
+    received: Instant,
+}
+
+impl HostClock {
+    pub fn new(host_us: u64, received: Instant) -> Self {
+        HostClock { host_us, received }
+    }
+
+    pub fn stamp(&self, at: Instant) -> u64 {
+        let since = at.saturating_duration_since(self.received);
+        self.host_us.saturating_add(since.as_micros() as u64)
+    }
+}
+
+// Shortest time between frames that keeps making them under cpu_budget percent of one cpu
+pub fn budget_interval(busy: Duration, cpu_budget: Option<u8>) -> Duration {
+    match cpu_budget {
+        Some(percent) if percent > 0 && percent < 100 => busy * 100 / percent as u32,
+        _ => Duration::ZERO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_notices_single_byte_changes() {
+        let mut data = vec![255u8; 1072 * 3 + 5];
+        let original = fingerprint(&data);
+        for i in [0, 7, 8, 1000, data.len() - 1] {
+            data[i] = 254;
+            assert_ne!(fingerprint(&data), original, "byte {}", i);
+            data[i] = 255;
+        }
+        assert_eq!(fingerprint(&data), original);
+    }
+
+    #[test]
+    fn poller_backs_off_while_idle() {
+        let mut poller = ChangePoller::new(100, 1000);
+        assert!(poller.poll(1));
+        assert_eq!(poller.interval(), Duration::from_millis(100));
+        let intervals: Vec<u64> = (0..5)
+            .map(|_| {
+                assert!(!poller.poll(1));
+                poller.interval().as_millis() as u64
+            })
+            .collect();
+        assert_eq!(intervals, vec![200, 400, 800, 1000, 1000]);
+        assert!(poller.poll(2));
+        assert_eq!(poller.interval(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn full_checks_catch_what_sampling_misses() {
+        let mut poller = ChangePoller::new(100, 1000);
+        assert!(poller.full_check());
+        assert!(poller.poll(10));
+        // Sampled fingerprints are different numbers from the full one
+        for _ in 1..FULL_CHECK_EVERY {
+            assert!(!poller.full_check());
+            assert!(!poller.poll(20));
+        }
+        // A one line change the samples didn't see
+        assert!(poller.full_check());
+        assert!(poller.poll(11));
+        assert!(!poller.poll(20));
+        assert!(poller.poll(21));
+    }
+
+    #[test]
+    fn host_clock_runs_on_from_start_streaming() {
+        let received = Instant::now();
+        let clock = HostClock::new(5_000_000, received);
+        assert_eq!(clock.stamp(received), 5_000_000);
+        assert_eq!(
+            clock.stamp(received + Duration::from_millis(1500)),
+            6_500_000
+        );
+        // Captured before it arrived can't happen, but doesn't go back either
+        assert_eq!(clock.stamp(received - Duration::from_millis(1)), 5_000_000);
+    }
+
+    #[test]
+    fn budget_stretches_the_interval() {
+        let busy = Duration::from_millis(50);
+        assert_eq!(budget_interval(busy, None), Duration::ZERO);
+        assert_eq!(budget_interval(busy, Some(100)), Duration::ZERO);
+        assert_eq!(budget_interval(busy, Some(25)), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn poller_max_is_at_least_min() {
+        let mut poller = ChangePoller::new(500, 10);
+        poller.poll(1);
+        poller.poll(1);
+        assert_eq!(poller.interval(), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,201 @@
+// Logging
+use log::{debug, info, warn};
+
+// Device
+use crate::framebuffer::{screeninfo, FixScreenInfo, VarScreenInfo};
+use memmap2::{MmapMut, MmapOptions};
+use std::fs::{File, OpenOptions};
+use std::io;
+
+// Half the size of a target, and how thick its cross is
+const TARGET_RADIUS: i64 = 24;
+const CROSS_WIDTH: i64 = 3;
+
+// Draws calibration targets straight into the framebuffer. Only the mirror sees them,
+// the e-ink panel isn't refreshed, and the app redraws over them sooner or later
+pub struct Targets {
+    file: File,
+    map: MmapMut,
+    var: VarScreenInfo,
+    line_length: u32,
+    saved: Vec<(usize, u8, u8)>, // Under the target, what was there and what we wrote
+}
+
+impl Targets {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let (var, fix) = screeninfo(&file)?;
+        Self::from_file(&file, var, fix)
+    }
+
+    pub fn from_file(file: &File, var: VarScreenInfo, fix: FixScreenInfo) -> io::Result<Self> {
+        let needed = fix.line_length as usize * var.yres_virtual.max(var.yres) as usize;
+        let len = (fix.smem_len as usize).max(needed);
+        // Safety: like in Framebuffer, a torn target is the worst that can happen
+        let map = unsafe { MmapOptions::new().len(len).map_mut(file)? };
+        Ok(Targets {
+            file: file.try_clone()?,
+            map,
+            var,
+            line_length: fix.line_length,
+            saved: Vec::new(),
+        })
+    }
+
+    // Black on white, whatever the pixel format is, all bits off or on
+    fn fill(&mut self, x: i64, y: i64, value: u8) {
+        if x < 0 || y < 0 || x >= self.var.xres as i64 || y >= self.var.yres as i64 {
+            return;
+        }
+        let bytes_per_pixel = (self.var.bits_per_pixel as usize).div_ceil(8);
+        let start = (self.var.yoffset as usize + y as usize) * self.line_length as usize
+            + (self.var.xoffset as usize + x as usize) * bytes_per_pixel;
+        for offset in start..start + bytes_per_pixel {
+            if let Some(byte) = self.map.get_mut(offset) {
+                self.saved.push((offset, *byte, value));
+                *byte = value;
+            }
+        }
+    }
+
+    // Asks the device again, the screen may have turned since the last target. Only
+    // works on a real framebuffer device
+    fn refresh(&mut self) {
+        let (var, fix) = match screeninfo(&self.file) {
+            Ok(info) => info,
+            Err(err) => {
+                debug!(
+                    "Can't read the screen info again, keeping the old one: {}",
+                    err
+                );
+                return;
+            }
+        };
+        let layout = |var: &VarScreenInfo, line_length: u32| {
+            (
+                var.xres,
+                var.yres,
+                var.xoffset,
+                var.yoffset,
+                var.bits_per_pixel,
+                line_length,
+            )
+        };
+        if layout(&var, fix.line_length) == layout(&self.var, self.line_length) {
+            return;
+        }
+        debug!(
+            "Framebuffer changed, targets go to {}x{} now",
+            var.xres, var.yres
+        );
+        match Self::from_file(&self.file, var, fix) {
+            Ok(targets) => *self = targets,
+            Err(err) => warn!("Failed to map the framebuffer again: {}", err),
+        }
+    }
+
+    // A cross on a white square, centered on x / y. The previous one goes away
+    pub fn draw(&mut self, x: u32, y: u32) {
+        self.clear();
+        self.refresh();
+        info!("Drawing a calibration target at x:{} y:{}", x, y);
+        let (x, y) = (x as i64, y as i64);
+        for dy in -TARGET_RADIUS..=TARGET_RADIUS {
+            for dx in -TARGET_RADIUS..=TARGET_RADIUS {
+                let on_cross = dx.abs() <= CROSS_WIDTH / 2 || dy.abs() <= CROSS_WIDTH / 2;
+                self.fill(x + dx, y + dy, if on_cross { 0x00 } else { 0xFF });
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        if self.saved.is_empty() {
+            return;
+        }
+        debug!("Removing the calibration target");
+        // Whatever the app drew over the target since is newer than what we kept
+        for (offset, byte, drawn) in self.saved.drain(..) {
+            if self.map[offset] == drawn {
+                self.map[offset] = byte;
+            }
+        }
+    }
+}
+
+impl Drop for Targets {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, Write};
+
+    // 8bpp, 100x80 of gray 0x80
+    fn fake_framebuffer() -> (tempfile::NamedTempFile, VarScreenInfo, FixScreenInfo) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[0x80; 100 * 80]).unwrap();
+        let var = VarScreenInfo {
+            xres: 100,
+            yres: 80,
+            xres_virtual: 100,
+            yres_virtual: 80,
+            bits_per_pixel: 8,
+            ..Default::default()
+        };
+        let fix = FixScreenInfo {
+            smem_len: 100 * 80,
+            line_length: 100,
+            ..Default::default()
+        };
+        (file, var, fix)
+    }
+
+    fn pixels(file: &mut tempfile::NamedTempFile) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn draws_and_restores() {
+        let (mut file, var, fix) = fake_framebuffer();
+        let mut targets = Targets::from_file(file.as_file(), var, fix).unwrap();
+        targets.draw(10, 70);
+        targets.map.flush().unwrap();
+        let drawn = pixels(&mut file);
+        assert_eq!(drawn[70 * 100 + 10], 0x00);
+        assert_eq!(drawn[60 * 100 + 20], 0xFF);
+        // Clipped at the edges, nothing wrapped to the other side
+        assert_eq!(drawn[70 * 100 + 99], 0x80);
+        // The next one removes the previous
+        targets.draw(50, 10);
+        targets.map.flush().unwrap();
+        let drawn = pixels(&mut file);
+        assert_eq!(drawn[70 * 100 + 10], 0x80);
+        assert_eq!(drawn[10 * 100 + 50], 0x00);
+        drop(targets);
+        assert_eq!(pixels(&mut file), vec![0x80; 100 * 80]);
+    }
+
+    #[test]
+    fn keeps_what_the_app_drew_over_it() {
+        let (mut file, var, fix) = fake_framebuffer();
+        let mut targets = Targets::from_file(file.as_file(), var, fix).unwrap();
+        targets.draw(50, 40);
+        // The app redraws a line through the target
+        for x in 0..100 {
+            targets.map[40 * 100 + x] = 0x33;
+        }
+        targets.clear();
+        targets.map.flush().unwrap();
+        let restored = pixels(&mut file);
+        assert_eq!(restored[40 * 100 + 50], 0x33);
+        assert_eq!(restored[40 * 100 + 60], 0x33);
+        assert_eq!(restored[30 * 100 + 50], 0x80);
+        assert_eq!(restored[30 * 100 + 60], 0x80);
+    }
+}
@@ -0,0 +1,449 @@
+// Logging
+use log::{info, warn};
+
+// Device
+use crate::touch::{write_events, InputEvent, TouchInput, EV_KEY, EV_SYN, SYN_REPORT};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+
+// Other
+use crate::Args;
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+// From linux/uinput.h
+const UINPUT_PATH: &str = "/dev/uinput";
+const UI_DEV_CREATE: u32 = 0x5501;
+const UI_DEV_DESTROY: u32 = 0x5502;
+const UI_SET_EVBIT: u32 = 0x40045564;
+const UI_SET_KEYBIT: u32 = 0x40045565;
+const ABS_CNT: usize = 64;
+const BUS_VIRTUAL: u16 = 0x06;
+
+const KEY_BACKSPACE: u16 = 14;
+const KEY_TAB: u16 = 15;
+const KEY_ENTER: u16 = 28;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_SPACE: u16 = 57;
+
+// The on screen keyboard needs a moment to redraw after every tap
+const LAYOUT_TAP_GAP: Duration = Duration::from_millis(150);
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextBackend {
+    Auto,     // A virtual keyboard if uinput works, the layout otherwise
+    Keyboard, // A virtual keyboard through uinput
+    Layout,   // Tap the on screen keyboard, see --keyboard-layout
+}
+
+// Typed text from the host
+pub trait TextInput: Send {
+    // The layout needs the touchscreen, the keyboard doesn't
+    fn type_text(&mut self, text: &str, touch: &mut dyn TouchInput) -> io::Result<()>;
+}
+
+// Key code and shift for a character, US layout
+fn key_for(c: char) -> Option<(u16, bool)> {
+    const ROWS: [(&str, &str, u16); 4] = [
+        ("1234567890-=", "!@#$%^&*()_+", 2),
+        ("qwertyuiop[]", "QWERTYUIOP{}", 16),
+        ("asdfghjkl;'`", "ASDFGHJKL:\"~", 30),
+        ("zxcvbnm,./", "ZXCVBNM<>?", 44),
+    ];
+    match c {
+        ' ' => return Some((KEY_SPACE, false)),
+        '\n' => return Some((KEY_ENTER, false)),
+        '\t' => return Some((KEY_TAB, false)),
+        '\u{8}' => return Some((KEY_BACKSPACE, false)),
+        '\\' => return Some((43, false)),
+        '|' => return Some((43, true)),
+        _ => (),
+    }
+    ROWS.iter().find_map(|(plain, shifted, first)| {
+        if let Some(n) = plain.chars().position(|key| key == c) {
+            Some((first + n as u16, false))
+        } else {
+            let n = shifted.chars().position(|key| key == c)?;
+            Some((first + n as u16, true))
+        }
+    })
+}
+
+// struct uinput_user_dev, the old way of setting up a device. Newer kernels still take it
+#[repr(C)]
+struct UinputUserDev {
+    name: [u8; 80],
+    id: [u16; 4], // bustype, vendor, product, version
+    ff_effects_max: u32,
+    absmax: [i32; ABS_CNT],
+    absmin: [i32; ABS_CNT],
+    absfuzz: [i32; ABS_CNT],
+    absflat: [i32; ABS_CNT],
+}
+
+fn ioctl(file: &File, request: u32, value: libc::c_int) -> io::Result<()> {
+    // The request type is c_ulong on glibc and c_int on musl, hence the "as _"
+    if unsafe { libc::ioctl(file.as_raw_fd(), request as _, value) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// A keyboard that doesn't exist, apps see it like a usb one
+pub struct Keyboard {
+    out: File,
+}
+
+impl Keyboard {
+    pub fn create() -> io::Result<Self> {
+        let mut file = OpenOptions::new().write(true).open(UINPUT_PATH)?;
+        ioctl(&file, UI_SET_EVBIT, EV_KEY as _)?;
+        for code in 1..=KEY_SPACE {
+            ioctl(&file, UI_SET_KEYBIT, code as _)?;
+        }
+        let mut device = UinputUserDev {
+            name: [0; 80],
+            id: [BUS_VIRTUAL, 0, 0, 1],
+            ff_effects_max: 0,
+            absmax: [0; ABS_CNT],
+            absmin: [0; ABS_CNT],
+            absfuzz: [0; ABS_CNT],
+            absflat: [0; ABS_CNT],
+        };
+        let name = b"mirKobo keyboard";
+        device.name[..name.len()].copy_from_slice(name);
+        // Safety: plain old data, written as is
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &device as *const _ as *const u8,
+                std::mem::size_of::<UinputUserDev>(),
+            )
+        };
+        file.write_all(bytes)?;
+        ioctl(&file, UI_DEV_CREATE, 0)?;
+        // Apps need some time to notice a new device
+        thread::sleep(Duration::from_millis(500));
+        Ok(Keyboard { out: file })
+    }
+}
+
+// Key presses and releases for the text, shift around capitals and symbols
+fn key_events(text: &str) -> Vec<InputEvent> {
+    let mut events = Vec::new();
+    for c in text.chars() {
+        let Some((code, shift)) = key_for(c) else {
+            warn!("No key for {:?}, skipping it", c);
+            continue;
+        };
+        let mut key = |code: u16, value: i32| {
+            events.push(InputEvent::new(EV_KEY, code, value));
+            events.push(InputEvent::new(EV_SYN, SYN_REPORT, 0));
+        };
+        if shift {
+            key(KEY_LEFTSHIFT, 1);
+        }
+        key(code, 1);
+        key(code, 0);
+        if shift {
+            key(KEY_LEFTSHIFT, 0);
+        }
+    }
+    events
+}
+
+impl TextInput for Keyboard {
+    fn type_text(&mut self, text: &str, _touch: &mut dyn TouchInput) -> io::Result<()> {
+        write_events(&mut self.out, &key_events(text))
+    }
+}
+
+// Removes the device, otherwise it stays around until the file is closed anyway
+impl Drop for Keyboard {
+    fn drop(&mut self) {
+        let _ = ioctl(&self.out, UI_DEV_DESTROY, 0);
+    }
+}
+
+struct LayoutKey {
+    name: String,
+    pos: (u16, u16),
+    switch: Option<usize>, // Page this key goes to
+}
+
+struct Page {
+    name: String,
+    keys: Vec<LayoutKey>,
+}
+
+impl Page {
+    fn key(&self, name: &str) -> Option<&LayoutKey> {
+        self.keys.iter().find(|key| key.name == name)
+    }
+}
+
+// Where the keys of the on screen keyboard are, from a file like:
+//
+//  # The first page is the one that shows up first
+//  [letters]
+//  q 40 900
+//  shift 30 1100
+//  123 60 1200 numbers  <- switches to the numbers page
+//  [numbers]
+//  1 40 900
+//  hash 80 900
+//  abc 60 1200 letters
+//
+// Keys are characters, or space, enter, backspace, hash and shift. Capitals
+// without a key of their own are shift and the small letter
+//
+// x and y are raw touchscreen coordinates, they go to the touchscreen as they are. The
+// host's flips, swap and rotation don't apply to them, so they are only right for the
+// rotation the layout was written for, and a turned reader needs a layout of its own.
+// evtest on the touchscreen shows them when tapping the keys
+pub struct KeyboardLayout {
+    pages: Vec<Page>,
+    current: usize,
+}
+
+fn key_name(c: char) -> String {
+    match c {
+        ' ' => String::from("space"),
+        '\n' => String::from("enter"),
+        '\u{8}' => String::from("backspace"),
+        '#' => String::from("hash"),
+        _ => c.to_string(),
+    }
+}
+
+impl KeyboardLayout {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut pages: Vec<Page> = Vec::new();
+        let mut switches = Vec::new(); // page, key, target name, line
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line
+                .strip_prefix('[')
+                .and_then(|rest| rest.strip_suffix(']'))
+            {
+                pages.push(Page {
+                    name: name.to_string(),
+                    keys: Vec::new(),
+                });
+                continue;
+            }
+            let error = |what: &str| format!("line {}: {}: {:?}", n + 1, what, line);
+            let page_index = pages
+                .len()
+                .checked_sub(1)
+                .ok_or_else(|| error("key before any [page]"))?;
+            let page = &mut pages[page_index];
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (name, x, y) = match words[..] {
+                [name, x, y] | [name, x, y, _] => (name, x, y),
+                _ => return Err(error("expected key x y [page]")),
+            };
+            let coordinate =
+                |value: &str| value.parse::<u16>().map_err(|_| error("bad coordinate"));
+            if let Some(target) = words.get(3) {
+                switches.push((page_index, page.keys.len(), target.to_string(), n + 1));
+            }
+            page.keys.push(LayoutKey {
+                name: name.to_string(),
+                pos: (coordinate(x)?, coordinate(y)?),
+                switch: None,
+            });
+        }
+        if pages.is_empty() {
+            return Err(String::from("no pages"));
+        }
+        for (page, key, target, line) in switches {
+            let Some(target) = pages.iter().position(|page| page.name == target) else {
+                return Err(format!("line {}: no page {:?}", line, target));
+            };
+            pages[page].keys[key].switch = Some(target);
+        }
+        Ok(KeyboardLayout { pages, current: 0 })
+    }
+
+    // Shortest way from the current page to one where found() gives taps
+    fn route(
+        &self,
+        found: impl Fn(&Page) -> Option<Vec<(u16, u16)>>,
+    ) -> Option<(Vec<(u16, u16)>, usize)> {
+        let mut visited = vec![false; self.pages.len()];
+        let mut queue = VecDeque::from([(self.current, Vec::new())]);
+        visited[self.current] = true;
+        while let Some((page, mut taps)) = queue.pop_front() {
+            if let Some(found) = found(&self.pages[page]) {
+                taps.extend(found);
+                return Some((taps, page));
+            }
+            for key in &self.pages[page].keys {
+                if let Some(next) = key.switch.filter(|next| !visited[*next]) {
+                    visited[next] = true;
+                    let mut taps = taps.clone();
+                    taps.push(key.pos);
+                    queue.push_back((next, taps));
+                }
+            }
+        }
+        None
+    }
+
+    // Where to tap for this text, the layout follows the page switches
+    pub fn taps(&mut self, text: &str) -> Vec<(u16, u16)> {
+        let mut taps = Vec::new();
+        for c in text.chars() {
+            let name = key_name(c);
+            let lower = key_name(c.to_ascii_lowercase());
+            let found = self
+                .route(|page| Some(vec![page.key(&name)?.pos]))
+                .or_else(|| {
+                    self.route(|page| Some(vec![page.key("shift")?.pos, page.key(&lower)?.pos]))
+                        .filter(|_| c.is_uppercase())
+                });
+            match found {
+                Some((found, page)) => {
+                    taps.extend(found);
+                    self.current = page;
+                }
+                None => warn!("No key for {:?} in the layout, skipping it", c),
+            }
+        }
+        taps
+    }
+}
+
+impl TextInput for KeyboardLayout {
+    fn type_text(&mut self, text: &str, touch: &mut dyn TouchInput) -> io::Result<()> {
+        for (x, y) in self.taps(text) {
+            touch.down(x, y)?;
+            touch.up(x, y, Duration::ZERO)?;
+            thread::sleep(LAYOUT_TAP_GAP);
+        }
+        Ok(())
+    }
+}
+
+fn layout(path: &Option<PathBuf>) -> io::Result<KeyboardLayout> {
+    let Some(path) = path else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no --keyboard-layout given",
+        ));
+    };
+    let text = std::fs::read_to_string(path)?;
+    KeyboardLayout::parse(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+// None when there is no way to type at all, the host won't offer it then
+pub fn open(args: &Args) -> io::Result<Option<Box<dyn TextInput>>> {
+    match args.text_backend {
+        TextBackend::Auto => match Keyboard::create() {
+            Ok(keyboard) => {
+                info!("Typing through a virtual keyboard");
+                Ok(Some(Box::new(keyboard)))
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to create a virtual keyboard: {}, trying the layout",
+                    err
+                );
+                match layout(&args.keyboard_layout) {
+                    Ok(layout) => Ok(Some(Box::new(layout))),
+                    Err(err) => {
+                        warn!("No keyboard layout either: {}, text entry is off", err);
+                        Ok(None)
+                    }
+                }
+            }
+        },
+        TextBackend::Keyboard => Ok(Some(Box::new(Keyboard::create()?))),
+        TextBackend::Layout => Ok(Some(Box::new(layout(&args.keyboard_layout)?))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUT: &str = "# Two pages
+[letters]
+h 10 10
+i 20 10
+shift 0 30
+space 50 40
+123 0 40 numbers
+[numbers]
+1 10 10
+hash 20 10
+abc 0 40 letters
+";
+
+    #[test]
+    fn us_keys() {
+        assert_eq!(key_for('q'), Some((16, false)));
+        assert_eq!(key_for('Q'), Some((16, true)));
+        assert_eq!(key_for('0'), Some((11, false)));
+        assert_eq!(key_for('?'), Some((53, true)));
+        assert_eq!(key_for('\n'), Some((KEY_ENTER, false)));
+        assert_eq!(key_for('ł'), None);
+    }
+
+    #[test]
+    fn shift_wraps_the_key() {
+        let events: Vec<(u16, i32)> = key_events("Hi")
+            .into_iter()
+            .filter(|event| event.kind == EV_KEY)
+            .map(|event| (event.code, event.value))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (KEY_LEFTSHIFT, 1),
+                (35, 1),
+                (35, 0),
+                (KEY_LEFTSHIFT, 0),
+                (23, 1),
+                (23, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn layout_switches_pages() {
+        let mut layout = KeyboardLayout::parse(LAYOUT).unwrap();
+        assert_eq!(
+            layout.taps("Hi 1#"),
+            [
+                (0, 30),
+                (10, 10),
+                (20, 10),
+                (50, 40),
+                (0, 40),
+                (10, 10),
+                (20, 10)
+            ]
+        );
+        // Still on the numbers page
+        assert_eq!(layout.taps("h"), [(0, 40), (10, 10)]);
+        // Unknown keys are skipped
+        assert_eq!(layout.taps("x"), []);
+    }
+
+    #[test]
+    fn bad_layouts() {
+        assert!(KeyboardLayout::parse("").is_err());
+        assert!(KeyboardLayout::parse("a 1 2").is_err());
+        assert!(KeyboardLayout::parse("[a]\nb 1").is_err());
+        assert!(KeyboardLayout::parse("[a]\nb 1 x").is_err());
+        assert!(KeyboardLayout::parse("[a]\nb 1 2 c").is_err());
+    }
+}
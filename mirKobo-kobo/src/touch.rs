@@ -0,0 +1,750 @@
+// Logging
+use log::{debug, info, warn};
+
+// Device
+use crate::device::click;
+use crate::input_devices;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+// Other
+use crate::Args;
+use clap::ValueEnum;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Network
+use mir_kobo_proto::{InputKind, PinchPoint, TouchPoint, TouchProtocol};
+
+// From linux/input-event-codes.h
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_ABS: u16 = 0x03;
+pub const SYN_REPORT: u16 = 0;
+pub const SYN_MT_REPORT: u16 = 2;
+pub const BTN_TOUCH: u16 = 0x14a;
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const ABS_MT_SLOT: u16 = 0x2f;
+pub const ABS_MT_POSITION_X: u16 = 0x35;
+pub const ABS_MT_POSITION_Y: u16 = 0x36;
+pub const ABS_MT_TRACKING_ID: u16 = 0x39;
+
+const PROC_PATH: &str = "/proc";
+// What touch_emulate always used
+const DEFAULT_TOUCH_DEVICE: &str = "/dev/input/event1";
+
+// Shortest touch, some apps ignore taps that are too short
+const TAP_HOLD: Duration = Duration::from_millis(40);
+
+// Gaps in a stroke longer than this get points in between, so it stays continuous
+const STROKE_STEP_MS: u32 = 10;
+
+const EVENT_SIZE: usize = std::mem::size_of::<libc::input_event>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl InputEvent {
+    pub fn new(kind: u16, code: u16, value: i32) -> Self {
+        InputEvent { kind, code, value }
+    }
+
+    // struct input_event, the kernel sets the time itself when we write to it
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        let raw = libc::input_event {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_: self.kind,
+            code: self.code,
+            value: self.value,
+        };
+        // Safety: input_event is plain old data without padding
+        let raw = unsafe { std::slice::from_raw_parts(&raw as *const _ as *const u8, EVENT_SIZE) };
+        bytes.extend_from_slice(raw);
+    }
+}
+
+// One write, so the whole report arrives together
+pub fn write_events(out: &mut impl Write, events: &[InputEvent]) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(events.len() * EVENT_SIZE);
+    for event in events {
+        event.write_to(&mut bytes);
+    }
+    out.write_all(&bytes)?;
+    out.flush()
+}
+
+// Events read from a device, a partial one at the end is left out
+pub fn read_events(bytes: &[u8]) -> Vec<InputEvent> {
+    bytes
+        .chunks_exact(EVENT_SIZE)
+        .map(|chunk| {
+            // Safety: the chunk is exactly one input_event
+            let raw: libc::input_event =
+                unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const _) };
+            InputEvent::new(raw.type_, raw.code, raw.value)
+        })
+        .collect()
+}
+
+// Multitouch protocols from Documentation/input/multi-touch-protocol.rst
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MtProtocol {
+    A,      // Anonymous contacts, each one ends with SYN_MT_REPORT. Older Kobos
+    B,      // Slots with tracking ids. Most newer Kobos
+    Single, // No multitouch at all, only ABS_X and ABS_Y
+}
+
+impl From<TouchProtocol> for MtProtocol {
+    fn from(protocol: TouchProtocol) -> Self {
+        match protocol {
+            TouchProtocol::A => MtProtocol::A,
+            TouchProtocol::B => MtProtocol::B,
+            TouchProtocol::Single => MtProtocol::Single,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TouchBackend {
+    Auto,         // Native if the touchscreen can be opened, touch_emulate otherwise
+    Native,       // Write the events to the touchscreen ourselves
+    TouchEmulate, // Launch touch_emulate for every tap
+}
+
+// Where the input goes
+pub trait TouchInput: Send {
+    // What this backend can do, for Hello
+    fn inputs(&self) -> Vec<InputKind> {
+        vec![InputKind::Click]
+    }
+    // Finger down, it stays there until up()
+    fn down(&mut self, x: u16, y: u16) -> io::Result<()>;
+    // Finger up at x / y, but not before it was down for held
+    fn up(&mut self, x: u16, y: u16, held: Duration) -> io::Result<()>;
+    // Up for a down() that won't get its up(), the host went away while holding
+    fn lift(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    // The touchscreen event device, to read taps from while calibrating
+    fn device_path(&self) -> Option<&str> {
+        None
+    }
+    // One finger down at the first point, following the rest in time, up at the last
+    fn stroke(&mut self, _points: &[TouchPoint]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this touch backend can only tap",
+        ))
+    }
+    // Like stroke(), with two fingers
+    fn pinch(&mut self, _points: &[PinchPoint]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this touch backend can't pinch",
+        ))
+    }
+}
+
+// Adds points on straight lines between the ones that are more than step_ms apart
+pub fn stroke_steps(points: &[TouchPoint], step_ms: u32) -> Vec<TouchPoint> {
+    let mut steps = Vec::with_capacity(points.len());
+    for pair in points.windows(2) {
+        let (from, to) = (pair[0], pair[1]);
+        steps.push(from);
+        let gap = to.time_ms.saturating_sub(from.time_ms);
+        for n in 1..gap.div_ceil(step_ms.max(1)) {
+            let t = (n * step_ms) as f32 / gap as f32;
+            let between = |a: u16, b: u16| (a as f32 + (b as f32 - a as f32) * t).round() as u16;
+            steps.push(TouchPoint {
+                x: between(from.x, to.x),
+                y: between(from.y, to.y),
+                time_ms: from.time_ms + n * step_ms,
+            });
+        }
+    }
+    steps.extend(points.last());
+    steps
+}
+
+// Writes events straight to a touchscreen event device
+pub struct Touchscreen<W: Write> {
+    out: W,
+    path: Option<String>, // Of the device, if it was opened from one
+    protocol: MtProtocol,
+    down: usize,      // Fingers on the screen after the last touch()
+    tracking_id: i32, // Next tracking id, for protocol B
+    pressed: Option<(Instant, (u16, u16))>, // When and where down() put the finger
+}
+
+impl Touchscreen<File> {
+    pub fn open(path: &str, protocol: MtProtocol) -> io::Result<Self> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        Ok(Touchscreen {
+            path: Some(path.to_string()),
+            ..Touchscreen::new(file, protocol)
+        })
+    }
+}
+
+impl<W: Write> Touchscreen<W> {
+    pub fn new(out: W, protocol: MtProtocol) -> Self {
+        Touchscreen {
+            out,
+            path: None,
+            protocol,
+            down: 0,
+            tracking_id: 0,
+            pressed: None,
+        }
+    }
+
+    // Everything for one report, from the current state to those fingers
+    pub fn events(&mut self, fingers: &[(u16, u16)]) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+        match self.protocol {
+            MtProtocol::Single => {
+                if let Some((x, y)) = fingers.first() {
+                    events.push(InputEvent::new(EV_ABS, ABS_X, *x as i32));
+                    events.push(InputEvent::new(EV_ABS, ABS_Y, *y as i32));
+                }
+            }
+            MtProtocol::A => {
+                for (x, y) in fingers {
+                    events.push(InputEvent::new(EV_ABS, ABS_MT_POSITION_X, *x as i32));
+                    events.push(InputEvent::new(EV_ABS, ABS_MT_POSITION_Y, *y as i32));
+                    events.push(InputEvent::new(EV_SYN, SYN_MT_REPORT, 0));
+                }
+                if fingers.is_empty() {
+                    events.push(InputEvent::new(EV_SYN, SYN_MT_REPORT, 0));
+                }
+            }
+            MtProtocol::B => {
+                for (slot, (x, y)) in fingers.iter().enumerate() {
+                    events.push(InputEvent::new(EV_ABS, ABS_MT_SLOT, slot as i32));
+                    if slot >= self.down {
+                        events.push(InputEvent::new(
+                            EV_ABS,
+                            ABS_MT_TRACKING_ID,
+                            self.tracking_id,
+                        ));
+                        self.tracking_id = (self.tracking_id + 1) & 0xffff;
+                    }
+                    events.push(InputEvent::new(EV_ABS, ABS_MT_POSITION_X, *x as i32));
+                    events.push(InputEvent::new(EV_ABS, ABS_MT_POSITION_Y, *y as i32));
+                }
+                for slot in fingers.len()..self.down {
+                    events.push(InputEvent::new(EV_ABS, ABS_MT_SLOT, slot as i32));
+                    events.push(InputEvent::new(EV_ABS, ABS_MT_TRACKING_ID, -1));
+                }
+            }
+        }
+        if self.down == 0 && !fingers.is_empty() {
+            events.push(InputEvent::new(EV_KEY, BTN_TOUCH, 1));
+        } else if self.down != 0 && fingers.is_empty() {
+            events.push(InputEvent::new(EV_KEY, BTN_TOUCH, 0));
+        }
+        events.push(InputEvent::new(EV_SYN, SYN_REPORT, 0));
+        self.down = fingers.len();
+        events
+    }
+
+    // Fingers at those positions at those ms from now, lifted after the last step
+    fn replay<const N: usize>(&mut self, steps: &[(u32, [(u16, u16); N])]) -> io::Result<()> {
+        let started = Instant::now();
+        for (time_ms, fingers) in steps {
+            let at = Duration::from_millis(*time_ms as u64);
+            if let Some(wait) = at.checked_sub(started.elapsed()) {
+                thread::sleep(wait);
+            }
+            self.touch(fingers)?;
+        }
+        self.touch(&[])
+    }
+
+    // Moves the fingers to those positions, fingers that are missing get lifted
+    pub fn touch(&mut self, fingers: &[(u16, u16)]) -> io::Result<()> {
+        let events = self.events(fingers);
+        write_events(&mut self.out, &events)
+    }
+}
+
+impl<W: Write + Send> TouchInput for Touchscreen<W> {
+    fn inputs(&self) -> Vec<InputKind> {
+        match self.protocol {
+            MtProtocol::Single => vec![InputKind::Click, InputKind::Gesture],
+            _ => vec![InputKind::Click, InputKind::Gesture, InputKind::Pinch],
+        }
+    }
+
+    fn down(&mut self, x: u16, y: u16) -> io::Result<()> {
+        debug!("Touch down at x:{} y:{}", x, y);
+        self.pressed = Some((Instant::now(), (x, y)));
+        self.touch(&[(x, y)])
+    }
+
+    fn up(&mut self, x: u16, y: u16, held: Duration) -> io::Result<()> {
+        debug!("Touch up at x:{} y:{} after {:?}", x, y, held);
+        if let Some((at, position)) = self.pressed.take() {
+            if let Some(wait) = held.max(TAP_HOLD).checked_sub(at.elapsed()) {
+                thread::sleep(wait);
+            }
+            if position != (x, y) {
+                self.touch(&[(x, y)])?;
+            }
+        }
+        self.touch(&[])
+    }
+
+    fn lift(&mut self) -> io::Result<()> {
+        self.pressed = None;
+        if self.down == 0 {
+            return Ok(());
+        }
+        debug!("Lifting {} fingers left on the screen", self.down);
+        self.touch(&[])
+    }
+
+    fn device_path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    fn stroke(&mut self, points: &[TouchPoint]) -> io::Result<()> {
+        debug!("Stroke with {} points", points.len());
+        let steps: Vec<(u32, [(u16, u16); 1])> = stroke_steps(points, STROKE_STEP_MS)
+            .iter()
+            .map(|point| (point.time_ms, [(point.x, point.y)]))
+            .collect();
+        self.replay(&steps)
+    }
+
+    fn pinch(&mut self, points: &[PinchPoint]) -> io::Result<()> {
+        debug!("Pinch with {} points", points.len());
+        let finger = |pick: fn(&PinchPoint) -> (u16, u16)| {
+            let points: Vec<TouchPoint> = points
+                .iter()
+                .map(|point| {
+                    let (x, y) = pick(point);
+                    TouchPoint {
+                        x,
+                        y,
+                        time_ms: point.time_ms,
+                    }
+                })
+                .collect();
+            stroke_steps(&points, STROKE_STEP_MS)
+        };
+        // Both fingers have the same times, so their steps line up
+        let steps: Vec<(u32, [(u16, u16); 2])> = finger(|point| point.first)
+            .iter()
+            .zip(&finger(|point| point.second))
+            .map(|(a, b)| (a.time_ms, [(a.x, a.y), (b.x, b.y)]))
+            .collect();
+        self.replay(&steps)
+    }
+}
+
+// The old way, a sister project binary
+pub struct TouchEmulate {
+    bin_path: String,
+    device_path: String,
+}
+
+// It can only tap, so the tap happens on up and holds are lost
+impl TouchInput for TouchEmulate {
+    fn down(&mut self, _x: u16, _y: u16) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn up(&mut self, x: u16, y: u16, _held: Duration) -> io::Result<()> {
+        click(x, y, &self.bin_path, &self.device_path);
+        Ok(())
+    }
+
+    fn device_path(&self) -> Option<&str> {
+        Some(&self.device_path)
+    }
+}
+
+// The touchscreen and its protocol, as given or detected. None if nothing looks like one
+fn touchscreen(
+    touch_device: Option<String>,
+    touch_protocol: Option<MtProtocol>,
+) -> Option<(String, MtProtocol)> {
+    if let (Some(path), Some(protocol)) = (&touch_device, touch_protocol) {
+        return Some((path.clone(), protocol));
+    }
+    let devices = match input_devices::list(Path::new(PROC_PATH)) {
+        Ok(devices) => devices,
+        Err(err) => {
+            warn!("Failed to list input devices: {}", err);
+            Vec::new()
+        }
+    };
+    let detected = match &touch_device {
+        // Still look it up, for the protocol
+        Some(path) => devices
+            .iter()
+            .find(|device| device.device_path().as_ref() == Some(path)),
+        None => input_devices::find_touchscreen(&devices),
+    };
+    if let Some(device) = detected {
+        info!(
+            "Touchscreen is {:?} at {:?}, protocol {:?}",
+            device.name,
+            device.device_path(),
+            device.mt_protocol()
+        );
+    }
+    let path = touch_device.or_else(|| detected.and_then(|device| device.device_path()))?;
+    let protocol = touch_protocol
+        .or_else(|| detected.map(|device| device.mt_protocol()))
+        .unwrap_or(MtProtocol::B);
+    Some((path, protocol))
+}
+
+pub fn open(args: &Args) -> io::Result<Box<dyn TouchInput>> {
+    open_device(args, args.touch_device.clone(), args.touch_protocol)
+}
+
+// Like open, but the touchscreen and protocol can come from the host's profile
+pub fn open_device(
+    args: &Args,
+    touch_device: Option<String>,
+    touch_protocol: Option<MtProtocol>,
+) -> io::Result<Box<dyn TouchInput>> {
+    let found = touchscreen(touch_device, touch_protocol);
+    let found_any = found.is_some();
+    let (device_path, protocol) =
+        found.unwrap_or_else(|| (DEFAULT_TOUCH_DEVICE.to_string(), MtProtocol::B));
+    let touch_emulate = || {
+        Box::new(TouchEmulate {
+            bin_path: args.touch_emulate_path.clone(),
+            device_path: device_path.clone(),
+        })
+    };
+    match args.touch_backend {
+        // Writing to whatever event1 is could go to an accelerometer, and nothing would happen
+        TouchBackend::Auto if !found_any => {
+            warn!(
+                "No touchscreen found, falling back to touch_emulate on {}",
+                device_path
+            );
+            Ok(touch_emulate())
+        }
+        TouchBackend::Auto => match Touchscreen::open(&device_path, protocol) {
+            Ok(touchscreen) => {
+                info!(
+                    "Writing touch events to {} with protocol {:?}",
+                    device_path, protocol
+                );
+                Ok(Box::new(touchscreen))
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to open {}: {}, falling back to touch_emulate",
+                    device_path, err
+                );
+                Ok(touch_emulate())
+            }
+        },
+        TouchBackend::Native => {
+            if !found_any {
+                warn!("No touchscreen found, trying {}", device_path);
+            }
+            Ok(Box::new(Touchscreen::open(&device_path, protocol)?))
+        }
+        TouchBackend::TouchEmulate => Ok(touch_emulate()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek};
+
+    // Reads back what was written to the fake device, without the times
+    fn written(file: &mut File) -> Vec<(u16, u16, i32)> {
+        let mut bytes = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes.len() % EVENT_SIZE, 0);
+        bytes
+            .chunks_exact(EVENT_SIZE)
+            .map(|chunk| {
+                // Safety: the chunk is exactly one input_event
+                let event: libc::input_event =
+                    unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const _) };
+                (event.type_, event.code, event.value)
+            })
+            .collect()
+    }
+
+    fn tap(protocol: MtProtocol) -> Vec<(u16, u16, i32)> {
+        let mut device = tempfile::tempfile().unwrap();
+        let mut touchscreen = Touchscreen::new(device.try_clone().unwrap(), protocol);
+        touchscreen.down(100, 200).unwrap();
+        touchscreen.up(100, 200, Duration::ZERO).unwrap();
+        written(&mut device)
+    }
+
+    #[test]
+    fn protocol_a_tap() {
+        assert_eq!(
+            tap(MtProtocol::A),
+            [
+                (EV_ABS, ABS_MT_POSITION_X, 100),
+                (EV_ABS, ABS_MT_POSITION_Y, 200),
+                (EV_SYN, SYN_MT_REPORT, 0),
+                (EV_KEY, BTN_TOUCH, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_SYN, SYN_MT_REPORT, 0),
+                (EV_KEY, BTN_TOUCH, 0),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn protocol_b_tap() {
+        assert_eq!(
+            tap(MtProtocol::B),
+            [
+                (EV_ABS, ABS_MT_SLOT, 0),
+                (EV_ABS, ABS_MT_TRACKING_ID, 0),
+                (EV_ABS, ABS_MT_POSITION_X, 100),
+                (EV_ABS, ABS_MT_POSITION_Y, 200),
+                (EV_KEY, BTN_TOUCH, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_ABS, ABS_MT_SLOT, 0),
+                (EV_ABS, ABS_MT_TRACKING_ID, -1),
+                (EV_KEY, BTN_TOUCH, 0),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn single_touch_tap() {
+        assert_eq!(
+            tap(MtProtocol::Single),
+            [
+                (EV_ABS, ABS_X, 100),
+                (EV_ABS, ABS_Y, 200),
+                (EV_KEY, BTN_TOUCH, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_KEY, BTN_TOUCH, 0),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn held_finger_is_lifted() {
+        let mut device = tempfile::tempfile().unwrap();
+        let mut touchscreen = Touchscreen::new(device.try_clone().unwrap(), MtProtocol::B);
+        touchscreen.lift().unwrap();
+        assert!(written(&mut device).is_empty());
+        touchscreen.down(100, 200).unwrap();
+        touchscreen.lift().unwrap();
+        touchscreen.lift().unwrap();
+        let events = written(&mut device);
+        assert_eq!(
+            events[6..],
+            [
+                (EV_ABS, ABS_MT_SLOT, 0),
+                (EV_ABS, ABS_MT_TRACKING_ID, -1),
+                (EV_KEY, BTN_TOUCH, 0),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn protocol_b_tracks_fingers() {
+        let mut device = tempfile::tempfile().unwrap();
+        let mut touchscreen = Touchscreen::new(device.try_clone().unwrap(), MtProtocol::B);
+        touchscreen.touch(&[(1, 1)]).unwrap();
+        touchscreen.touch(&[(2, 2), (9, 9)]).unwrap();
+        touchscreen.touch(&[(3, 3)]).unwrap();
+        touchscreen.touch(&[]).unwrap();
+        touchscreen.touch(&[(4, 4)]).unwrap();
+        let events = written(&mut device);
+        let reports: Vec<&[(u16, u16, i32)]> = events
+            .split_inclusive(|event| *event == (EV_SYN, SYN_REPORT, 0))
+            .collect();
+        assert_eq!(reports.len(), 5);
+        // Second finger joins with a new id, the first one only moves
+        assert_eq!(
+            reports[1],
+            [
+                (EV_ABS, ABS_MT_SLOT, 0),
+                (EV_ABS, ABS_MT_POSITION_X, 2),
+                (EV_ABS, ABS_MT_POSITION_Y, 2),
+                (EV_ABS, ABS_MT_SLOT, 1),
+                (EV_ABS, ABS_MT_TRACKING_ID, 1),
+                (EV_ABS, ABS_MT_POSITION_X, 9),
+                (EV_ABS, ABS_MT_POSITION_Y, 9),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+        // Second finger lifted, BTN_TOUCH stays down
+        assert_eq!(
+            reports[2],
+            [
+                (EV_ABS, ABS_MT_SLOT, 0),
+                (EV_ABS, ABS_MT_POSITION_X, 3),
+                (EV_ABS, ABS_MT_POSITION_Y, 3),
+                (EV_ABS, ABS_MT_SLOT, 1),
+                (EV_ABS, ABS_MT_TRACKING_ID, -1),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+        // A new touch never reuses an id
+        assert!(reports[4].contains(&(EV_ABS, ABS_MT_TRACKING_ID, 2)));
+    }
+
+    #[test]
+    fn long_press_is_held_and_lifted_where_it_ended() {
+        let mut device = tempfile::tempfile().unwrap();
+        let mut touchscreen = Touchscreen::new(device.try_clone().unwrap(), MtProtocol::A);
+        let started = Instant::now();
+        touchscreen.down(100, 200).unwrap();
+        touchscreen
+            .up(101, 200, Duration::from_millis(120))
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(120));
+        assert_eq!(
+            written(&mut device),
+            [
+                (EV_ABS, ABS_MT_POSITION_X, 100),
+                (EV_ABS, ABS_MT_POSITION_Y, 200),
+                (EV_SYN, SYN_MT_REPORT, 0),
+                (EV_KEY, BTN_TOUCH, 1),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_ABS, ABS_MT_POSITION_X, 101),
+                (EV_ABS, ABS_MT_POSITION_Y, 200),
+                (EV_SYN, SYN_MT_REPORT, 0),
+                (EV_SYN, SYN_REPORT, 0),
+                (EV_SYN, SYN_MT_REPORT, 0),
+                (EV_KEY, BTN_TOUCH, 0),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+    }
+
+    fn point(x: u16, y: u16, time_ms: u32) -> TouchPoint {
+        TouchPoint { x, y, time_ms }
+    }
+
+    #[test]
+    fn stroke_steps_fill_gaps() {
+        let steps = stroke_steps(
+            &[point(0, 0, 0), point(100, 50, 40), point(100, 50, 45)],
+            10,
+        );
+        assert_eq!(
+            steps,
+            [
+                point(0, 0, 0),
+                point(25, 13, 10),
+                point(50, 25, 20),
+                point(75, 38, 30),
+                point(100, 50, 40),
+                point(100, 50, 45),
+            ]
+        );
+        assert_eq!(stroke_steps(&[point(1, 2, 0)], 10), [point(1, 2, 0)]);
+        assert!(stroke_steps(&[], 10).is_empty());
+    }
+
+    #[test]
+    fn stroke_is_one_continuous_touch() {
+        let mut device = tempfile::tempfile().unwrap();
+        let mut touchscreen = Touchscreen::new(device.try_clone().unwrap(), MtProtocol::B);
+        touchscreen
+            .stroke(&[point(10, 500, 0), point(30, 500, 20)])
+            .unwrap();
+        let events = written(&mut device);
+        let reports: Vec<&[(u16, u16, i32)]> = events
+            .split_inclusive(|event| *event == (EV_SYN, SYN_REPORT, 0))
+            .collect();
+        // Down, one step in between, the last point, up
+        assert_eq!(reports.len(), 4);
+        let xs: Vec<i32> = events
+            .iter()
+            .filter(|event| event.1 == ABS_MT_POSITION_X)
+            .map(|event| event.2)
+            .collect();
+        assert_eq!(xs, [10, 20, 30]);
+        let ids: Vec<i32> = events
+            .iter()
+            .filter(|event| event.1 == ABS_MT_TRACKING_ID)
+            .map(|event| event.2)
+            .collect();
+        assert_eq!(ids, [0, -1]);
+    }
+
+    #[test]
+    fn pinch_moves_two_fingers_together() {
+        let mut device = tempfile::tempfile().unwrap();
+        let mut touchscreen = Touchscreen::new(device.try_clone().unwrap(), MtProtocol::B);
+        touchscreen
+            .pinch(&[
+                PinchPoint {
+                    first: (100, 300),
+                    second: (200, 300),
+                    time_ms: 0,
+                },
+                PinchPoint {
+                    first: (80, 300),
+                    second: (220, 300),
+                    time_ms: 20,
+                },
+            ])
+            .unwrap();
+        let events = written(&mut device);
+        let reports: Vec<&[(u16, u16, i32)]> = events
+            .split_inclusive(|event| *event == (EV_SYN, SYN_REPORT, 0))
+            .collect();
+        assert_eq!(reports.len(), 4);
+        let xs: Vec<i32> = events
+            .iter()
+            .filter(|event| event.1 == ABS_MT_POSITION_X)
+            .map(|event| event.2)
+            .collect();
+        assert_eq!(xs, [100, 200, 90, 210, 80, 220]);
+        // Both go down in the first report and up in the last
+        assert_eq!(
+            reports[3],
+            [
+                (EV_ABS, ABS_MT_SLOT, 0),
+                (EV_ABS, ABS_MT_TRACKING_ID, -1),
+                (EV_ABS, ABS_MT_SLOT, 1),
+                (EV_ABS, ABS_MT_TRACKING_ID, -1),
+                (EV_KEY, BTN_TOUCH, 0),
+                (EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn protocol_a_reports_every_finger() {
+        let mut touchscreen = Touchscreen::new(Vec::new(), MtProtocol::A);
+        let events = touchscreen.events(&[(1, 2), (3, 4)]);
+        let contacts = events
+            .iter()
+            .filter(|event| event.code == SYN_MT_REPORT && event.kind == EV_SYN)
+            .count();
+        assert_eq!(contacts, 2);
+        assert_eq!(touchscreen.out.len(), 0); // events() doesn't write
+    }
+}
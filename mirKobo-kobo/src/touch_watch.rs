@@ -0,0 +1,251 @@
+// Logging
+use log::{debug, info, warn};
+
+// Device
+use crate::touch::{
+    read_events, InputEvent, ABS_MT_POSITION_X, ABS_MT_POSITION_Y, ABS_MT_SLOT, ABS_MT_TRACKING_ID,
+    ABS_X, ABS_Y, BTN_TOUCH, EV_ABS, EV_KEY, EV_SYN, SYN_MT_REPORT, SYN_REPORT,
+};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+
+// Other
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+// How often the reading thread looks if it should stop
+const STOP_CHECK_MS: i32 = 100;
+
+// Follows the first finger through the reports of a touchscreen, whichever protocol it
+// speaks. Only the raw position, nothing the host does to its clicks
+#[derive(Default)]
+pub struct TapTracker {
+    slot: i32, // Protocol B, fingers other than slot 0 are ignored
+    x: Option<i32>,
+    y: Option<i32>,
+    down: bool,
+    lifted: bool,     // In the report being read
+    protocol_a: bool, // It sent SYN_MT_REPORT, an empty report is a lift
+    contact: bool,    // Protocol A, the contact being read has a position
+    contacts: usize,  // Protocol A, contacts in the report being read
+}
+
+impl TapTracker {
+    // Where the finger left the screen, at the end of the report it did
+    pub fn event(&mut self, event: InputEvent) -> Option<(u32, u32)> {
+        match (event.kind, event.code) {
+            (EV_ABS, ABS_MT_SLOT) => self.slot = event.value,
+            (EV_ABS, ABS_MT_TRACKING_ID) if self.slot == 0 => {
+                if event.value < 0 {
+                    self.lifted = true;
+                } else {
+                    self.down = true;
+                }
+            }
+            (EV_ABS, ABS_MT_POSITION_X) if self.slot == 0 => self.position(Some(event.value), None),
+            (EV_ABS, ABS_MT_POSITION_Y) if self.slot == 0 => self.position(None, Some(event.value)),
+            (EV_ABS, ABS_X) => self.position(Some(event.value), None),
+            (EV_ABS, ABS_Y) => self.position(None, Some(event.value)),
+            (EV_KEY, BTN_TOUCH) => {
+                if event.value == 0 {
+                    self.lifted = true;
+                } else {
+                    self.down = true;
+                }
+            }
+            (EV_SYN, SYN_MT_REPORT) => {
+                self.protocol_a = true;
+                if self.contact {
+                    self.contacts += 1;
+                }
+                self.contact = false;
+            }
+            (EV_SYN, SYN_REPORT) => return self.report(),
+            _ => (),
+        }
+        None
+    }
+
+    fn position(&mut self, x: Option<i32>, y: Option<i32>) {
+        self.x = x.or(self.x);
+        self.y = y.or(self.y);
+        self.down = true;
+        self.contact = true;
+    }
+
+    fn report(&mut self) -> Option<(u32, u32)> {
+        let empty = self.protocol_a && self.contacts == 0 && !self.contact;
+        let lifted = self.down && (self.lifted || empty);
+        self.lifted = false;
+        self.contact = false;
+        self.contacts = 0;
+        if !lifted {
+            return None;
+        }
+        self.down = false;
+        let (x, y) = (self.x?, self.y?);
+        Some((x.max(0) as u32, y.max(0) as u32))
+    }
+}
+
+// Reads taps from a touchscreen in a thread until dropped. Reading doesn't take the
+// touchscreen away from the apps, unless one of them grabbed it, then nothing comes
+pub struct TouchWatch {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TouchWatch {
+    pub fn open(path: &str, tapped: impl FnMut((u32, u32)) + Send + 'static) -> io::Result<Self> {
+        let file = File::open(path)?;
+        info!("Reading taps from {} for the calibration", path);
+        Ok(Self::start(file, tapped))
+    }
+
+    pub fn start(mut file: File, mut tapped: impl FnMut((u32, u32)) + Send + 'static) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut tracker = TapTracker::default();
+            let mut buffer = [0u8; 64 * std::mem::size_of::<libc::input_event>()];
+            let mut pending = Vec::new(); // A read can end in the middle of an event
+            while !stopped.load(Ordering::Relaxed) {
+                let mut poll = libc::pollfd {
+                    fd: file.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // Safety: one pollfd that lives through the call
+                match unsafe { libc::poll(&mut poll, 1, STOP_CHECK_MS) } {
+                    0 => continue,
+                    n if n < 0 => {
+                        let err = io::Error::last_os_error();
+                        if err.kind() == io::ErrorKind::Interrupted {
+                            continue;
+                        }
+                        warn!("Failed to wait for taps: {}", err);
+                        return;
+                    }
+                    _ => (),
+                }
+                let read = match file.read(&mut buffer) {
+                    Ok(0) => {
+                        debug!("Touchscreen is gone, no more taps");
+                        return;
+                    }
+                    Ok(read) => read,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        warn!("Failed to read taps: {}", err);
+                        return;
+                    }
+                };
+                pending.extend_from_slice(&buffer[..read]);
+                let events = read_events(&pending);
+                pending.drain(..events.len() * std::mem::size_of::<libc::input_event>());
+                for event in events {
+                    if let Some(position) = tracker.event(event) {
+                        debug!("Tapped at x:{} y:{}", position.0, position.1);
+                        tapped(position);
+                    }
+                }
+            }
+        });
+        TouchWatch {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for TouchWatch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::touch::{write_events, MtProtocol, Touchscreen};
+    use std::io::Write;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    fn taps(events: &[InputEvent]) -> Vec<(u32, u32)> {
+        let mut tracker = TapTracker::default();
+        events
+            .iter()
+            .filter_map(|event| tracker.event(*event))
+            .collect()
+    }
+
+    // What Touchscreen writes for a tap is what a real one reports
+    fn written_tap(protocol: MtProtocol) -> Vec<InputEvent> {
+        let mut touchscreen = Touchscreen::new(Vec::new(), protocol);
+        let mut events = touchscreen.events(&[(100, 200)]);
+        events.extend(touchscreen.events(&[(110, 210)]));
+        events.extend(touchscreen.events(&[]));
+        events
+    }
+
+    #[test]
+    fn every_protocol_gives_the_lift() {
+        for protocol in [MtProtocol::A, MtProtocol::B, MtProtocol::Single] {
+            assert_eq!(taps(&written_tap(protocol)), [(110, 210)], "{:?}", protocol);
+        }
+    }
+
+    #[test]
+    fn other_fingers_and_moves_are_no_taps() {
+        let abs = |code, value| InputEvent::new(EV_ABS, code, value);
+        let syn = InputEvent::new(EV_SYN, SYN_REPORT, 0);
+        let events = [
+            abs(ABS_MT_SLOT, 0),
+            abs(ABS_MT_TRACKING_ID, 5),
+            abs(ABS_MT_POSITION_X, 10),
+            abs(ABS_MT_POSITION_Y, 20),
+            syn,
+            // A second finger comes and goes
+            abs(ABS_MT_SLOT, 1),
+            abs(ABS_MT_TRACKING_ID, 6),
+            abs(ABS_MT_POSITION_X, 900),
+            abs(ABS_MT_POSITION_Y, 900),
+            syn,
+            abs(ABS_MT_TRACKING_ID, -1),
+            syn,
+            // The first one moves, only x changes
+            abs(ABS_MT_SLOT, 0),
+            abs(ABS_MT_POSITION_X, 12),
+            syn,
+            abs(ABS_MT_TRACKING_ID, -1),
+            syn,
+        ];
+        assert_eq!(taps(&events), [(12, 20)]);
+    }
+
+    #[test]
+    fn reads_taps_until_dropped() {
+        let (mut device, ours) = UnixStream::pair().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let watch = TouchWatch::start(File::from(OwnedFd::from(ours)), move |tap| {
+            tx.send(tap).unwrap();
+        });
+        // Split in the middle of an event, like a short read
+        let mut bytes = Vec::new();
+        write_events(&mut bytes, &written_tap(MtProtocol::B)).unwrap();
+        let (first, rest) = bytes.split_at(bytes.len() / 2 + 3);
+        device.write_all(first).unwrap();
+        device.write_all(rest).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok((110, 210)));
+        drop(watch);
+        assert!(rx.recv_timeout(Duration::from_millis(10)).is_err());
+    }
+}
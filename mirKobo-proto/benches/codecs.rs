@@ -0,0 +1,70 @@
+// Compares what every frame encoding costs on the device
+// On the host: cargo bench -p mir_kobo_proto
+// On the device: cross build --release --benches --target armv7-unknown-linux-musleabihf,
+// copy target/armv7-unknown-linux-musleabihf/release/deps/codecs-* over and run it,
+// optionally with a png screenshot of the reader as the first argument
+
+use mir_kobo_proto::{png_to_gray, Frame, FrameEncoding};
+use std::time::{Duration, Instant};
+
+const ROUNDS: u32 = 20;
+
+// Clara HD sized, mostly white with lines of "text"
+fn synthetic_page() -> (u32, u32, Vec<u8>) {
+    let (width, height) = (1072u32, 1448u32);
+    let pixels = (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            let in_line = (y % 40) < 22 && y > 80 && y < height - 80;
+            let in_glyph = (x * 7 + y * 3) % 11 < 5 && x > 60 && x < width - 60;
+            if in_line && in_glyph {
+                0x10
+            } else {
+                0xFF
+            }
+        })
+        .collect();
+    (width, height, pixels)
+}
+
+fn main() {
+    let (width, height, pixels) = match std::env::args().nth(1).filter(|arg| arg != "--bench") {
+        Some(path) => png_to_gray(&std::fs::read(&path).expect("failed to read png"))
+            .expect("failed to decode png"),
+        None => synthetic_page(),
+    };
+    println!("Frame {}x{}, {} bytes of gray", width, height, pixels.len());
+    println!(
+        "{:<6} {:>12} {:>12} {:>12}",
+        "codec", "bytes", "encode ms", "decode ms"
+    );
+
+    for encoding in [
+        FrameEncoding::Png,
+        FrameEncoding::Raw,
+        FrameEncoding::Zstd,
+        FrameEncoding::Lz4,
+    ] {
+        let mut encode_time = Duration::ZERO;
+        let mut decode_time = Duration::ZERO;
+        let mut size = 0;
+        for _ in 0..ROUNDS {
+            let start = Instant::now();
+            let frame = Frame::encode(width, height, &pixels, encoding).unwrap();
+            encode_time += start.elapsed();
+            size = frame.data.len();
+
+            let start = Instant::now();
+            let decoded = frame.decode().unwrap();
+            decode_time += start.elapsed();
+            assert_eq!(decoded.len(), pixels.len());
+        }
+        println!(
+            "{:<6} {:>12} {:>12.2} {:>12.2}",
+            format!("{:?}", encoding),
+            size,
+            encode_time.as_secs_f64() * 1000.0 / ROUNDS as f64,
+            decode_time.as_secs_f64() * 1000.0 / ROUNDS as f64
+        );
+    }
+}
@@ -0,0 +1,166 @@
+// Logging
+use log::{error, info, warn};
+
+// Arguments
+use clap::Command;
+
+// Other
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use toml_edit::{Document, Value};
+
+// Key and value pairs, the values as they would be typed on the command line
+pub type Values = Vec<(String, String)>;
+
+// --config has to be known before clap runs, clap needs the config for its defaults
+pub fn path_from_args(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+// Keys are named like the arguments, add_to_x = -8 is --add-to-x -8
+pub fn parse(text: &str) -> Result<Values, String> {
+    let document: Document = text.parse().map_err(|err| format!("{}", err))?;
+    let mut values = Vec::new();
+    for (key, item) in document.iter() {
+        let value = match item.as_value() {
+            Some(Value::String(text)) => text.value().clone(),
+            Some(Value::Integer(number)) => number.value().to_string(),
+            Some(Value::Float(number)) => number.value().to_string(),
+            Some(Value::Boolean(boolean)) => boolean.value().to_string(),
+            _ => {
+                return Err(format!(
+                    "{} should be a string, a number or true or false",
+                    key
+                ))
+            }
+        };
+        values.push((key.to_string(), value));
+    }
+    Ok(values)
+}
+
+pub fn load(path: &Path) -> Result<Values, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    parse(&text)
+}
+
+// The config to start with and where it is. A --config that can't be loaded is an error,
+// the default one can be missing, and is left out if it's broken
+pub fn initial(
+    args: &[OsString],
+    default_path: Option<PathBuf>,
+) -> Result<(Option<PathBuf>, Values), String> {
+    if let Some(path) = path_from_args(args) {
+        info!("Loading the config {:?}", path);
+        let values =
+            load(&path).map_err(|err| format!("Failed to load the config {:?}: {}", path, err))?;
+        return Ok((Some(path), values));
+    }
+    let Some(path) = default_path else {
+        return Ok((None, Vec::new()));
+    };
+    if !path.exists() {
+        return Ok((Some(path), Vec::new()));
+    }
+    info!("Loading the config {:?}", path);
+    let values = load(&path).unwrap_or_else(|err| {
+        error!("Failed to load the config {:?}, ignoring it: {}", path, err);
+        Vec::new()
+    });
+    Ok((Some(path), values))
+}
+
+// The config becomes the defaults, so the command line still wins and clap checks the values
+pub fn apply(mut command: Command, values: &[(String, String)]) -> Command {
+    for (key, value) in values {
+        if key == "config" || !command.get_arguments().any(|arg| arg.get_id() == key) {
+            warn!("Unknown key {} in the config, ignoring it", key);
+            continue;
+        }
+        command = command.mut_arg(key, |arg| arg.default_value(value.clone()));
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction};
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn command() -> Command {
+        Command::new("test")
+            .arg(Arg::new("port").long("port").default_value("24356"))
+            .arg(Arg::new("touch_device").long("touch-device"))
+            .arg(
+                Arg::new("invert_x")
+                    .long("invert-x")
+                    .action(ArgAction::SetTrue)
+                    .default_value("true"),
+            )
+    }
+
+    #[test]
+    fn finds_the_config_argument() {
+        assert_eq!(
+            path_from_args(&args(&["host", "--port", "1", "--config", "a.toml"])),
+            Some(PathBuf::from("a.toml"))
+        );
+        assert_eq!(
+            path_from_args(&args(&["kobo", "--config=b.toml"])),
+            Some(PathBuf::from("b.toml"))
+        );
+        assert_eq!(path_from_args(&args(&["host", "--config"])), None);
+        assert_eq!(path_from_args(&args(&["kobo"])), None);
+    }
+
+    #[test]
+    fn config_is_under_the_command_line() {
+        let values = parse(
+            "port = 1234\ninvert_x = false\ntouch_device = \"/dev/input/event1\"\nunknown = \"yes\"\n",
+        )
+        .unwrap();
+        let matches = apply(command(), &values).get_matches_from(["test"]);
+        assert_eq!(matches.get_one::<String>("port").unwrap(), "1234");
+        assert_eq!(
+            matches.get_one::<String>("touch_device").unwrap(),
+            "/dev/input/event1"
+        );
+        // A flag that is on by default can finally be turned off
+        assert!(!matches.get_flag("invert_x"));
+        assert!(apply(command(), &values)
+            .get_matches_from(["test", "--invert-x"])
+            .get_flag("invert_x"));
+        let matches = apply(command(), &values).get_matches_from(["test", "--port", "5"]);
+        assert_eq!(matches.get_one::<String>("port").unwrap(), "5");
+        assert!(parse("port = [1, 2]").is_err());
+        assert!(parse("[table]\nkey = 1").is_err());
+    }
+
+    #[test]
+    fn only_the_default_config_can_be_missing() {
+        let missing = PathBuf::from("/nonexistent/mirkobo.toml");
+        assert!(initial(
+            &args(&["kobo", "--config", "/nonexistent/mirkobo.toml"]),
+            None
+        )
+        .is_err());
+        assert_eq!(
+            initial(&args(&["kobo"]), Some(missing.clone())),
+            Ok((Some(missing), Vec::new()))
+        );
+        assert_eq!(initial(&args(&["kobo"]), None), Ok((None, Vec::new())));
+    }
+}
@@ -0,0 +1,443 @@
+use crate::{Frame, FrameEncoding, FrameError, FromClientMessage};
+use serde::{Deserialize, Serialize};
+
+// Screens are compared in tiles of this many pixels, a changed letter dirties one or two
+pub const TILE_SIZE: u32 = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn area(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+// A changed part of the screen, the frame is as big as the rectangle
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Patch {
+    pub x: u32,
+    pub y: u32,
+    pub frame: Frame,
+}
+
+impl Patch {
+    pub fn rect(&self) -> Rect {
+        Rect {
+            x: self.x,
+            y: self.y,
+            width: self.frame.width,
+            height: self.frame.height,
+        }
+    }
+}
+
+fn tile_changed(previous: &[u8], current: &[u8], width: u32, tile: Rect) -> bool {
+    (tile.y..tile.y + tile.height).any(|y| {
+        let start = (y * width + tile.x) as usize;
+        let end = start + tile.width as usize;
+        previous[start..end] != current[start..end]
+    })
+}
+
+// Rectangles covering every pixel that differs. Dirty tiles next to each other in a row
+// become one run, runs with the same span in consecutive rows become one rectangle
+pub fn dirty_rects(previous: &[u8], current: &[u8], width: u32, height: u32) -> Vec<Rect> {
+    let tiles_x = width.div_ceil(TILE_SIZE);
+    let tiles_y = height.div_ceil(TILE_SIZE);
+    let tile = |tx: u32, ty: u32| Rect {
+        x: tx * TILE_SIZE,
+        y: ty * TILE_SIZE,
+        width: TILE_SIZE.min(width - tx * TILE_SIZE),
+        height: TILE_SIZE.min(height - ty * TILE_SIZE),
+    };
+
+    // In tile units until the end
+    let mut done: Vec<Rect> = Vec::new();
+    let mut open: Vec<Rect> = Vec::new();
+    for ty in 0..tiles_y {
+        let mut runs = Vec::new();
+        let mut tx = 0;
+        while tx < tiles_x {
+            if tile_changed(previous, current, width, tile(tx, ty)) {
+                let start = tx;
+                while tx < tiles_x && tile_changed(previous, current, width, tile(tx, ty)) {
+                    tx += 1;
+                }
+                runs.push((start, tx - start));
+            } else {
+                tx += 1;
+            }
+        }
+
+        let mut still_open = Vec::new();
+        for (start, len) in runs {
+            match open.iter().position(|r| r.x == start && r.width == len) {
+                Some(i) => {
+                    let mut rect = open.swap_remove(i);
+                    rect.height += 1;
+                    still_open.push(rect);
+                }
+                None => still_open.push(Rect {
+                    x: start,
+                    y: ty,
+                    width: len,
+                    height: 1,
+                }),
+            }
+        }
+        done.append(&mut open);
+        open = still_open;
+    }
+    done.append(&mut open);
+
+    done.into_iter()
+        .map(|r| Rect {
+            x: r.x * TILE_SIZE,
+            y: r.y * TILE_SIZE,
+            width: (r.width * TILE_SIZE).min(width - r.x * TILE_SIZE),
+            height: (r.height * TILE_SIZE).min(height - r.y * TILE_SIZE),
+        })
+        .collect()
+}
+
+pub fn crop(pixels: &[u8], width: u32, rect: Rect) -> Vec<u8> {
+    let mut output = Vec::with_capacity(rect.area());
+    for y in rect.y..rect.y + rect.height {
+        let start = (y * width + rect.x) as usize;
+        output.extend_from_slice(&pixels[start..start + rect.width as usize]);
+    }
+    output
+}
+
+fn blit(target: &mut [u8], width: u32, rect: Rect, pixels: &[u8]) {
+    for (row, line) in pixels.chunks_exact(rect.width as usize).enumerate() {
+        let start = ((rect.y + row as u32) * width + rect.x) as usize;
+        target[start..start + rect.width as usize].copy_from_slice(line);
+    }
+}
+
+// Device side, remembers the last frame and turns the next one into a Frame or a Delta
+pub struct DeltaEncoder {
+    previous: Option<(u32, u32, Vec<u8>)>,
+    sequence: u32,
+    since_keyframe: u32,
+    keyframe_interval: u32, // 0 and 1 mean only keyframes
+}
+
+impl DeltaEncoder {
+    pub fn new(keyframe_interval: u32) -> Self {
+        DeltaEncoder {
+            previous: None,
+            sequence: 0,
+            since_keyframe: 0,
+            keyframe_interval,
+        }
+    }
+
+    // The next frame will be a full one
+    pub fn request_keyframe(&mut self) {
+        self.previous = None;
+    }
+
+    pub fn encode(
+        &mut self,
+        width: u32,
+        height: u32,
+        pixels: Vec<u8>,
+        encoding: FrameEncoding,
+        requested_us: Option<u64>,
+        captured_us: Option<u64>,
+    ) -> Result<FromClientMessage, FrameError> {
+        let sequence = self.sequence.wrapping_add(1);
+        let rects = match &self.previous {
+            Some((w, h, previous))
+                if (*w, *h) == (width, height)
+                    && pixels.len() == previous.len()
+                    && self.since_keyframe + 1 < self.keyframe_interval =>
+            {
+                let rects = dirty_rects(previous, &pixels, width, height);
+                let dirty: usize = rects.iter().map(Rect::area).sum();
+                // Half of the screen changed, a keyframe costs about the same
+                (dirty * 2 <= pixels.len()).then_some(rects)
+            }
+            _ => None,
+        };
+
+        let message = match rects {
+            Some(rects) => {
+                let mut patches = Vec::with_capacity(rects.len());
+                for rect in rects {
+                    let frame = Frame::encode(
+                        rect.width,
+                        rect.height,
+                        &crop(&pixels, width, rect),
+                        encoding,
+                    )?;
+                    patches.push(Patch {
+                        x: rect.x,
+                        y: rect.y,
+                        frame,
+                    });
+                }
+                self.since_keyframe += 1;
+                FromClientMessage::Delta {
+                    sequence,
+                    requested_us,
+                    captured_us,
+                    patches,
+                }
+            }
+            None => {
+                let frame = Frame::encode(width, height, &pixels, encoding)?;
+                self.since_keyframe = 0;
+                FromClientMessage::Frame {
+                    sequence,
+                    requested_us,
+                    captured_us,
+                    frame,
+                }
+            }
+        };
+        self.sequence = sequence;
+        self.previous = Some((width, height, pixels));
+        Ok(message)
+    }
+}
+
+// Host side, keeps the full picture and applies deltas to it
+#[derive(Default)]
+pub struct FrameAssembler {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    sequence: Option<u32>, // None until a keyframe arrives, or after something went wrong
+}
+
+impl FrameAssembler {
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn keyframe(&mut self, sequence: u32, frame: &Frame) -> Result<(), FrameError> {
+        self.sequence = None;
+        self.pixels = frame.decode()?;
+        self.width = frame.width;
+        self.height = frame.height;
+        self.sequence = Some(sequence);
+        Ok(())
+    }
+
+    // Returns the patched rectangles with their pixels. On an error the picture is
+    // unusable until the next keyframe
+    pub fn delta(
+        &mut self,
+        sequence: u32,
+        patches: &[Patch],
+    ) -> Result<Vec<(Rect, Vec<u8>)>, FrameError> {
+        let expected = self.sequence.take().map(|last| last.wrapping_add(1));
+        if expected != Some(sequence) {
+            return Err(FrameError::MissingBase);
+        }
+        let mut decoded = Vec::with_capacity(patches.len());
+        for patch in patches {
+            let rect = patch.rect();
+            if rect.x as u64 + rect.width as u64 > self.width as u64
+                || rect.y as u64 + rect.height as u64 > self.height as u64
+            {
+                return Err(FrameError::Size(format!(
+                    "patch {:?} is outside of {}x{}",
+                    rect, self.width, self.height
+                )));
+            }
+            let pixels = patch.frame.decode()?;
+            blit(&mut self.pixels, self.width, rect, &pixels);
+            decoded.push((rect, pixels));
+        }
+        self.sequence = Some(sequence);
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 16) as u32
+        }
+    }
+
+    #[test]
+    fn identical_frames_have_no_rects() {
+        let pixels = vec![7; 100 * 70];
+        assert!(dirty_rects(&pixels, &pixels, 100, 70).is_empty());
+    }
+
+    #[test]
+    fn rects_cover_every_change_and_merge() {
+        let (width, height) = (100, 70);
+        let previous = vec![255; 100 * 70];
+        let mut current = previous.clone();
+        // A block over 2x2 tiles and a pixel in the bottom right corner
+        for y in 10..50 {
+            for x in 20..40 {
+                current[y * 100 + x] = 0;
+            }
+        }
+        current[69 * 100 + 99] = 0;
+        let mut rects = dirty_rects(&previous, &current, width, height);
+        rects.sort_by_key(|r| (r.y, r.x));
+        assert_eq!(
+            rects,
+            vec![
+                Rect {
+                    x: 0,
+                    y: 0,
+                    width: 64,
+                    height: 64
+                },
+                Rect {
+                    x: 96,
+                    y: 64,
+                    width: 4,
+                    height: 6
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reconstruction_is_bit_exact() {
+        let (width, height) = (150u32, 97u32);
+        let mut random = Random(0x6b6f626f);
+        let mut pixels = vec![255u8; (width * height) as usize];
+        let mut encoder = DeltaEncoder::new(10);
+        let mut assembler = FrameAssembler::default();
+        let mut deltas = 0;
+
+        for step in 0..200 {
+            // Mostly small edits, sometimes a whole new page
+            if step % 37 == 0 {
+                pixels.iter_mut().for_each(|p| *p = random.next() as u8);
+            } else {
+                for _ in 0..random.next() % 5 {
+                    let x = random.next() % width;
+                    let y = random.next() % height;
+                    let w = (random.next() % 40).min(width - x);
+                    let h = (random.next() % 20).min(height - y);
+                    let value = random.next() as u8;
+                    for yy in y..y + h {
+                        for xx in x..x + w {
+                            pixels[(yy * width + xx) as usize] = value;
+                        }
+                    }
+                }
+            }
+            let encoding = [FrameEncoding::Raw, FrameEncoding::Lz4, FrameEncoding::Zstd][step % 3];
+            let message = encoder
+                .encode(width, height, pixels.clone(), encoding, None, None)
+                .unwrap();
+            // Through the wire format too
+            match FromClientMessage::decode(&message.encode()).unwrap() {
+                FromClientMessage::Frame {
+                    sequence, frame, ..
+                } => assembler.keyframe(sequence, &frame).unwrap(),
+                FromClientMessage::Delta {
+                    sequence, patches, ..
+                } => {
+                    deltas += 1;
+                    assembler.delta(sequence, &patches).unwrap();
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+            assert_eq!(assembler.size(), (width, height));
+            assert!(assembler.pixels() == pixels.as_slice(), "step {}", step);
+        }
+        assert!(deltas > 100);
+    }
+
+    #[test]
+    fn keyframes_are_periodic_and_on_request() {
+        let mut encoder = DeltaEncoder::new(3);
+        let pixels = vec![0; 64 * 64];
+        let kinds: Vec<bool> = (0..7)
+            .map(|i| {
+                if i == 5 {
+                    encoder.request_keyframe();
+                }
+                let message = encoder
+                    .encode(64, 64, pixels.clone(), FrameEncoding::Raw, None, None)
+                    .unwrap();
+                matches!(message, FromClientMessage::Frame { .. })
+            })
+            .collect();
+        assert_eq!(kinds, vec![true, false, false, true, false, true, false]);
+    }
+
+    #[test]
+    fn missing_base_needs_a_keyframe() {
+        let mut encoder = DeltaEncoder::new(100);
+        let mut assembler = FrameAssembler::default();
+        let pixels = vec![0; 64 * 64];
+        let FromClientMessage::Frame {
+            sequence, frame, ..
+        } = encoder
+            .encode(64, 64, pixels.clone(), FrameEncoding::Raw, None, None)
+            .unwrap()
+        else {
+            panic!("expected a keyframe");
+        };
+        assembler.keyframe(sequence, &frame).unwrap();
+        // Lost one
+        encoder
+            .encode(64, 64, pixels.clone(), FrameEncoding::Raw, None, None)
+            .unwrap();
+        let FromClientMessage::Delta {
+            sequence, patches, ..
+        } = encoder
+            .encode(64, 64, pixels.clone(), FrameEncoding::Raw, None, None)
+            .unwrap()
+        else {
+            panic!("expected a delta");
+        };
+        assert_eq!(
+            assembler.delta(sequence, &patches),
+            Err(FrameError::MissingBase)
+        );
+        // Still broken for the next one too
+        assert_eq!(
+            assembler.delta(sequence + 1, &[]),
+            Err(FrameError::MissingBase)
+        );
+    }
+
+    #[test]
+    fn patches_outside_the_screen_are_errors() {
+        let mut assembler = FrameAssembler::default();
+        let frame = Frame::encode(4, 4, &[0; 16], FrameEncoding::Raw).unwrap();
+        assembler.keyframe(1, &frame).unwrap();
+        let patch = Patch {
+            x: 3,
+            y: 0,
+            frame: Frame::encode(2, 1, &[1, 1], FrameEncoding::Raw).unwrap(),
+        };
+        assert!(assembler.delta(2, &[patch]).is_err());
+    }
+}
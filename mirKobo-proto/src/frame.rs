@@ -0,0 +1,300 @@
+use crate::FrameEncoding;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Nothing with an eink screen comes close, it only protects us from absurd sizes in broken frames
+pub const MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+
+// Keep Unknown as the last variant
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Gray8, // One byte of luma per pixel
+    #[serde(other)]
+    Unknown, // Something a newer build knows about
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32, // Bytes per line after decoding, at least width
+    pub format: PixelFormat,
+    pub encoding: FrameEncoding,
+    pub data: Vec<u8>,
+}
+
+// The data is way too long to print
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Frame")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("stride", &self.stride)
+            .field("format", &self.format)
+            .field("encoding", &self.encoding)
+            .field("data_len", &self.data.len())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    Unsupported(String), // Encoding or pixel format we don't know
+    Codec(String),       // The compressor or decompressor failed
+    Size(String),        // Pixels don't match the declared geometry
+    MissingBase,         // A delta for a frame we don't have
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Unsupported(what) => write!(f, "unsupported {}", what),
+            FrameError::Codec(reason) => write!(f, "codec failed: {}", reason),
+            FrameError::Size(reason) => write!(f, "wrong frame size: {}", reason),
+            FrameError::MissingBase => write!(f, "delta doesn't apply to the last frame"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+// BT.601, the same weights everywhere so the host and the device agree on gray
+pub fn luma(r: u32, g: u32, b: u32) -> u8 {
+    ((r * 77 + g * 150 + b * 29 + 128) >> 8) as u8
+}
+
+fn codec_error(err: impl fmt::Display) -> FrameError {
+    FrameError::Codec(err.to_string())
+}
+
+fn gray_to_png(width: u32, height: u32, pixels: &[u8]) -> Result<Vec<u8>, FrameError> {
+    let mut output = Vec::new();
+    let mut encoder = png::Encoder::new(&mut output, width, height);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Fast);
+    let mut writer = encoder.write_header().map_err(codec_error)?;
+    writer.write_image_data(pixels).map_err(codec_error)?;
+    writer.finish().map_err(codec_error)?;
+    Ok(output)
+}
+
+// Any png, like the ones from fbgrab, to width, height and 8 bit gray
+pub fn png_to_gray(data: &[u8]) -> Result<(u32, u32, Vec<u8>), FrameError> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(codec_error)?;
+    let (width, height) = (reader.info().width, reader.info().height);
+    if width as usize * height as usize * 4 > MAX_FRAME_BYTES {
+        return Err(FrameError::Size(format!(
+            "{}x{} png is too big",
+            width, height
+        )));
+    }
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(codec_error)?;
+    let (color, _) = reader.output_color_type();
+    let channels = color.samples();
+    let mut gray = Vec::with_capacity(width as usize * height as usize);
+    for line in buffer[..info.buffer_size()].chunks_exact(info.line_size) {
+        for pixel in line[..width as usize * channels].chunks_exact(channels) {
+            gray.push(match color {
+                png::ColorType::Rgb | png::ColorType::Rgba => {
+                    luma(pixel[0] as u32, pixel[1] as u32, pixel[2] as u32)
+                }
+                _ => pixel[0], // Gray, gray with alpha
+            });
+        }
+    }
+    Ok((width, height, gray))
+}
+
+impl Frame {
+    // pixels are width * height bytes of gray, without padding
+    pub fn encode(
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        encoding: FrameEncoding,
+    ) -> Result<Frame, FrameError> {
+        if pixels.len() != width as usize * height as usize {
+            return Err(FrameError::Size(format!(
+                "{} bytes for {}x{}",
+                pixels.len(),
+                width,
+                height
+            )));
+        }
+        let data = match encoding {
+            FrameEncoding::Png => gray_to_png(width, height, pixels)?,
+            FrameEncoding::Raw => pixels.to_vec(),
+            // Level 1, the device cpu is the bottleneck, not usb
+            FrameEncoding::Zstd => zstd::bulk::compress(pixels, 1).map_err(codec_error)?,
+            FrameEncoding::Lz4 => lz4_flex::compress(pixels),
+            FrameEncoding::Unknown => {
+                return Err(FrameError::Unsupported(format!("encoding {:?}", encoding)))
+            }
+        };
+        Ok(Frame {
+            width,
+            height,
+            stride: width,
+            format: PixelFormat::Gray8,
+            encoding,
+            data,
+        })
+    }
+
+    // Back to width * height bytes of gray, without padding
+    pub fn decode(&self) -> Result<Vec<u8>, FrameError> {
+        if self.format != PixelFormat::Gray8 {
+            return Err(FrameError::Unsupported(format!(
+                "pixel format {:?}",
+                self.format
+            )));
+        }
+        if self.stride < self.width {
+            return Err(FrameError::Size(format!(
+                "stride {} is smaller than width {}",
+                self.stride, self.width
+            )));
+        }
+        let expected = self.stride as usize * self.height as usize;
+        if expected > MAX_FRAME_BYTES {
+            return Err(FrameError::Size(format!("{} bytes is too big", expected)));
+        }
+        let raw = match self.encoding {
+            FrameEncoding::Png => {
+                let (width, height, gray) = png_to_gray(&self.data)?;
+                if (width, height) != (self.width, self.height) {
+                    return Err(FrameError::Size(format!(
+                        "png is {}x{}, frame says {}x{}",
+                        width, height, self.width, self.height
+                    )));
+                }
+                return Ok(gray);
+            }
+            FrameEncoding::Raw => self.data.clone(),
+            FrameEncoding::Zstd => {
+                zstd::bulk::decompress(&self.data, expected).map_err(codec_error)?
+            }
+            FrameEncoding::Lz4 => {
+                lz4_flex::decompress(&self.data, expected).map_err(codec_error)?
+            }
+            FrameEncoding::Unknown => {
+                return Err(FrameError::Unsupported(format!(
+                    "encoding {:?}",
+                    self.encoding
+                )))
+            }
+        };
+        if raw.len() != expected {
+            return Err(FrameError::Size(format!(
+                "{} bytes, expected {}",
+                raw.len(),
+                expected
+            )));
+        }
+        if self.stride == self.width {
+            return Ok(raw);
+        }
+        Ok(raw
+            .chunks_exact(self.stride as usize)
+            .flat_map(|line| &line[..self.width as usize])
+            .copied()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENCODINGS: [FrameEncoding; 4] = [
+        FrameEncoding::Png,
+        FrameEncoding::Raw,
+        FrameEncoding::Zstd,
+        FrameEncoding::Lz4,
+    ];
+
+    // Mostly white with some "text", like a page
+    fn page(width: u32, height: u32) -> Vec<u8> {
+        (0..width * height)
+            .map(|i| {
+                if (i % width) % 7 < 2 && (i / width) % 5 < 3 {
+                    0
+                } else {
+                    255
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_encoding_round_trips() {
+        let pixels = page(37, 11);
+        for encoding in ENCODINGS {
+            let frame = Frame::encode(37, 11, &pixels, encoding).unwrap();
+            assert_eq!(frame.encoding, encoding);
+            assert_eq!(frame.decode().unwrap(), pixels, "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn compression_helps_on_a_page() {
+        let pixels = page(200, 100);
+        for encoding in [FrameEncoding::Zstd, FrameEncoding::Lz4] {
+            let frame = Frame::encode(200, 100, &pixels, encoding).unwrap();
+            assert!(frame.data.len() < pixels.len() / 4, "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn stride_padding_is_removed() {
+        let frame = Frame {
+            width: 2,
+            height: 2,
+            stride: 3,
+            format: PixelFormat::Gray8,
+            encoding: FrameEncoding::Raw,
+            data: vec![1, 2, 0, 3, 4, 0],
+        };
+        assert_eq!(frame.decode().unwrap(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn broken_frames_are_errors() {
+        assert!(Frame::encode(2, 2, &[0; 3], FrameEncoding::Raw).is_err());
+        assert!(Frame::encode(1, 1, &[0], FrameEncoding::Unknown).is_err());
+
+        let mut frame = Frame::encode(4, 4, &page(4, 4), FrameEncoding::Lz4).unwrap();
+        frame.height = 5;
+        assert!(frame.decode().is_err());
+        let mut frame = Frame::encode(4, 4, &page(4, 4), FrameEncoding::Zstd).unwrap();
+        frame.data.truncate(frame.data.len() / 2);
+        assert!(frame.decode().is_err());
+        let mut frame = Frame::encode(4, 4, &page(4, 4), FrameEncoding::Png).unwrap();
+        frame.width = 3;
+        assert!(frame.decode().is_err());
+        let mut frame = Frame::encode(4, 4, &page(4, 4), FrameEncoding::Raw).unwrap();
+        frame.format = PixelFormat::Unknown;
+        assert!(frame.decode().is_err());
+        frame.format = PixelFormat::Gray8;
+        frame.height = u32::MAX;
+        assert!(frame.decode().is_err());
+    }
+
+    #[test]
+    fn color_png_becomes_gray() {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 2, 1);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(&[255, 0, 0, 255, 255, 255, 255, 255])
+            .unwrap();
+        writer.finish().unwrap();
+        assert_eq!(png_to_gray(&data).unwrap(), (2, 1, vec![77, 255]));
+    }
+}
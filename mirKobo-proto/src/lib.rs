@@ -0,0 +1,534 @@
+#[cfg(feature = "config")]
+pub mod config;
+mod delta;
+mod frame;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub use delta::{crop, dirty_rects, DeltaEncoder, FrameAssembler, Patch, Rect, TILE_SIZE};
+pub use frame::{luma, png_to_gray, Frame, FrameError, PixelFormat, MAX_FRAME_BYTES};
+
+// Bump this every time a message changes in a way older builds can't decode
+pub const PROTOCOL_VERSION: u32 = 17;
+
+// Keep Unknown as the last variant, new encodings go above it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameEncoding {
+    Png,
+    Raw,  // Uncompressed pixels
+    Zstd, // Pixels compressed with zstd
+    Lz4,  // Pixels compressed with lz4
+    #[serde(other)]
+    Unknown, // Something a newer build knows about
+}
+
+// Clicks, gestures, keys... Keep Unknown as the last variant
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKind {
+    Click,   // TouchDown and TouchUp, so long presses too
+    Gesture, // Drags and swipes
+    Pinch,   // Two fingers
+    Key,     // Hardware buttons
+    Text,    // Typing whole strings
+    #[serde(other)]
+    Unknown, // Something a newer build knows about
+}
+
+// How a touchscreen reports fingers, for TouchDevice
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TouchProtocol {
+    A,      // Linux multitouch protocol A, anonymous contacts
+    B,      // Multitouch protocol B, slots
+    Single, // Only ABS_X and ABS_Y
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Capabilities {
+    pub encodings: Vec<FrameEncoding>, // In order of preference
+    pub inputs: Vec<InputKind>,
+    pub draw_targets: bool, // Calibration targets can be drawn into the framebuffer
+}
+
+impl Capabilities {
+    // What both sides can do, in our order of preference
+    pub fn common(&self, other: &Capabilities) -> Capabilities {
+        Capabilities {
+            encodings: self
+                .encodings
+                .iter()
+                .filter(|e| **e != FrameEncoding::Unknown && other.encodings.contains(e))
+                .copied()
+                .collect(),
+            inputs: self
+                .inputs
+                .iter()
+                .filter(|i| **i != InputKind::Unknown && other.inputs.contains(i))
+                .copied()
+                .collect(),
+            draw_targets: self.draw_targets && other.draw_targets,
+        }
+    }
+
+    pub fn supports_input(&self, input: InputKind) -> bool {
+        self.inputs.contains(&input)
+    }
+}
+
+// How long the device spent on a frame, sent right after it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameTiming {
+    pub sequence: u32,
+    pub capture_us: u32,      // Reading the screen
+    pub encode_us: u32,       // Diffing and compressing
+    pub min_interval_us: u32, // Don't ask for frames faster than this, 0 means no limit
+}
+
+// A point of a gesture, time_ms is counted from the first point
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TouchPoint {
+    pub x: u16,
+    pub y: u16,
+    pub time_ms: u32,
+}
+
+// Both fingers of a pinch at one moment, time_ms is counted from the first one
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinchPoint {
+    pub first: (u16, u16),
+    pub second: (u16, u16),
+    pub time_ms: u32,
+}
+
+// Kobo buttons for FromServerMessage::Key, codes from linux/input-event-codes.h
+pub const KEY_LIGHT: u16 = 90; // KEY_KATAKANA, the frontlight button of older models
+pub const KEY_HOME: u16 = 102;
+pub const KEY_POWER: u16 = 116;
+pub const KEY_PAGE_BACK: u16 = 193; // KEY_F23, page turn buttons of the Libra, Forma and Sage
+pub const KEY_PAGE_FORWARD: u16 = 194; // KEY_F24
+
+// Everything the host can do on the device
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Input {
+    TouchDown(u16, u16),                      // Finger down at x / y
+    TouchUp { x: u16, y: u16, held_ms: u32 }, // Finger up at x / y, held_ms after it went down on the host
+    Gesture(Vec<TouchPoint>), // One finger down at the first point, moving through the rest, up at the last
+    Pinch(Vec<PinchPoint>),   // Like Gesture, with two fingers
+    Key(u16, bool),           // Key code, pressed or released
+    Text(String),             // Typed on the host, \n is enter and \u{8} backspace
+}
+
+// Hello needs to stay the first variant in both enums, see peek_hello_version
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum FromClientMessage {
+    Hello {
+        version: u32,
+        capabilities: Capabilities,
+        model: Option<String>, // Like n873, picks the profile on the host
+    }, // First message after connecting, answered with Hello
+    Frame {
+        sequence: u32,
+        requested_us: Option<u64>, // From the RequestScreen it answers, None when streamed
+        captured_us: Option<u64>, // When a streamed one was captured, in the host clock of StartStreaming
+        frame: Frame,
+    }, // A keyframe, the whole screen
+    Delta {
+        sequence: u32,
+        requested_us: Option<u64>,
+        captured_us: Option<u64>,
+        patches: Vec<Patch>,
+    }, // Only what changed since the frame with sequence - 1
+    Timing(FrameTiming),
+    //ChunkSize(usize), // Used when a message is potentially to big - not needed in websockets, yay
+    ScreenSize((u32, u32)), // x, y
+    Rotation(u32), // Quarter turns clockwise like var.rotate of the framebuffer, sent after ScreenSize
+    //Done, // Indicates it's done with the previous message
+    InputAck {
+        id: u32,
+        error: Option<String>,
+    }, // An Input was injected, or why it wasn't
+    TargetTouched((u32, u32)), // Raw touchscreen x, y of a tap on the device while a target is drawn
+    ProtocolError(String), // The last message from the host couldn't be decoded
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum FromServerMessage {
+    Hello {
+        version: u32,
+        capabilities: Capabilities,
+    }, // Answers for Hello
+    Input {
+        id: u32,
+        input: Input,
+    }, // Answered with InputAck once it's done, ids go up by one
+    DrawTarget(Option<(u32, u32)>), // Calibration target at x / y of the framebuffer, None removes it
+    RequestScreen {
+        requested_us: u64,
+    }, // requested_us is the host clock, it comes back with the frame
+    RequestKeyframe, // A delta didn't apply, the next frame needs to be a full one
+    StartStreaming {
+        min_interval_ms: u32,
+        max_interval_ms: u32,
+        host_us: u64, // The host clock when it was sent, like requested_us
+    }, // Instead of RequestScreen, the device pushes frames when the screen changes
+    TouchDevice {
+        path: Option<String>,
+        protocol: Option<TouchProtocol>,
+    }, // From the profile for the model, after Hello. None leaves it to the device
+    ProtocolError(String), // The last message from the device couldn't be decoded
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    VersionMismatch(u32), // A Hello from a build speaking this version
+    Truncated,            // The message ended too early
+    Malformed(String),    // Anything else bincode didn't like, like an unknown variant
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::VersionMismatch(version) => write!(
+                f,
+                "protocol version mismatch: we speak {}, the other side speaks {}",
+                PROTOCOL_VERSION, version
+            ),
+            DecodeError::Truncated => write!(f, "message is truncated"),
+            DecodeError::Malformed(reason) => write!(f, "message is malformed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// What bincode::serialize does, but bytes left over after a message are an error
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .reject_trailing_bytes()
+}
+
+// Everything that goes over the websocket, so both sides encode it the same way
+pub trait Message: Serialize + DeserializeOwned {
+    fn encode(&self) -> Vec<u8> {
+        // Our enums don't have anything bincode can't handle, so this can't fail
+        bincode_options()
+            .serialize(self)
+            .expect("failed to serialize message")
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        bincode_options().deserialize(data).map_err(|err| {
+            if let Some(version) = peek_hello_version(data) {
+                if version != PROTOCOL_VERSION {
+                    return DecodeError::VersionMismatch(version);
+                }
+            }
+            match *err {
+                bincode::ErrorKind::Io(ref io)
+                    if io.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    DecodeError::Truncated
+                }
+                other => DecodeError::Malformed(other.to_string()),
+            }
+        })
+    }
+}
+
+impl Message for FromClientMessage {}
+impl Message for FromServerMessage {}
+
+// Gets the version out of a Hello even if the rest of it can't be decoded, for example
+// because the other side is a different build. bincode writes the variant index first,
+// then the fields in order
+pub fn peek_hello_version(data: &[u8]) -> Option<u32> {
+    let variant = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    if variant != 0 {
+        return None;
+    }
+    Some(u32::from_le_bytes(data.get(4..8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Message + std::fmt::Debug + PartialEq>(message: T) {
+        let data = message.encode();
+        assert_eq!(T::decode(&data).unwrap(), message);
+    }
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            encodings: vec![FrameEncoding::Png],
+            inputs: vec![InputKind::Click],
+            draw_targets: true,
+        }
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        round_trip(FromClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities(),
+            model: Some(String::from("n873")),
+        });
+        for encoding in [
+            FrameEncoding::Png,
+            FrameEncoding::Raw,
+            FrameEncoding::Zstd,
+            FrameEncoding::Lz4,
+        ] {
+            let frame = Frame::encode(3, 2, &[0, 64, 128, 192, 255, 0], encoding).unwrap();
+            round_trip(FromClientMessage::Frame {
+                sequence: 1,
+                requested_us: Some(1_700_000_000_000_000),
+                captured_us: None,
+                frame: frame.clone(),
+            });
+            round_trip(FromClientMessage::Delta {
+                sequence: u32::MAX,
+                requested_us: None,
+                captured_us: Some(1_700_000_000_000_000),
+                patches: vec![Patch { x: 5, y: 7, frame }],
+            });
+        }
+        round_trip(FromClientMessage::Timing(FrameTiming {
+            sequence: 3,
+            capture_us: 41_000,
+            encode_us: 9_000,
+            min_interval_us: 200_000,
+        }));
+        round_trip(FromClientMessage::ScreenSize((1072, 1448)));
+        round_trip(FromClientMessage::Rotation(3));
+        round_trip(FromClientMessage::InputAck { id: 0, error: None });
+        round_trip(FromClientMessage::InputAck {
+            id: u32::MAX,
+            error: Some(String::from("no input device has key 116")),
+        });
+        round_trip(FromClientMessage::TargetTouched((4095, 0)));
+        round_trip(FromClientMessage::ProtocolError(String::from(
+            "message is truncated",
+        )));
+    }
+
+    fn input(input: Input) {
+        round_trip(FromServerMessage::Input { id: 7, input });
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        round_trip(FromServerMessage::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::default(),
+        });
+        input(Input::TouchDown(0, 0));
+        input(Input::TouchDown(u16::MAX, 758));
+        input(Input::TouchUp {
+            x: 3,
+            y: 4,
+            held_ms: 1200,
+        });
+        input(Input::Gesture(vec![
+            TouchPoint {
+                x: 10,
+                y: 500,
+                time_ms: 0,
+            },
+            TouchPoint {
+                x: 900,
+                y: 510,
+                time_ms: 180,
+            },
+        ]));
+        input(Input::Pinch(vec![PinchPoint {
+            first: (100, 200),
+            second: (300, 200),
+            time_ms: 0,
+        }]));
+        input(Input::Key(KEY_POWER, true));
+        input(Input::Text(String::from("Hasło 123\n")));
+        round_trip(FromServerMessage::DrawTarget(Some((214, 1158))));
+        round_trip(FromServerMessage::DrawTarget(None));
+        round_trip(FromServerMessage::TouchDevice {
+            path: Some(String::from("/dev/input/event1")),
+            protocol: Some(TouchProtocol::A),
+        });
+        round_trip(FromServerMessage::TouchDevice {
+            path: None,
+            protocol: None,
+        });
+        round_trip(FromServerMessage::RequestScreen {
+            requested_us: 1_700_000_000_000_000,
+        });
+        round_trip(FromServerMessage::RequestKeyframe);
+        round_trip(FromServerMessage::StartStreaming {
+            min_interval_ms: 100,
+            max_interval_ms: 2000,
+            host_us: 1_700_000_000_000_000,
+        });
+        round_trip(FromServerMessage::ProtocolError(String::new()));
+    }
+
+    #[test]
+    fn hello_version_can_be_peeked() {
+        let hello = FromClientMessage::Hello {
+            version: 42,
+            capabilities: capabilities(),
+            model: Some(String::from("n873")),
+        };
+        assert_eq!(peek_hello_version(&hello.encode()), Some(42));
+        let hello = FromServerMessage::Hello {
+            version: 7,
+            capabilities: capabilities(),
+        };
+        assert_eq!(peek_hello_version(&hello.encode()), Some(7));
+        assert_eq!(
+            peek_hello_version(&FromServerMessage::RequestScreen { requested_us: 0 }.encode()),
+            None
+        );
+        assert_eq!(peek_hello_version(&[0, 0, 0]), None);
+    }
+
+    #[test]
+    fn unknown_capabilities_are_tolerated() {
+        // A newer build sends an encoding index we don't know
+        let mut data = bincode::serialize(&capabilities()).unwrap();
+        let encoding_index = 8; // 8 bytes of Vec length
+        data[encoding_index..encoding_index + 4].copy_from_slice(&99u32.to_le_bytes());
+        let decoded: Capabilities = bincode::deserialize(&data).unwrap();
+        assert_eq!(decoded.encodings, vec![FrameEncoding::Unknown]);
+        assert!(decoded.common(&capabilities()).encodings.is_empty());
+    }
+
+    #[test]
+    fn common_capabilities_keep_our_order() {
+        let ours = Capabilities {
+            encodings: vec![FrameEncoding::Unknown, FrameEncoding::Png],
+            inputs: vec![InputKind::Click],
+            draw_targets: true,
+        };
+        let theirs = Capabilities {
+            encodings: vec![FrameEncoding::Png, FrameEncoding::Unknown],
+            inputs: Vec::new(),
+            draw_targets: false,
+        };
+        let common = ours.common(&theirs);
+        assert_eq!(common.encodings, vec![FrameEncoding::Png]);
+        assert!(!common.supports_input(InputKind::Click));
+        assert!(!common.draw_targets);
+    }
+
+    #[test]
+    fn decode_errors_are_typed() {
+        let data = FromServerMessage::Input {
+            id: 1,
+            input: Input::TouchDown(10, 20),
+        }
+        .encode();
+        assert_eq!(
+            FromServerMessage::decode(&data[..data.len() - 1]),
+            Err(DecodeError::Truncated)
+        );
+        assert!(matches!(
+            FromServerMessage::decode(&99u32.to_le_bytes()),
+            Err(DecodeError::Malformed(_))
+        ));
+        // Something tacked on the end isn't a message either
+        let mut longer = data.clone();
+        longer.push(0);
+        assert!(matches!(
+            FromServerMessage::decode(&longer),
+            Err(DecodeError::Malformed(_))
+        ));
+        let mut hello = FromClientMessage::Hello {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: capabilities(),
+            model: Some(String::from("n873")),
+        }
+        .encode();
+        hello.truncate(10);
+        assert_eq!(
+            FromClientMessage::decode(&hello),
+            Err(DecodeError::VersionMismatch(PROTOCOL_VERSION + 1))
+        );
+    }
+
+    // Small xorshift, so the corpus is the same on every run
+    fn corpus(seed: u64, count: usize) -> Vec<Vec<u8>> {
+        let mut state = seed;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        (0..count)
+            .map(|_| {
+                let len = (next() % 64) as usize;
+                let mut data: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+                // Make valid variant indexes common, otherwise almost everything fails on the first 4 bytes
+                if data.len() >= 4 && next() % 2 == 0 {
+                    data[0..4].copy_from_slice(&((next() % 8) as u32).to_le_bytes());
+                }
+                data
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        for data in corpus(0x6b6f626f, 20000) {
+            let _ = FromClientMessage::decode(&data);
+            let _ = FromServerMessage::decode(&data);
+        }
+    }
+
+    #[test]
+    fn every_truncation_is_an_error() {
+        let client = [
+            FromClientMessage::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: capabilities(),
+                model: Some(String::from("n873")),
+            },
+            FromClientMessage::Frame {
+                sequence: 3,
+                requested_us: Some(u64::MAX),
+                captured_us: None,
+                frame: Frame::encode(2, 2, &[1, 2, 3, 4], FrameEncoding::Raw).unwrap(),
+            },
+            FromClientMessage::ScreenSize((1, 2)),
+            FromClientMessage::Rotation(1),
+        ];
+        for data in client.iter().map(Message::encode) {
+            for len in 0..data.len() {
+                assert!(FromClientMessage::decode(&data[..len]).is_err());
+            }
+        }
+        let server = [
+            FromServerMessage::Input {
+                id: 2,
+                input: Input::TouchDown(3, 4),
+            },
+            FromServerMessage::Input {
+                id: 3,
+                input: Input::TouchUp {
+                    x: 3,
+                    y: 4,
+                    held_ms: 50,
+                },
+            },
+            FromServerMessage::ProtocolError(String::from("oops")),
+        ];
+        for data in server.iter().map(Message::encode) {
+            for len in 0..data.len() {
+                assert!(FromServerMessage::decode(&data[..len]).is_err());
+            }
+        }
+    }
+}